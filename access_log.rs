@@ -0,0 +1,178 @@
+use hyper::header::HeaderMap;
+use serde::Deserialize;
+use serde_json::Value;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Correlation header: taken from the caller when acceptable, otherwise
+// generated; echoed on the response and forwarded upstream
+pub const X_REQUEST_ID: &str = "x-request-id";
+
+// Longest inbound request ID we are willing to pass on
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FileAccessLog {
+    enabled: Option<bool>,
+    path: Option<PathBuf>,
+    include_body: Option<bool>,
+}
+
+#[derive(Debug, Clone)]
+pub struct AccessLogConfig {
+    pub enabled: bool,
+    // File to append to; standard output when unset
+    pub path: Option<PathBuf>,
+    // Log JSON request bodies, after the `redaction.log` rules
+    pub include_body: bool,
+}
+
+impl Default for AccessLogConfig {
+    fn default() -> AccessLogConfig {
+        AccessLogConfig { enabled: true, path: None, include_body: false }
+    }
+}
+
+impl AccessLogConfig {
+    pub fn merge(&self, file: &FileAccessLog) -> AccessLogConfig {
+        let mut c = self.clone();
+        if let Some(e) = file.enabled {
+            c.enabled = e;
+        }
+        if let Some(p) = &file.path {
+            c.path = Some(p.clone());
+        }
+        if let Some(b) = file.include_body {
+            c.include_body = b;
+        }
+        c
+    }
+}
+
+// The caller's request ID, if it is short printable ASCII
+pub fn inbound_request_id(headers: &HeaderMap) -> Option<String> {
+    let value = headers.get(X_REQUEST_ID)?.to_str().ok()?.trim();
+    let valid = !value.is_empty()
+        && value.len() <= MAX_REQUEST_ID_LEN
+        && value.bytes().all(|b| b.is_ascii_graphic());
+    valid.then(|| value.to_string())
+}
+
+// Writes one JSON object per line for every finished request
+pub struct AccessLog {
+    out: Option<Mutex<Box<dyn Write + Send>>>,
+}
+
+impl AccessLog {
+    pub fn open(config: &AccessLogConfig) -> Result<AccessLog, String> {
+        if !config.enabled {
+            return Ok(AccessLog { out: None });
+        }
+        let out: Box<dyn Write + Send> = match &config.path {
+            Some(path) => Box::new(
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .map_err(|e| format!("cannot open access log {}: {}", path.display(), e))?,
+            ),
+            None => Box::new(std::io::stdout()),
+        };
+        Ok(AccessLog { out: Some(Mutex::new(out)) })
+    }
+
+    // `entry` must be a JSON object; a `time` field is added to it
+    pub fn write(&self, entry: Value) {
+        let Some(out) = &self.out else {
+            return;
+        };
+        let mut line = serde_json::Map::new();
+        line.insert("time".into(), rfc3339(SystemTime::now()).into());
+        if let Value::Object(fields) = entry {
+            line.extend(fields);
+        }
+        let mut text = Value::Object(line).to_string();
+        text.push('\n');
+        let mut out = out.lock().unwrap();
+        if let Err(e) = out.write_all(text.as_bytes()).and_then(|_| out.flush()) {
+            eprintln!("Failed to write access log: {}", e);
+        }
+    }
+}
+
+// UTC timestamp with millisecond precision, e.g. `2024-05-01T12:00:00.123Z`
+fn rfc3339(t: SystemTime) -> String {
+    let since = t.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since.as_secs();
+    let (days, rem) = (secs / 86_400, secs % 86_400);
+    // Civil date from days since 1970-01-01 (Howard Hinnant's algorithm)
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60,
+        since.subsec_millis()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::header::HeaderValue;
+    use std::time::Duration;
+
+    fn at(secs: u64, millis: u64) -> String {
+        rfc3339(UNIX_EPOCH + Duration::from_secs(secs) + Duration::from_millis(millis))
+    }
+
+    #[test]
+    fn timestamps() {
+        assert_eq!(at(0, 0), "1970-01-01T00:00:00.000Z");
+        assert_eq!(at(1_709_210_096, 789), "2024-02-29T12:34:56.789Z");
+        assert_eq!(at(1_709_210_096 + 86_400, 0), "2024-03-01T12:34:56.000Z");
+        assert_eq!(at(951_782_400, 0), "2000-02-29T00:00:00.000Z");
+        // 2100 is not a leap year
+        assert_eq!(at(4_107_542_400 - 86_400, 0), "2100-02-28T00:00:00.000Z");
+        assert_eq!(at(946_684_799, 999), "1999-12-31T23:59:59.999Z");
+        assert_eq!(at(946_684_800, 0), "2000-01-01T00:00:00.000Z");
+        assert_eq!(at(1_704_067_199, 0), "2023-12-31T23:59:59.000Z");
+        // Times before the epoch are clamped to it
+        assert_eq!(rfc3339(UNIX_EPOCH - Duration::from_secs(1)), "1970-01-01T00:00:00.000Z");
+    }
+
+    #[test]
+    fn inbound_request_ids() {
+        let id = |value: &[u8]| {
+            let mut headers = HeaderMap::new();
+            headers.insert(X_REQUEST_ID, HeaderValue::from_bytes(value).unwrap());
+            inbound_request_id(&headers)
+        };
+        assert_eq!(inbound_request_id(&HeaderMap::new()), None);
+        assert_eq!(id(b"abc-123").as_deref(), Some("abc-123"));
+        assert_eq!(id(b"  padded\t").as_deref(), Some("padded"));
+        assert_eq!(id(b""), None);
+        assert_eq!(id(b"   "), None);
+        let longest = "x".repeat(MAX_REQUEST_ID_LEN);
+        assert_eq!(id(longest.as_bytes()), Some(longest.clone()));
+        assert_eq!(id(format!("{}x", longest).as_bytes()), None);
+        // Inner spaces and non-ASCII bytes could forge log fields
+        assert_eq!(id(b"two words"), None);
+        assert_eq!(id("caf\u{e9}".as_bytes()), None);
+    }
+}
@@ -0,0 +1,197 @@
+use crate::errors::ApiError;
+use crate::AppState;
+use hyper::header::{HeaderValue, AUTHORIZATION, WWW_AUTHENTICATE};
+use hyper::{Body, Method, Request, Response, StatusCode};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+// Prefix reserved for operational endpoints; never matched against routes
+pub const ADMIN_PREFIX: &str = "/admin/";
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FileAdmin {
+    // Exactly one of `token` and `token_env` gives the bearer token
+    token: Option<String>,
+    token_env: Option<String>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct AdminConfig {
+    // SHA-256 of the token that unlocks the mutating endpoints; they are
+    // refused while none is configured
+    token_hash: Option<[u8; 32]>,
+}
+
+impl AdminConfig {
+    pub fn from_file(file: &FileAdmin) -> Result<AdminConfig, String> {
+        let token = match (&file.token, &file.token_env) {
+            (Some(t), None) => t.clone(),
+            (None, Some(var)) => std::env::var(var).map_err(|_| format!("environment variable {} is not set", var))?,
+            _ => return Err("exactly one of `token` and `token_env` must be set".to_string()),
+        };
+        if token.is_empty() {
+            return Err("token must not be empty".to_string());
+        }
+        Ok(AdminConfig { token_hash: Some(Sha256::digest(token.as_bytes()).into()) })
+    }
+
+    // Endpoints that change state need `Authorization: Bearer <token>`.
+    // Digests are compared rather than the tokens, so response timing says
+    // nothing about the token itself.
+    fn authorize(&self, req: &Request<Body>) -> Result<(), ApiError> {
+        let Some(expected) = &self.token_hash else {
+            return Err(ApiError::new(
+                StatusCode::FORBIDDEN,
+                "admin-disabled",
+                "Admin endpoints that change state require an [admin] token",
+            ));
+        };
+        let presented = req
+            .headers()
+            .get(AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "))
+            .map(|t| <[u8; 32]>::from(Sha256::digest(t.trim().as_bytes())));
+        match presented {
+            Some(hash) if hash == *expected => Ok(()),
+            Some(_) => Err(unauthorized("invalid-credentials", "The admin token is not valid")),
+            None => Err(unauthorized("missing-credentials", "Send the admin token as a bearer token")),
+        }
+    }
+}
+
+fn unauthorized(kind: &'static str, detail: &str) -> ApiError {
+    ApiError::new(StatusCode::UNAUTHORIZED, kind, detail)
+        .with_header(WWW_AUTHENTICATE, HeaderValue::from_static("Bearer realm=\"admin\""))
+}
+
+// Helper: JSON response with the given status
+fn json_response(status: StatusCode, value: &serde_json::Value) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(hyper::header::CONTENT_TYPE, "application/json")
+        .body(Body::from(value.to_string()))
+        .unwrap()
+}
+
+fn unknown_upstream(name: &str) -> ApiError {
+    ApiError::new(StatusCode::NOT_FOUND, "not-found", format!("No upstream named '{}'", name))
+}
+
+// GET  /admin/circuit-breakers
+// GET  /admin/circuit-breakers/{upstream}
+// POST /admin/circuit-breakers/{upstream}/trip
+// POST /admin/circuit-breakers/{upstream}/reset
+fn circuit_breakers(req: &Request<Body>, rest: &[&str], state: &AppState) -> Result<Response<Body>, ApiError> {
+    let method = req.method();
+    match rest {
+        [] => {
+            if method != Method::GET {
+                return Err(ApiError::method_not_allowed("GET"));
+            }
+            let all: serde_json::Map<_, _> = state
+                .breakers
+                .iter()
+                .map(|(name, b)| (name.clone(), b.snapshot()))
+                .collect();
+            Ok(json_response(StatusCode::OK, &serde_json::Value::Object(all)))
+        }
+        [name] => match state.breakers.get(*name) {
+            Some(b) if method == Method::GET => Ok(json_response(StatusCode::OK, &b.snapshot())),
+            Some(_) => Err(ApiError::method_not_allowed("GET")),
+            None => Err(unknown_upstream(name)),
+        },
+        [name, action @ ("trip" | "reset")] => match state.breakers.get(*name) {
+            Some(b) if method == Method::POST => {
+                state.config.admin.authorize(req)?;
+                if *action == "trip" {
+                    b.trip();
+                } else {
+                    b.reset();
+                }
+                Ok(json_response(StatusCode::OK, &b.snapshot()))
+            }
+            Some(_) => Err(ApiError::method_not_allowed("POST")),
+            None => Err(unknown_upstream(name)),
+        },
+        _ => Err(ApiError::not_found()),
+    }
+}
+
+// GET  /admin/cache
+// POST /admin/cache/purge[?route=<pattern>]
+fn cache(req: &Request<Body>, rest: &[&str], state: &AppState) -> Result<Response<Body>, ApiError> {
+    match rest {
+        [] if req.method() == Method::GET => Ok(json_response(StatusCode::OK, &state.cache.stats())),
+        [] => Err(ApiError::method_not_allowed("GET")),
+        ["purge"] if req.method() == Method::POST => {
+            state.config.admin.authorize(req)?;
+            let route = url::form_urlencoded::parse(req.uri().query().unwrap_or_default().as_bytes())
+                .find(|(k, _)| k == "route")
+                .map(|(_, v)| v.into_owned());
+            let purged = state.cache.purge(route.as_deref());
+            Ok(json_response(StatusCode::OK, &serde_json::json!({ "purged": purged })))
+        }
+        ["purge"] => Err(ApiError::method_not_allowed("POST")),
+        _ => Err(ApiError::not_found()),
+    }
+}
+
+// Dispatch a request whose path starts with `ADMIN_PREFIX`
+pub fn handle_admin(req: &Request<Body>, state: &AppState) -> Result<Response<Body>, ApiError> {
+    let path = &req.uri().path()[ADMIN_PREFIX.len()..];
+    let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+    match segments.as_slice() {
+        ["circuit-breakers", rest @ ..] => circuit_breakers(req, rest, state),
+        ["cache", rest @ ..] => cache(req, rest, state),
+        _ => Err(ApiError::not_found()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn post(authorization: Option<&str>) -> Request<Body> {
+        let mut req = Request::post("/admin/circuit-breakers/default/trip");
+        if let Some(value) = authorization {
+            req = req.header(AUTHORIZATION, value);
+        }
+        req.body(Body::empty()).unwrap()
+    }
+
+    fn configured() -> AdminConfig {
+        AdminConfig::from_file(&FileAdmin { token: Some("s3cret".to_string()), token_env: None }).unwrap()
+    }
+
+    #[test]
+    fn anonymous_requests_are_rejected() {
+        let err = configured().authorize(&post(None)).unwrap_err();
+        assert_eq!(err.status, StatusCode::UNAUTHORIZED);
+    }
+
+    #[test]
+    fn wrong_token_is_rejected() {
+        let err = configured().authorize(&post(Some("Bearer guess"))).unwrap_err();
+        assert_eq!(err.status, StatusCode::UNAUTHORIZED);
+    }
+
+    #[test]
+    fn matching_token_is_accepted() {
+        assert!(configured().authorize(&post(Some("Bearer s3cret"))).is_ok());
+    }
+
+    #[test]
+    fn refused_without_configured_token() {
+        let err = AdminConfig::default().authorize(&post(Some("Bearer s3cret"))).unwrap_err();
+        assert_eq!(err.status, StatusCode::FORBIDDEN);
+    }
+
+    #[test]
+    fn token_is_required() {
+        assert!(AdminConfig::from_file(&FileAdmin::default()).is_err());
+        let empty = FileAdmin { token: Some(String::new()), token_env: None };
+        assert!(AdminConfig::from_file(&empty).is_err());
+    }
+}
@@ -0,0 +1,437 @@
+use crate::config::RouteConfig;
+use crate::errors::ApiError;
+use crate::jwt;
+use crate::AppState;
+use hyper::header::{HeaderName, HeaderValue};
+use hyper::{Body, Request, StatusCode};
+use serde::Deserialize;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const DEFAULT_HEADER: &str = "x-api-key";
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FileApiKeys {
+    // Exactly one of `file` and `sqlite` names the key store
+    file: Option<PathBuf>,
+    sqlite: Option<PathBuf>,
+    header: Option<String>,
+    query_param: Option<String>,
+    reload_interval_ms: Option<u64>,
+}
+
+#[derive(Debug, Clone)]
+pub enum KeySource {
+    File(PathBuf),
+    Sqlite(PathBuf),
+}
+
+#[derive(Debug, Clone)]
+pub struct ApiKeyConfig {
+    pub source: KeySource,
+    // Where clients present their key; the header wins if both are sent
+    pub header: HeaderName,
+    pub query_param: Option<String>,
+    // How often the store is re-read, so new and revoked keys take effect
+    pub reload_interval: Duration,
+}
+
+impl ApiKeyConfig {
+    // Store paths are resolved against `base_dir`, the config file's directory
+    pub fn from_file(file: &FileApiKeys, base_dir: &Path) -> Result<ApiKeyConfig, String> {
+        let source = match (&file.file, &file.sqlite) {
+            (Some(p), None) => KeySource::File(base_dir.join(p)),
+            (None, Some(p)) => KeySource::Sqlite(base_dir.join(p)),
+            _ => return Err("exactly one of `file` and `sqlite` must be set".to_string()),
+        };
+        let header = file.header.as_deref().unwrap_or(DEFAULT_HEADER);
+        let header = HeaderName::from_bytes(header.as_bytes())
+            .map_err(|_| format!("invalid header name '{}'", header))?;
+        if let Some("") = file.query_param.as_deref() {
+            return Err("query_param must not be empty".to_string());
+        }
+        let reload_interval = match file.reload_interval_ms {
+            Some(0) => return Err("reload_interval_ms must be greater than 0".to_string()),
+            Some(ms) => Duration::from_millis(ms),
+            None => Duration::from_secs(30),
+        };
+        Ok(ApiKeyConfig { source, header, query_param: file.query_param.clone(), reload_interval })
+    }
+}
+
+// One entry of the key store; only the SHA-256 of the key itself is kept
+#[derive(Debug, Clone)]
+struct ApiKey {
+    id: String,
+    owner: Option<String>,
+    scopes: Vec<String>,
+    expires_at: Option<SystemTime>,
+}
+
+// Key file layout
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileKeyStore {
+    #[serde(default)]
+    keys: Vec<FileKey>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileKey {
+    id: String,
+    // Hex SHA-256 of the key
+    hash: String,
+    owner: Option<String>,
+    #[serde(default)]
+    scopes: Vec<String>,
+    expires_at: Option<toml::value::Datetime>,
+}
+
+// Who made the request, for the access log
+#[derive(Debug, Clone)]
+pub enum Principal {
+    ApiKey { id: String, owner: Option<String> },
+    Jwt { subject: Option<String> },
+}
+
+impl Principal {
+    // Stable identity of the caller, for keys that must not be shared
+    // between callers
+    pub fn caller(&self) -> String {
+        match self {
+            Principal::ApiKey { id, .. } => format!("key:{}", id),
+            Principal::Jwt { subject } => format!("sub:{}", subject.as_deref().unwrap_or_default()),
+        }
+    }
+}
+
+pub fn hash_key(key: &str) -> String {
+    Sha256::digest(key.as_bytes()).iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn normalize_hash(hash: &str) -> Result<String, String> {
+    let hex = hash.trim().trim_start_matches("sha256:").to_ascii_lowercase();
+    if hex.len() != 64 || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err("hash must be 64 hex characters (SHA-256)".to_string());
+    }
+    Ok(hex)
+}
+
+// Days since 1970-01-01 for a proleptic Gregorian date (Howard Hinnant's algorithm)
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let yoe = y.rem_euclid(400);
+    let mp = (month + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+fn toml_time(dt: &toml::value::Datetime) -> Result<SystemTime, String> {
+    let date = dt.date.ok_or("expires_at must include a date")?;
+    let mut secs = days_from_civil(i64::from(date.year), i64::from(date.month), i64::from(date.day)) * 86_400;
+    if let Some(t) = dt.time {
+        secs += i64::from(t.hour) * 3600 + i64::from(t.minute) * 60 + i64::from(t.second);
+    }
+    match dt.offset {
+        Some(toml::value::Offset::Custom { minutes }) => secs -= i64::from(minutes) * 60,
+        Some(toml::value::Offset::Z) => {}
+        // Dates without an offset are taken as UTC
+        None => {}
+    }
+    let secs = u64::try_from(secs).map_err(|_| "expires_at must be after 1970".to_string())?;
+    Ok(UNIX_EPOCH + Duration::from_secs(secs))
+}
+
+fn load_file(path: &Path) -> Result<HashMap<String, ApiKey>, String> {
+    let text = std::fs::read_to_string(path).map_err(|e| format!("cannot read {}: {}", path.display(), e))?;
+    let store: FileKeyStore = toml::from_str(&text).map_err(|e| format!("invalid key file {}: {}", path.display(), e))?;
+    let mut keys = HashMap::new();
+    for (i, k) in store.keys.into_iter().enumerate() {
+        let ctx = format!("{}: keys[{}]", path.display(), i);
+        let hash = normalize_hash(&k.hash).map_err(|e| format!("{}: {}", ctx, e))?;
+        let expires_at = k.expires_at.as_ref().map(toml_time).transpose().map_err(|e| format!("{}: {}", ctx, e))?;
+        let key = ApiKey { id: k.id, owner: k.owner, scopes: k.scopes, expires_at };
+        if keys.insert(hash, key).is_some() {
+            return Err(format!("{}: duplicate hash", ctx));
+        }
+    }
+    Ok(keys)
+}
+
+// Table `api_keys(id TEXT, key_hash TEXT, owner TEXT, scopes TEXT, expires_at INTEGER)`;
+// `scopes` is space-separated and `expires_at` is in Unix seconds
+fn load_sqlite(path: &Path) -> Result<HashMap<String, ApiKey>, String> {
+    let ctx = |e: rusqlite::Error| format!("{}: {}", path.display(), e);
+    let conn = rusqlite::Connection::open_with_flags(path, rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY).map_err(ctx)?;
+    let mut stmt = conn
+        .prepare("SELECT id, key_hash, owner, scopes, expires_at FROM api_keys")
+        .map_err(ctx)?;
+    let rows = stmt
+        .query_map([], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, Option<String>>(2)?,
+                row.get::<_, Option<String>>(3)?,
+                row.get::<_, Option<i64>>(4)?,
+            ))
+        })
+        .map_err(ctx)?;
+    let mut keys = HashMap::new();
+    for row in rows {
+        let (id, hash, owner, scopes, expires_at) = row.map_err(ctx)?;
+        let key_ctx = format!("{}: key '{}'", path.display(), id);
+        let hash = normalize_hash(&hash).map_err(|e| format!("{}: {}", key_ctx, e))?;
+        let key = ApiKey {
+            id,
+            owner,
+            scopes: scopes.unwrap_or_default().split_whitespace().map(str::to_string).collect(),
+            expires_at: expires_at.map(|s| UNIX_EPOCH + Duration::from_secs(s.max(0) as u64)),
+        };
+        if keys.insert(hash, key).is_some() {
+            return Err(format!("{}: duplicate hash", key_ctx));
+        }
+    }
+    Ok(keys)
+}
+
+fn load(source: &KeySource) -> Result<HashMap<String, ApiKey>, String> {
+    match source {
+        KeySource::File(p) => load_file(p),
+        KeySource::Sqlite(p) => load_sqlite(p),
+    }
+}
+
+// In-memory copy of the key store, indexed by key hash
+pub struct KeyStore {
+    config: ApiKeyConfig,
+    keys: RwLock<HashMap<String, ApiKey>>,
+}
+
+impl KeyStore {
+    pub fn open(config: ApiKeyConfig) -> Result<KeyStore, String> {
+        let keys = load(&config.source)?;
+        Ok(KeyStore { config, keys: RwLock::new(keys) })
+    }
+
+    pub fn query_param(&self) -> Option<&str> {
+        self.config.query_param.as_deref()
+    }
+
+    // The presented key, from the header or else the query string
+    fn presented_key(&self, req: &Request<Body>) -> Option<String> {
+        if let Some(v) = req.headers().get(&self.config.header) {
+            return v.to_str().ok().map(|s| s.trim().to_string());
+        }
+        let name = self.config.query_param.as_deref()?;
+        url::form_urlencoded::parse(req.uri().query()?.as_bytes())
+            .find(|(k, _)| k == name)
+            .map(|(_, v)| v.into_owned())
+    }
+
+    // `WWW-Authenticate` challenge describing how to present a key
+    fn challenge(&self) -> String {
+        format!("ApiKey header=\"{}\"", self.config.header)
+    }
+
+    fn unauthorized(&self, kind: &'static str, detail: &str) -> ApiError {
+        ApiError::new(StatusCode::UNAUTHORIZED, kind, detail).with_header(
+            hyper::header::WWW_AUTHENTICATE,
+            HeaderValue::from_str(&self.challenge()).expect("valid challenge"),
+        )
+    }
+
+    // Check a presented key and that it holds every scope the route requires
+    fn check(&self, presented: &str, route: &RouteConfig) -> Result<Principal, ApiError> {
+        let keys = self.keys.read().unwrap();
+        let key = keys
+            .get(&hash_key(presented))
+            .ok_or_else(|| self.unauthorized("invalid-credentials", "Unknown API key"))?;
+        if key.expires_at.is_some_and(|t| t <= SystemTime::now()) {
+            return Err(self.unauthorized("invalid-credentials", "API key has expired"));
+        }
+        let missing: Vec<&String> = route.scopes.iter().filter(|s| !key.scopes.contains(s)).collect();
+        if !missing.is_empty() {
+            return Err(ApiError::new(
+                StatusCode::FORBIDDEN,
+                "insufficient-scope",
+                format!("API key '{}' lacks the scopes this route requires", key.id),
+            )
+            .with_extension("missing_scopes", missing.iter().map(|s| s.as_str()).collect::<Vec<_>>()));
+        }
+        // Claims can only be checked on a bearer token
+        if !route.claims.is_empty() {
+            return Err(ApiError::new(
+                StatusCode::FORBIDDEN,
+                "insufficient-claims",
+                "This route requires a bearer token with specific claims",
+            ));
+        }
+        Ok(Principal::ApiKey { id: key.id.clone(), owner: key.owner.clone() })
+    }
+}
+
+// Authenticate the caller with whichever configured credential they present
+// (a bearer token is tried first) and authorize them for the route
+pub fn authenticate(state: &AppState, req: &Request<Body>, route: &RouteConfig) -> Result<Principal, ApiError> {
+    if let (Some(validator), Some(token)) = (&state.jwt, jwt::bearer_token(req)) {
+        let claims = validator.verify(token)?;
+        jwt::authorize(&claims, route)?;
+        let subject = claims.get("sub").and_then(Value::as_str).map(str::to_string);
+        return Ok(Principal::Jwt { subject });
+    }
+    if let Some(store) = &state.api_keys {
+        if let Some(presented) = store.presented_key(req).filter(|k| !k.is_empty()) {
+            return store.check(&presented, route);
+        }
+    }
+
+    let mut challenges = Vec::new();
+    if state.jwt.is_some() {
+        challenges.push("Bearer".to_string());
+    }
+    if let Some(store) = &state.api_keys {
+        challenges.push(store.challenge());
+    }
+    let detail = match (state.jwt.is_some(), state.api_keys.is_some()) {
+        (true, true) => "A bearer token or API key is required",
+        (true, false) => "A bearer token is required",
+        _ => "An API key is required",
+    };
+    Err(ApiError::new(StatusCode::UNAUTHORIZED, "missing-credentials", detail).with_header(
+        hyper::header::WWW_AUTHENTICATE,
+        HeaderValue::from_str(&challenges.join(", ")).expect("valid challenge"),
+    ))
+}
+
+// Re-read the key store on an interval; a failed reload keeps the old keys
+pub fn spawn_reload(state: Arc<AppState>) {
+    let Some(store) = &state.api_keys else {
+        return;
+    };
+    let mut interval = tokio::time::interval(store.config.reload_interval);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    tokio::spawn(async move {
+        // The first tick fires immediately and the store was just loaded
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            let store = state.api_keys.as_ref().expect("key store present");
+            let source = store.config.source.clone();
+            match tokio::task::spawn_blocking(move || load(&source)).await {
+                Ok(Ok(keys)) => *store.keys.write().unwrap() = keys,
+                Ok(Err(e)) => eprintln!("Failed to reload API keys: {}", e),
+                Err(e) => eprintln!("Failed to reload API keys: {}", e),
+            }
+        }
+    });
+}
+
+// Remove the API key parameter so it is not passed to the upstream; other
+// parameters are kept exactly as sent
+pub fn strip_query_param(query: Option<&str>, name: &str) -> Option<String> {
+    let kept: Vec<&str> = query?
+        .split('&')
+        .filter(|pair| url::form_urlencoded::parse(pair.as_bytes()).next().is_none_or(|(k, _)| k != name))
+        .collect();
+    if kept.is_empty() {
+        return None;
+    }
+    Some(kept.join("&"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Fresh path in the temp directory, removed when dropped
+    struct TempPath(PathBuf);
+
+    impl TempPath {
+        fn new(name: &str) -> TempPath {
+            let path = std::env::temp_dir().join(format!("rust_api-{}-{}", std::process::id(), name));
+            std::fs::remove_file(&path).ok();
+            TempPath(path)
+        }
+    }
+
+    impl Drop for TempPath {
+        fn drop(&mut self) {
+            std::fs::remove_file(&self.0).ok();
+        }
+    }
+
+    fn sqlite_store(name: &str, rows: &[(&str, &str)]) -> TempPath {
+        let path = TempPath::new(name);
+        let conn = rusqlite::Connection::open(&path.0).unwrap();
+        conn.execute_batch("CREATE TABLE api_keys (id TEXT, key_hash TEXT, owner TEXT, scopes TEXT, expires_at INTEGER)")
+            .unwrap();
+        for (id, key) in rows {
+            conn.execute(
+                "INSERT INTO api_keys (id, key_hash, owner, scopes, expires_at) VALUES (?1, ?2, 'ops', 'read write', 4102444800)",
+                (id, hash_key(key)),
+            )
+            .unwrap();
+        }
+        path
+    }
+
+    #[test]
+    fn sqlite_store_loads_keys() {
+        let path = sqlite_store("keys.db", &[("k1", "alpha"), ("k2", "beta")]);
+        let keys = load_sqlite(&path.0).unwrap();
+        let key = &keys[&hash_key("beta")];
+        assert_eq!(key.id, "k2");
+        assert_eq!(key.owner.as_deref(), Some("ops"));
+        assert_eq!(key.scopes, ["read", "write"]);
+        assert_eq!(key.expires_at, Some(UNIX_EPOCH + Duration::from_secs(4_102_444_800)));
+    }
+
+    #[test]
+    fn duplicate_hashes_are_rejected_by_both_stores() {
+        let db = sqlite_store("dup.db", &[("k1", "alpha"), ("k2", "alpha")]);
+        let err = load_sqlite(&db.0).unwrap_err();
+        assert_eq!(err, format!("{}: key 'k2': duplicate hash", db.0.display()));
+
+        let file = TempPath::new("dup.toml");
+        let hash = hash_key("alpha");
+        let text = format!("[[keys]]\nid = \"k1\"\nhash = \"{hash}\"\n[[keys]]\nid = \"k2\"\nhash = \"sha256:{hash}\"\n");
+        std::fs::write(&file.0, text).unwrap();
+        let err = load_file(&file.0).unwrap_err();
+        assert_eq!(err, format!("{}: keys[1]: duplicate hash", file.0.display()));
+    }
+
+    #[test]
+    fn hashes_are_normalized() {
+        let hex = hash_key("alpha");
+        assert_eq!(normalize_hash(&format!(" sha256:{} ", hex.to_uppercase())).unwrap(), hex);
+        assert!(normalize_hash(&hex[1..]).is_err());
+        assert!(normalize_hash(&"z".repeat(64)).is_err());
+    }
+
+    #[test]
+    fn expiry_dates_are_utc() {
+        let at = |s: &str| toml_time(&s.parse().unwrap()).unwrap();
+        assert_eq!(at("1970-01-01"), UNIX_EPOCH);
+        assert_eq!(at("2000-03-01T00:00:00Z"), UNIX_EPOCH + Duration::from_secs(951_868_800));
+        assert_eq!(at("2024-02-29T12:30:00+02:00"), UNIX_EPOCH + Duration::from_secs(1_709_202_600));
+        assert!(toml_time(&"1969-12-31".parse().unwrap()).is_err());
+        assert!(toml_time(&"12:00:00".parse().unwrap()).is_err());
+    }
+
+    #[test]
+    fn key_parameter_is_stripped_from_the_query() {
+        assert_eq!(strip_query_param(Some("api_key=s3cret&page=2"), "api_key").as_deref(), Some("page=2"));
+        assert_eq!(strip_query_param(Some("a=%20x&api%5Fkey=s3cret&b"), "api_key").as_deref(), Some("a=%20x&b"));
+        assert_eq!(strip_query_param(Some("api_key=s3cret"), "api_key"), None);
+        assert_eq!(strip_query_param(None, "api_key"), None);
+    }
+}
@@ -0,0 +1,368 @@
+use serde::Deserialize;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// Circuit breaker settings as written in the config file; every field is
+// optional so an upstream only needs to list what it overrides
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FileBreaker {
+    enabled: Option<bool>,
+    failure_rate_threshold: Option<f64>,
+    minimum_requests: Option<u32>,
+    window_secs: Option<u64>,
+    open_secs: Option<u64>,
+    half_open_max_calls: Option<u32>,
+}
+
+#[derive(Debug, Clone)]
+pub struct BreakerSettings {
+    pub enabled: bool,
+    // Fraction of failed calls in the window that opens the breaker
+    failure_rate_threshold: f64,
+    // Calls needed in the window before the failure rate is considered
+    minimum_requests: u32,
+    window: Duration,
+    // How long the breaker stays open before letting probes through
+    open_for: Duration,
+    half_open_max_calls: u32,
+}
+
+impl Default for BreakerSettings {
+    fn default() -> BreakerSettings {
+        BreakerSettings {
+            enabled: true,
+            failure_rate_threshold: 0.5,
+            minimum_requests: 20,
+            window: Duration::from_secs(30),
+            open_for: Duration::from_secs(30),
+            half_open_max_calls: 1,
+        }
+    }
+}
+
+impl BreakerSettings {
+    // Apply the fields set in `file` on top of `self`
+    pub fn merge(&self, file: &FileBreaker) -> Result<BreakerSettings, String> {
+        let mut s = self.clone();
+        if let Some(e) = file.enabled {
+            s.enabled = e;
+        }
+        if let Some(t) = file.failure_rate_threshold {
+            if !(t > 0.0 && t <= 1.0) {
+                return Err(format!("failure_rate_threshold must be in (0, 1], got {}", t));
+            }
+            s.failure_rate_threshold = t;
+        }
+        if let Some(n) = file.minimum_requests {
+            s.minimum_requests = n.max(1);
+        }
+        if let Some(w) = file.window_secs {
+            if w == 0 {
+                return Err("window_secs must be at least 1".to_string());
+            }
+            s.window = Duration::from_secs(w);
+        }
+        if let Some(o) = file.open_secs {
+            s.open_for = Duration::from_secs(o);
+        }
+        if let Some(n) = file.half_open_max_calls {
+            if n == 0 {
+                return Err("half_open_max_calls must be at least 1".to_string());
+            }
+            s.half_open_max_calls = n;
+        }
+        Ok(s)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Closed,
+    // `forced` is set when tripped through the admin endpoint; such a breaker
+    // stays open until it is reset manually
+    Open { until: Instant, forced: bool },
+    HalfOpen { in_flight: u32 },
+}
+
+// Outcomes recorded during one second of the rolling window
+#[derive(Debug, Clone, Copy, Default)]
+struct Bucket {
+    second: u64,
+    successes: u32,
+    failures: u32,
+}
+
+struct Inner {
+    state: State,
+    buckets: Vec<Bucket>,
+}
+
+pub struct CircuitBreaker {
+    settings: BreakerSettings,
+    started: Instant,
+    inner: Mutex<Inner>,
+}
+
+// Permission to make one upstream call; report the outcome with `record`
+pub struct Permit<'a> {
+    breaker: &'a CircuitBreaker,
+    probe: bool,
+    recorded: bool,
+}
+
+impl Permit<'_> {
+    pub fn record(mut self, success: bool) {
+        self.recorded = true;
+        self.breaker.on_result(self.probe, success);
+    }
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        // A cancelled probe must still free its half-open slot
+        if self.probe && !self.recorded {
+            let mut inner = self.breaker.inner.lock().unwrap();
+            if let State::HalfOpen { in_flight } = &mut inner.state {
+                *in_flight = in_flight.saturating_sub(1);
+            }
+        }
+    }
+}
+
+impl CircuitBreaker {
+    pub fn new(settings: BreakerSettings) -> CircuitBreaker {
+        let buckets = vec![Bucket::default(); settings.window.as_secs() as usize];
+        CircuitBreaker {
+            settings,
+            started: Instant::now(),
+            inner: Mutex::new(Inner { state: State::Closed, buckets }),
+        }
+    }
+
+    fn second(&self) -> u64 {
+        self.started.elapsed().as_secs()
+    }
+
+    // Ask to make a call. Returns how long the caller should wait before
+    // retrying when the breaker is open.
+    pub fn acquire(&self) -> Result<Permit<'_>, Duration> {
+        let permit = |probe| Permit { breaker: self, probe, recorded: false };
+        if !self.settings.enabled {
+            return Ok(permit(false));
+        }
+        let mut inner = self.inner.lock().unwrap();
+        let now = Instant::now();
+        match inner.state {
+            State::Closed => Ok(permit(false)),
+            State::Open { until, forced } => {
+                if forced || now < until {
+                    // Report at least one second so `Retry-After` is useful
+                    return Err(until.saturating_duration_since(now).max(Duration::from_secs(1)));
+                }
+                inner.state = State::HalfOpen { in_flight: 1 };
+                Ok(permit(true))
+            }
+            State::HalfOpen { in_flight } => {
+                if in_flight >= self.settings.half_open_max_calls {
+                    return Err(Duration::from_secs(1));
+                }
+                inner.state = State::HalfOpen { in_flight: in_flight + 1 };
+                Ok(permit(true))
+            }
+        }
+    }
+
+    fn on_result(&self, probe: bool, success: bool) {
+        if !self.settings.enabled {
+            return;
+        }
+        let second = self.second();
+        let mut inner = self.inner.lock().unwrap();
+        if probe {
+            if let State::HalfOpen { .. } = inner.state {
+                if success {
+                    self.close(&mut inner);
+                } else {
+                    self.open(&mut inner, false);
+                }
+            }
+            return;
+        }
+        if inner.state != State::Closed {
+            return;
+        }
+        let len = inner.buckets.len() as u64;
+        let bucket = &mut inner.buckets[(second % len) as usize];
+        if bucket.second != second {
+            *bucket = Bucket { second, ..Bucket::default() };
+        }
+        if success {
+            bucket.successes += 1;
+        } else {
+            bucket.failures += 1;
+        }
+        let (total, failures) = self.window_counts(&inner, second);
+        if total >= self.settings.minimum_requests
+            && failures as f64 / total as f64 >= self.settings.failure_rate_threshold
+        {
+            self.open(&mut inner, false);
+        }
+    }
+
+    // Calls and failures recorded within the rolling window
+    fn window_counts(&self, inner: &Inner, now: u64) -> (u32, u32) {
+        let len = inner.buckets.len() as u64;
+        inner
+            .buckets
+            .iter()
+            .filter(|b| b.second + len > now)
+            .fold((0, 0), |(t, f), b| (t + b.successes + b.failures, f + b.failures))
+    }
+
+    fn open(&self, inner: &mut Inner, forced: bool) {
+        inner.state = State::Open { until: Instant::now() + self.settings.open_for, forced };
+    }
+
+    fn close(&self, inner: &mut Inner) {
+        inner.state = State::Closed;
+        inner.buckets.iter_mut().for_each(|b| *b = Bucket::default());
+    }
+
+    // Force the breaker open until `reset` is called
+    pub fn trip(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        self.open(&mut inner, true);
+    }
+
+    pub fn reset(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        self.close(&mut inner);
+    }
+
+    // Current state for the admin endpoint
+    pub fn snapshot(&self) -> serde_json::Value {
+        let second = self.second();
+        let inner = self.inner.lock().unwrap();
+        let (total, failures) = self.window_counts(&inner, second);
+        let (state, open_remaining) = match inner.state {
+            State::Closed => ("closed", None),
+            State::Open { forced: true, .. } => ("forced_open", None),
+            State::Open { until, .. } => {
+                ("open", Some(until.saturating_duration_since(Instant::now()).as_secs()))
+            }
+            State::HalfOpen { .. } => ("half_open", None),
+        };
+        serde_json::json!({
+            "enabled": self.settings.enabled,
+            "state": state,
+            "open_remaining_secs": open_remaining,
+            "window_requests": total,
+            "window_failures": failures,
+            "failure_rate": if total > 0 { failures as f64 / total as f64 } else { 0.0 },
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn breaker(open_secs: u64) -> CircuitBreaker {
+        let file = FileBreaker {
+            minimum_requests: Some(4),
+            open_secs: Some(open_secs),
+            half_open_max_calls: Some(2),
+            ..Default::default()
+        };
+        CircuitBreaker::new(BreakerSettings::default().merge(&file).unwrap())
+    }
+
+    fn record(b: &CircuitBreaker, outcomes: &[bool]) {
+        for &success in outcomes {
+            b.acquire().unwrap().record(success);
+        }
+    }
+
+    fn state(b: &CircuitBreaker) -> State {
+        b.inner.lock().unwrap().state
+    }
+
+    #[test]
+    fn opens_once_the_failure_rate_is_reached() {
+        let b = breaker(30);
+        // Below minimum_requests the rate is not considered
+        record(&b, &[false, false, false]);
+        assert_eq!(state(&b), State::Closed);
+        b.reset();
+        record(&b, &[true, true, true, false, false]);
+        assert_eq!(state(&b), State::Closed);
+        // 3 failures in 6 calls
+        record(&b, &[false]);
+        assert!(matches!(state(&b), State::Open { forced: false, .. }));
+        let wait = b.acquire().err().unwrap();
+        assert!(wait > Duration::from_secs(28) && wait <= Duration::from_secs(30));
+    }
+
+    #[test]
+    fn successes_keep_it_closed() {
+        let b = breaker(30);
+        record(&b, &[true, true, true, false, false, true, true]);
+        assert_eq!(state(&b), State::Closed);
+        assert_eq!(b.snapshot()["window_requests"], 7);
+        assert_eq!(b.snapshot()["window_failures"], 2);
+    }
+
+    #[test]
+    fn half_open_limits_probes_and_closes_on_success() {
+        let b = breaker(0);
+        record(&b, &[false, false, false, false]);
+        // open_secs = 0: the next call is already a probe
+        let first = b.acquire().unwrap();
+        let second = b.acquire().unwrap();
+        assert_eq!(state(&b), State::HalfOpen { in_flight: 2 });
+        assert_eq!(b.acquire().err(), Some(Duration::from_secs(1)));
+        // An abandoned probe frees its slot
+        drop(second);
+        assert_eq!(state(&b), State::HalfOpen { in_flight: 1 });
+        first.record(true);
+        assert_eq!(state(&b), State::Closed);
+        assert_eq!(b.snapshot()["window_requests"], 0);
+    }
+
+    #[test]
+    fn failed_probe_reopens() {
+        let b = breaker(0);
+        record(&b, &[false, false, false, false]);
+        b.acquire().unwrap().record(false);
+        assert!(matches!(state(&b), State::Open { forced: false, .. }));
+    }
+
+    #[test]
+    fn tripped_breaker_stays_open_until_reset() {
+        let b = breaker(0);
+        b.trip();
+        assert!(b.acquire().is_err());
+        assert_eq!(b.snapshot()["state"], "forced_open");
+        b.reset();
+        assert!(b.acquire().is_ok());
+        assert_eq!(b.snapshot()["state"], "closed");
+    }
+
+    #[test]
+    fn disabled_breaker_always_allows() {
+        let file = FileBreaker { enabled: Some(false), minimum_requests: Some(1), ..Default::default() };
+        let b = CircuitBreaker::new(BreakerSettings::default().merge(&file).unwrap());
+        record(&b, &[false, false, false]);
+        assert!(b.acquire().is_ok());
+    }
+
+    #[test]
+    fn merge_validates() {
+        let merge = |file: FileBreaker| BreakerSettings::default().merge(&file);
+        assert!(merge(FileBreaker { failure_rate_threshold: Some(0.0), ..Default::default() }).is_err());
+        assert!(merge(FileBreaker { failure_rate_threshold: Some(1.5), ..Default::default() }).is_err());
+        assert!(merge(FileBreaker { window_secs: Some(0), ..Default::default() }).is_err());
+        assert!(merge(FileBreaker { half_open_max_calls: Some(0), ..Default::default() }).is_err());
+    }
+}
@@ -0,0 +1,573 @@
+use crate::AppState;
+use bytes::Bytes;
+use hyper::header::{HeaderMap, HeaderName, HeaderValue, AGE, CACHE_CONTROL, DATE, EXPIRES, PRAGMA, SET_COOKIE, VARY};
+use hyper::{Body, Response, StatusCode};
+use reqwest::Url;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tokio::time::Instant;
+
+pub const X_CACHE: &str = "x-cache";
+
+// Statuses a shared cache may store when freshness is given (RFC 9110
+// section 15.1)
+const CACHEABLE_STATUSES: &[u16] = &[200, 203, 204, 300, 301, 308, 404, 405, 410, 414, 501];
+
+// How often entries past every stale window are dropped
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+// Headers describing one exchange rather than the cached response
+pub const UNCACHED_HEADERS: &[&str] = &["age", "x-retry-count"];
+
+// Cache settings as written in the config file. The `[cache]` section sets
+// the default for every route and a route-level table only needs to list
+// what it overrides. Either table enables caching unless it sets
+// `enabled = false`.
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FileCache {
+    enabled: Option<bool>,
+    // Freshness for responses that do not state their own
+    ttl_secs: Option<u64>,
+    stale_while_revalidate_secs: Option<u64>,
+    stale_if_error_secs: Option<u64>,
+    // Request headers that take part in the cache key
+    vary_headers: Option<Vec<String>>,
+    // Only valid in `[cache]`
+    max_bytes: Option<usize>,
+    max_entry_bytes: Option<usize>,
+}
+
+impl FileCache {
+    pub fn has_store_settings(&self) -> bool {
+        self.max_bytes.is_some() || self.max_entry_bytes.is_some()
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct CacheSettings {
+    enabled: bool,
+    ttl: Duration,
+    stale_while_revalidate: Duration,
+    stale_if_error: Duration,
+    vary_headers: Vec<HeaderName>,
+}
+
+impl CacheSettings {
+    // Apply the fields set in `file` on top of `self`
+    pub fn merge(&self, file: &FileCache) -> Result<CacheSettings, String> {
+        let mut s = self.clone();
+        s.enabled = file.enabled.unwrap_or(true);
+        if let Some(secs) = file.ttl_secs {
+            s.ttl = Duration::from_secs(secs);
+        }
+        if let Some(secs) = file.stale_while_revalidate_secs {
+            s.stale_while_revalidate = Duration::from_secs(secs);
+        }
+        if let Some(secs) = file.stale_if_error_secs {
+            s.stale_if_error = Duration::from_secs(secs);
+        }
+        if let Some(names) = &file.vary_headers {
+            s.vary_headers = names
+                .iter()
+                .map(|n| HeaderName::from_bytes(n.as_bytes()).map_err(|_| format!("invalid header name '{}' in vary_headers", n)))
+                .collect::<Result<_, _>>()?;
+        }
+        Ok(s)
+    }
+
+    // The caching to apply, if any
+    pub fn policy(&self) -> Option<CachePolicy> {
+        self.enabled.then(|| CachePolicy {
+            ttl: self.ttl,
+            stale_while_revalidate: self.stale_while_revalidate,
+            stale_if_error: self.stale_if_error,
+            vary_headers: self.vary_headers.clone(),
+        })
+    }
+}
+
+// How a route's responses are cached. The upstream's `Cache-Control` and
+// `Expires` take precedence over these defaults.
+#[derive(Debug, Clone)]
+pub struct CachePolicy {
+    pub ttl: Duration,
+    pub stale_while_revalidate: Duration,
+    pub stale_if_error: Duration,
+    pub vary_headers: Vec<HeaderName>,
+}
+
+#[derive(Debug, Clone)]
+pub struct CacheConfig {
+    // Total size of cached bodies and headers; least recently used entries
+    // are evicted beyond it
+    pub max_bytes: usize,
+    // Larger responses are not stored
+    pub max_entry_bytes: usize,
+}
+
+impl Default for CacheConfig {
+    fn default() -> CacheConfig {
+        CacheConfig { max_bytes: 64 * 1024 * 1024, max_entry_bytes: 1024 * 1024 }
+    }
+}
+
+impl CacheConfig {
+    pub fn from_file(file: &FileCache) -> Result<CacheConfig, String> {
+        let mut c = CacheConfig::default();
+        if let Some(n) = file.max_bytes {
+            c.max_bytes = n;
+        }
+        match file.max_entry_bytes {
+            Some(n) => c.max_entry_bytes = n,
+            None => c.max_entry_bytes = c.max_entry_bytes.min(c.max_bytes),
+        }
+        if c.max_entry_bytes > c.max_bytes {
+            return Err(format!("max_entry_bytes ({}) must not exceed max_bytes ({})", c.max_entry_bytes, c.max_bytes));
+        }
+        Ok(c)
+    }
+}
+
+// `Cache-Control` directives, lower-cased, with their values
+fn directives(headers: &HeaderMap) -> Vec<(String, Option<String>)> {
+    headers
+        .get_all(CACHE_CONTROL)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .filter(|d| !d.trim().is_empty())
+        .map(|d| match d.split_once('=') {
+            Some((name, value)) => (name.trim().to_ascii_lowercase(), Some(value.trim().trim_matches('"').to_string())),
+            None => (d.trim().to_ascii_lowercase(), None),
+        })
+        .collect()
+}
+
+// Largest delta-seconds value a cache uses; anything bigger, including
+// values that do not fit an integer, counts as this (RFC 9111 section 1.2.2)
+const MAX_DELTA_SECONDS: u64 = 1 << 31;
+
+fn delta_seconds(value: &str) -> Option<Duration> {
+    if value.is_empty() || !value.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let secs = value.parse::<u64>().unwrap_or(MAX_DELTA_SECONDS);
+    Some(Duration::from_secs(secs.min(MAX_DELTA_SECONDS)))
+}
+
+fn seconds(directives: &[(String, Option<String>)], name: &str) -> Option<Duration> {
+    directives
+        .iter()
+        .find(|(n, _)| n == name)
+        .and_then(|(_, v)| delta_seconds(v.as_deref()?))
+}
+
+// What the caller allows: `(use a stored response, store the new one)`
+pub fn request_allows(headers: &HeaderMap) -> (bool, bool) {
+    let d = directives(headers);
+    let has = |name: &str| d.iter().any(|(n, _)| n == name);
+    let no_cache = has("no-cache") || headers.get(PRAGMA).is_some_and(|v| v == "no-cache");
+    if has("no-store") {
+        return (false, false);
+    }
+    (!no_cache, true)
+}
+
+// Identifies one cached response
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct CacheKey([u8; 32]);
+
+impl CacheKey {
+    // Route name (which includes the method), upstream URL, the body as sent
+    // upstream with its object keys sorted, and the route's `vary_headers`
+    pub fn new(route: &str, url: &Url, body: Option<&serde_json::Value>, vary: &[HeaderName], headers: &HeaderMap) -> CacheKey {
+        let mut hasher = Sha256::new();
+        for part in [route, url.as_str()] {
+            hasher.update(part.as_bytes());
+            hasher.update([0]);
+        }
+        if let Some(body) = body {
+            let mut canonical = String::new();
+            canonical_json(body, &mut canonical);
+            hasher.update(canonical.as_bytes());
+        }
+        hasher.update([0]);
+        for name in vary {
+            hasher.update(name.as_str().as_bytes());
+            for value in headers.get_all(name) {
+                hasher.update([1]);
+                hasher.update(value.as_bytes());
+            }
+            hasher.update([0]);
+        }
+        CacheKey(hasher.finalize().into())
+    }
+}
+
+// JSON with object keys in sorted order, so payloads differing only in key
+// order share an entry
+pub fn canonical_json(value: &serde_json::Value, out: &mut String) {
+    match value {
+        serde_json::Value::Object(map) => {
+            let mut keys: Vec<&String> = map.keys().collect();
+            keys.sort();
+            out.push('{');
+            for (i, key) in keys.into_iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                out.push_str(&serde_json::Value::String(key.clone()).to_string());
+                out.push(':');
+                canonical_json(&map[key], out);
+            }
+            out.push('}');
+        }
+        serde_json::Value::Array(items) => {
+            out.push('[');
+            for (i, item) in items.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                canonical_json(item, out);
+            }
+            out.push(']');
+        }
+        other => out.push_str(&other.to_string()),
+    }
+}
+
+// How long a response may be served, from its headers and the route policy
+struct Freshness {
+    ttl: Duration,
+    stale_while_revalidate: Duration,
+    stale_if_error: Duration,
+    // Age the response already had when we received it
+    initial_age: Duration,
+}
+
+// None when the response must not be stored
+fn freshness(status: StatusCode, headers: &HeaderMap, policy: &CachePolicy) -> Option<Freshness> {
+    if !CACHEABLE_STATUSES.contains(&status.as_u16()) || headers.contains_key(SET_COOKIE) {
+        return None;
+    }
+    // The key only distinguishes the route's `vary_headers`
+    let varies_elsewhere = headers
+        .get_all(VARY)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(str::trim)
+        .filter(|v| !v.is_empty())
+        .any(|v| v == "*" || !policy.vary_headers.iter().any(|h| h.as_str().eq_ignore_ascii_case(v)));
+    if varies_elsewhere {
+        return None;
+    }
+    let d = directives(headers);
+    if d.iter().any(|(n, _)| n == "no-store" || n == "no-cache" || n == "private") {
+        return None;
+    }
+    let http_date = |name| {
+        headers
+            .get(name)
+            .and_then(|v: &HeaderValue| v.to_str().ok())
+            .and_then(|v| httpdate::parse_http_date(v).ok())
+    };
+    let expires = || {
+        let expires = http_date(EXPIRES)?;
+        let now = http_date(DATE).unwrap_or_else(SystemTime::now);
+        let lifetime = expires.duration_since(now).unwrap_or_default();
+        Some(lifetime.min(Duration::from_secs(MAX_DELTA_SECONDS)))
+    };
+    let lifetime = seconds(&d, "s-maxage")
+        .or_else(|| seconds(&d, "max-age"))
+        .or_else(|| {
+            // An unparseable `Expires` means already expired
+            headers.contains_key(EXPIRES).then(|| expires().unwrap_or_default())
+        })
+        .unwrap_or(policy.ttl);
+    let initial_age = headers
+        .get(AGE)
+        .and_then(|v| delta_seconds(v.to_str().ok()?.trim()))
+        .unwrap_or_default();
+    let ttl = lifetime.saturating_sub(initial_age);
+    if ttl.is_zero() {
+        return None;
+    }
+    Some(Freshness {
+        ttl,
+        stale_while_revalidate: seconds(&d, "stale-while-revalidate").unwrap_or(policy.stale_while_revalidate),
+        stale_if_error: seconds(&d, "stale-if-error").unwrap_or(policy.stale_if_error),
+        initial_age,
+    })
+}
+
+struct Entry {
+    // Route pattern, for purging by route
+    route: String,
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+    stored: Instant,
+    freshness: Freshness,
+    size: usize,
+    // Position in the LRU order
+    used: u64,
+    // A background refresh is under way
+    revalidating: bool,
+}
+
+impl Entry {
+    fn response(&self, x_cache: &'static str) -> Response<Body> {
+        let mut response = Response::new(Body::from(self.body.clone()));
+        *response.status_mut() = self.status;
+        *response.headers_mut() = self.headers.clone();
+        let age = self.freshness.initial_age + self.stored.elapsed();
+        response.headers_mut().insert(AGE, HeaderValue::from(age.as_secs()));
+        response.headers_mut().insert(X_CACHE, HeaderValue::from_static(x_cache));
+        response
+    }
+
+    // Past this, nothing can be served from the entry. None when it lies
+    // beyond what an `Instant` can represent, which means never.
+    fn expires(&self) -> Option<Instant> {
+        let f = &self.freshness;
+        self.stored.checked_add(f.ttl + f.stale_while_revalidate.max(f.stale_if_error))
+    }
+}
+
+// What the cache holds for a request
+pub enum Lookup {
+    Fresh(Response<Body>),
+    // Past its freshness but within stale-while-revalidate. `revalidate` is
+    // set for the one request that should refresh it.
+    Stale { response: Response<Body>, revalidate: bool },
+    // Only to be served if the upstream fails
+    StaleIfError(Response<Body>),
+    Miss,
+}
+
+#[derive(Default)]
+struct Store {
+    entries: HashMap<CacheKey, Entry>,
+    // Entries by last use, oldest first
+    lru: BTreeMap<u64, CacheKey>,
+    bytes: usize,
+    next_use: u64,
+}
+
+impl Store {
+    fn remove(&mut self, key: &CacheKey) -> Option<Entry> {
+        let entry = self.entries.remove(key)?;
+        self.lru.remove(&entry.used);
+        self.bytes -= entry.size;
+        Some(entry)
+    }
+
+    fn touch(&mut self, key: &CacheKey) {
+        let used = self.next_use;
+        self.next_use += 1;
+        if let Some(entry) = self.entries.get_mut(key) {
+            self.lru.remove(&entry.used);
+            entry.used = used;
+            self.lru.insert(used, *key);
+        }
+    }
+}
+
+// In-memory response cache shared by all routes, bounded by size
+pub struct Cache {
+    config: CacheConfig,
+    store: Mutex<Store>,
+}
+
+impl Cache {
+    pub fn new(config: CacheConfig) -> Cache {
+        Cache { config, store: Mutex::new(Store::default()) }
+    }
+
+    pub fn lookup(&self, key: &CacheKey) -> Lookup {
+        let mut store = self.store.lock().unwrap();
+        let now = Instant::now();
+        let Some(entry) = store.entries.get_mut(key) else {
+            return Lookup::Miss;
+        };
+        let age = now.saturating_duration_since(entry.stored);
+        let f = &entry.freshness;
+        let lookup = if age < f.ttl {
+            Lookup::Fresh(entry.response("HIT"))
+        } else if age < f.ttl + f.stale_while_revalidate {
+            let revalidate = !entry.revalidating;
+            entry.revalidating = true;
+            Lookup::Stale { response: entry.response("STALE"), revalidate }
+        } else if age < f.ttl + f.stale_if_error {
+            Lookup::StaleIfError(entry.response("STALE"))
+        } else {
+            store.remove(key);
+            return Lookup::Miss;
+        };
+        store.touch(key);
+        lookup
+    }
+
+    // Store `response` if it is cacheable, and return it marked as a miss
+    pub async fn store(&self, key: CacheKey, route: &str, policy: &CachePolicy, response: Response<Body>) -> Response<Body> {
+        let (mut parts, body) = response.into_parts();
+        parts.headers.insert(X_CACHE, HeaderValue::from_static("MISS"));
+        // Relayed bodies are already in memory
+        let body = match hyper::body::to_bytes(body).await {
+            Ok(b) => b,
+            Err(_) => return Response::from_parts(parts, Body::empty()),
+        };
+        let Some(freshness) = freshness(parts.status, &parts.headers, policy) else {
+            self.finish_revalidation(&key);
+            return Response::from_parts(parts, Body::from(body));
+        };
+        let mut headers = parts.headers.clone();
+        for name in UNCACHED_HEADERS.iter().copied().chain([X_CACHE]) {
+            headers.remove(name);
+        }
+        let size = body.len() + headers.iter().map(|(n, v)| n.as_str().len() + v.len()).sum::<usize>();
+        if size > self.config.max_entry_bytes {
+            self.finish_revalidation(&key);
+            return Response::from_parts(parts, Body::from(body));
+        }
+        let entry = Entry {
+            route: route.to_string(),
+            status: parts.status,
+            headers,
+            body: body.clone(),
+            stored: Instant::now(),
+            freshness,
+            size,
+            used: 0,
+            revalidating: false,
+        };
+        let mut store = self.store.lock().unwrap();
+        store.remove(&key);
+        while store.bytes + size > self.config.max_bytes {
+            let Some((_, oldest)) = store.lru.pop_first() else {
+                break;
+            };
+            if let Some(evicted) = store.entries.remove(&oldest) {
+                store.bytes -= evicted.size;
+            }
+        }
+        store.bytes += size;
+        store.entries.insert(key, entry);
+        store.touch(&key);
+        Response::from_parts(parts, Body::from(body))
+    }
+
+    // Let the next stale hit try again after a refresh that stored nothing
+    pub fn finish_revalidation(&self, key: &CacheKey) {
+        if let Some(entry) = self.store.lock().unwrap().entries.get_mut(key) {
+            entry.revalidating = false;
+        }
+    }
+
+    // Drop every entry, or those of one route pattern. Returns how many.
+    pub fn purge(&self, route: Option<&str>) -> usize {
+        let mut store = self.store.lock().unwrap();
+        let keys: Vec<CacheKey> = store
+            .entries
+            .iter()
+            .filter(|(_, e)| route.is_none_or(|r| e.route == r))
+            .map(|(k, _)| *k)
+            .collect();
+        for key in &keys {
+            store.remove(key);
+        }
+        keys.len()
+    }
+
+    // Drop entries that can no longer be served
+    fn sweep(&self) {
+        let mut store = self.store.lock().unwrap();
+        let now = Instant::now();
+        let expired: Vec<CacheKey> = store.entries.iter().filter(|(_, e)| e.expires().is_some_and(|t| t <= now)).map(|(k, _)| *k).collect();
+        for key in &expired {
+            store.remove(key);
+        }
+    }
+
+    pub fn stats(&self) -> serde_json::Value {
+        let store = self.store.lock().unwrap();
+        serde_json::json!({
+            "entries": store.entries.len(),
+            "bytes": store.bytes,
+            "max_bytes": self.config.max_bytes,
+        })
+    }
+}
+
+pub fn spawn_sweep(state: Arc<AppState>) {
+    let mut interval = tokio::time::interval(SWEEP_INTERVAL);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    tokio::spawn(async move {
+        loop {
+            interval.tick().await;
+            state.cache.sweep();
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy() -> CachePolicy {
+        CachePolicy {
+            ttl: Duration::ZERO,
+            stale_while_revalidate: Duration::ZERO,
+            stale_if_error: Duration::ZERO,
+            vary_headers: Vec::new(),
+        }
+    }
+
+    fn with_cache_control(value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(CACHE_CONTROL, HeaderValue::from_str(value).unwrap());
+        headers
+    }
+
+    #[test]
+    fn delta_seconds_are_clamped() {
+        let max = Duration::from_secs(MAX_DELTA_SECONDS);
+        assert_eq!(delta_seconds("60"), Some(Duration::from_secs(60)));
+        assert_eq!(delta_seconds("18446744073709551615"), Some(max));
+        assert_eq!(delta_seconds("99999999999999999999999999"), Some(max));
+        assert_eq!(delta_seconds("-1"), None);
+        assert_eq!(delta_seconds(""), None);
+    }
+
+    #[test]
+    fn huge_max_age_is_clamped() {
+        let headers = with_cache_control("max-age=18446744073709551615, stale-while-revalidate=18446744073709551615");
+        let f = freshness(StatusCode::OK, &headers, &policy()).unwrap();
+        assert_eq!(f.ttl, Duration::from_secs(MAX_DELTA_SECONDS));
+        assert_eq!(f.stale_while_revalidate, Duration::from_secs(MAX_DELTA_SECONDS));
+    }
+
+    #[tokio::test]
+    async fn huge_max_age_survives_sweep() {
+        let cache = Cache::new(CacheConfig::default());
+        let url = Url::parse("http://upstream/get").unwrap();
+        let key = CacheKey::new("GET /get", &url, None, &[], &HeaderMap::new());
+        let mut response = Response::new(Body::from("ok"));
+        response.headers_mut().insert(CACHE_CONTROL, HeaderValue::from_static("max-age=18446744073709551615"));
+        cache.store(key, "/get", &policy(), response).await;
+        cache.sweep();
+        assert!(matches!(cache.lookup(&key), Lookup::Fresh(_)));
+        assert_eq!(cache.stats()["entries"], 1);
+    }
+
+    #[test]
+    fn no_store_is_not_cached() {
+        let headers = with_cache_control("max-age=60, no-store");
+        assert!(freshness(StatusCode::OK, &headers, &policy()).is_none());
+    }
+}
@@ -0,0 +1,253 @@
+use crate::cache::CacheKey;
+use crate::errors::ApiError;
+use bytes::Bytes;
+use hyper::header::HeaderMap;
+use hyper::{Body, Response, StatusCode};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::watch;
+
+// Coalescing settings as written in the config file. The `[coalesce]`
+// section sets the default for every route and a route-level table only
+// needs to list what it overrides. Either table enables coalescing unless
+// it sets `enabled = false`.
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FileCoalesce {
+    enabled: Option<bool>,
+    // Longest a request waits on an identical call before making its own
+    max_wait_ms: Option<u64>,
+}
+
+#[derive(Debug, Clone)]
+pub struct CoalesceSettings {
+    enabled: bool,
+    max_wait: Duration,
+}
+
+impl Default for CoalesceSettings {
+    fn default() -> CoalesceSettings {
+        CoalesceSettings { enabled: false, max_wait: Duration::from_secs(5) }
+    }
+}
+
+impl CoalesceSettings {
+    // Apply the fields set in `file` on top of `self`
+    pub fn merge(&self, file: &FileCoalesce) -> Result<CoalesceSettings, String> {
+        let mut s = self.clone();
+        s.enabled = file.enabled.unwrap_or(true);
+        if let Some(ms) = file.max_wait_ms {
+            if ms == 0 {
+                return Err("max_wait_ms must be greater than 0".to_string());
+            }
+            s.max_wait = Duration::from_millis(ms);
+        }
+        Ok(s)
+    }
+
+    // The coalescing to apply, if any
+    pub fn policy(&self) -> Option<CoalescePolicy> {
+        self.enabled.then_some(CoalescePolicy { max_wait: self.max_wait })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CoalescePolicy {
+    pub max_wait: Duration,
+}
+
+// A finished call as handed to every request that waited on it
+#[derive(Debug)]
+struct Shared {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+}
+
+type Outcome = Option<Arc<Result<Shared, ApiError>>>;
+
+fn rebuild(outcome: &Result<Shared, ApiError>) -> Result<Response<Body>, ApiError> {
+    match outcome {
+        Ok(shared) => {
+            let mut response = Response::new(Body::from(shared.body.clone()));
+            *response.status_mut() = shared.status;
+            *response.headers_mut() = shared.headers.clone();
+            Ok(response)
+        }
+        Err(e) => Err(e.clone()),
+    }
+}
+
+// Upstream calls in progress, keyed like the response cache, so identical
+// concurrent requests make one call between them
+#[derive(Default)]
+pub struct Coalescer {
+    flights: Mutex<HashMap<CacheKey, (u64, watch::Receiver<Outcome>)>>,
+    next_id: AtomicU64,
+}
+
+pub enum Join<'a> {
+    // No identical call is in progress; the caller makes it and hands the
+    // result to `Flight::finish`
+    Leader(Flight<'a>),
+    // Another request is making the call
+    Follower(Waiter),
+}
+
+impl Coalescer {
+    pub fn join(&self, key: CacheKey) -> Join<'_> {
+        let mut flights = self.flights.lock().unwrap();
+        if let Some((_, rx)) = flights.get(&key) {
+            return Join::Follower(Waiter { rx: rx.clone() });
+        }
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = watch::channel(None);
+        flights.insert(key, (id, rx));
+        Join::Leader(Flight { coalescer: self, key, id, tx })
+    }
+}
+
+// The call made on behalf of every request joining it. Dropping it
+// unfinished, as happens when the leading client disconnects, releases the
+// waiters to make their own calls.
+pub struct Flight<'a> {
+    coalescer: &'a Coalescer,
+    key: CacheKey,
+    id: u64,
+    tx: watch::Sender<Outcome>,
+}
+
+impl Flight<'_> {
+    // Share the call's result with the waiters and return it to the leader.
+    // Relayed bodies are already in memory, so buffering them costs nothing.
+    pub async fn finish(self, result: Result<Response<Body>, ApiError>) -> Result<Response<Body>, ApiError> {
+        let outcome = match result {
+            Ok(response) => {
+                let (parts, body) = response.into_parts();
+                match hyper::body::to_bytes(body).await {
+                    Ok(body) => Ok(Shared { status: parts.status, headers: parts.headers, body }),
+                    Err(_) => return Ok(Response::from_parts(parts, Body::empty())),
+                }
+            }
+            Err(e) => Err(e),
+        };
+        let response = rebuild(&outcome);
+        self.tx.send_replace(Some(Arc::new(outcome)));
+        response
+    }
+}
+
+impl Drop for Flight<'_> {
+    fn drop(&mut self) {
+        let mut flights = self.coalescer.flights.lock().unwrap();
+        if flights.get(&self.key).is_some_and(|(id, _)| *id == self.id) {
+            flights.remove(&self.key);
+        }
+    }
+}
+
+pub struct Waiter {
+    rx: watch::Receiver<Outcome>,
+}
+
+impl Waiter {
+    // The leader's result, or None if it did not arrive within `max_wait`
+    // or the leader gave up
+    pub async fn wait(mut self, max_wait: Duration) -> Option<Result<Response<Body>, ApiError>> {
+        let outcome = tokio::time::timeout(max_wait, self.rx.wait_for(Option::is_some)).await.ok()?.ok()?;
+        outcome.as_deref().map(rebuild)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::Url;
+
+    fn key(path: &str) -> CacheKey {
+        let url = Url::parse(&format!("http://upstream{}", path)).unwrap();
+        CacheKey::new("GET /items", &url, None, &[], &HeaderMap::new())
+    }
+
+    fn leader<'a>(coalescer: &'a Coalescer, path: &str) -> Flight<'a> {
+        match coalescer.join(key(path)) {
+            Join::Leader(flight) => flight,
+            Join::Follower(_) => panic!("expected to lead"),
+        }
+    }
+
+    fn follower(coalescer: &Coalescer, path: &str) -> Waiter {
+        match coalescer.join(key(path)) {
+            Join::Follower(waiter) => waiter,
+            Join::Leader(_) => panic!("expected to follow"),
+        }
+    }
+
+    async fn body(response: Response<Body>) -> Bytes {
+        hyper::body::to_bytes(response.into_body()).await.unwrap()
+    }
+
+    const WAIT: Duration = Duration::from_secs(5);
+
+    #[tokio::test]
+    async fn followers_share_the_leaders_response() {
+        let coalescer = Coalescer::default();
+        let flight = leader(&coalescer, "/a");
+        let waiters = [follower(&coalescer, "/a"), follower(&coalescer, "/a")];
+        // A different request is not merged with the call in progress
+        let other = leader(&coalescer, "/b");
+
+        let mut upstream = Response::new(Body::from("shared"));
+        *upstream.status_mut() = StatusCode::CREATED;
+        upstream.headers_mut().insert("x-upstream", "1".parse().unwrap());
+        let led = flight.finish(Ok(upstream)).await.unwrap();
+        assert_eq!(body(led).await, "shared");
+        for waiter in waiters {
+            let response = waiter.wait(WAIT).await.unwrap().unwrap();
+            assert_eq!(response.status(), StatusCode::CREATED);
+            assert_eq!(response.headers()["x-upstream"], "1");
+            assert_eq!(body(response).await, "shared");
+        }
+        // The next identical request makes a new call
+        leader(&coalescer, "/a");
+        drop(other);
+    }
+
+    #[tokio::test]
+    async fn followers_give_up_after_max_wait() {
+        let coalescer = Coalescer::default();
+        let _flight = leader(&coalescer, "/a");
+        let started = tokio::time::Instant::now();
+        assert!(follower(&coalescer, "/a").wait(Duration::from_millis(50)).await.is_none());
+        assert!(started.elapsed() >= Duration::from_millis(50));
+    }
+
+    #[tokio::test]
+    async fn cancelled_leader_releases_followers() {
+        let coalescer = Coalescer::default();
+        let flight = leader(&coalescer, "/a");
+        let waiter = follower(&coalescer, "/a");
+        let (outcome, _) = tokio::join!(waiter.wait(WAIT), async { drop(flight) });
+        assert!(outcome.is_none());
+        // Nothing is left behind for later requests to wait on
+        leader(&coalescer, "/a");
+    }
+
+    #[tokio::test]
+    async fn failed_leader_shares_its_error_once() {
+        let coalescer = Coalescer::default();
+        let flight = leader(&coalescer, "/a");
+        let waiter = follower(&coalescer, "/a");
+        let led = flight.finish(Err(ApiError::bad_gateway("connection refused"))).await;
+        assert_eq!(led.unwrap_err().status, StatusCode::BAD_GATEWAY);
+        let followed = waiter.wait(WAIT).await.unwrap();
+        assert_eq!(followed.unwrap_err().status, StatusCode::BAD_GATEWAY);
+        // The failure is not handed to requests arriving afterwards
+        let flight = leader(&coalescer, "/a");
+        let ok = flight.finish(Ok(Response::new(Body::from("recovered")))).await.unwrap();
+        assert_eq!(body(ok).await, "recovered");
+    }
+}
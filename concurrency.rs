@@ -0,0 +1,422 @@
+use serde::Deserialize;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::oneshot;
+use tokio::time::Instant;
+
+// Share of the limit kept after an AIMD decrease
+const AIMD_BACKOFF: f64 = 0.9;
+// Weight of each latency sample in the gradient's long-term average
+const GRADIENT_LONG_WEIGHT: f64 = 0.05;
+// How far each gradient update moves the limit towards its target
+const GRADIENT_SMOOTHING: f64 = 0.2;
+
+// Concurrency settings as written in the config file, either globally in
+// `[concurrency]` or for one route
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FileConcurrency {
+    max_in_flight: Option<u32>,
+    min_in_flight: Option<u32>,
+    queue_size: Option<u32>,
+    queue_timeout_ms: Option<u64>,
+    // `none`, `aimd` or `gradient`
+    adaptive: Option<String>,
+    latency_threshold_ms: Option<u64>,
+}
+
+// How the limit follows upstream behaviour
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Adaptive {
+    // Always `max_in_flight`
+    None,
+    // Grow by one while busy and healthy, shrink on failures or slow calls
+    Aimd,
+    // Track the ratio of long-term to recent latency
+    Gradient,
+}
+
+#[derive(Debug, Clone)]
+pub struct ConcurrencySettings {
+    // Upper bound on concurrent upstream calls, and the starting limit
+    max_in_flight: u32,
+    // Floor for adaptive limits
+    min_in_flight: u32,
+    // Requests allowed to wait for a slot; more are shed immediately
+    queue_size: u32,
+    // Longest a request waits for a slot before it is shed
+    queue_timeout: Duration,
+    adaptive: Adaptive,
+    // AIMD treats slower calls like failures
+    latency_threshold: Duration,
+}
+
+impl Default for ConcurrencySettings {
+    fn default() -> ConcurrencySettings {
+        ConcurrencySettings {
+            max_in_flight: 100,
+            min_in_flight: 1,
+            queue_size: 50,
+            queue_timeout: Duration::from_secs(1),
+            adaptive: Adaptive::None,
+            latency_threshold: Duration::from_secs(2),
+        }
+    }
+}
+
+impl ConcurrencySettings {
+    // Apply the fields set in `file` on top of `self`
+    pub fn merge(&self, file: &FileConcurrency) -> Result<ConcurrencySettings, String> {
+        let mut s = self.clone();
+        if let Some(n) = file.max_in_flight {
+            if n == 0 {
+                return Err("max_in_flight must be at least 1".to_string());
+            }
+            s.max_in_flight = n;
+        }
+        if let Some(n) = file.min_in_flight {
+            if n == 0 {
+                return Err("min_in_flight must be at least 1".to_string());
+            }
+            s.min_in_flight = n;
+        }
+        if s.min_in_flight > s.max_in_flight {
+            return Err(format!(
+                "min_in_flight ({}) must not exceed max_in_flight ({})",
+                s.min_in_flight, s.max_in_flight
+            ));
+        }
+        if let Some(n) = file.queue_size {
+            s.queue_size = n;
+        }
+        if let Some(ms) = file.queue_timeout_ms {
+            s.queue_timeout = Duration::from_millis(ms);
+        }
+        if let Some(a) = &file.adaptive {
+            s.adaptive = match a.as_str() {
+                "none" => Adaptive::None,
+                "aimd" => Adaptive::Aimd,
+                "gradient" => Adaptive::Gradient,
+                _ => return Err(format!("unknown adaptive '{}' (expected none, aimd or gradient)", a)),
+            };
+        }
+        if let Some(ms) = file.latency_threshold_ms {
+            s.latency_threshold = Duration::from_millis(ms);
+        }
+        Ok(s)
+    }
+}
+
+// Why a request was turned away
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Shed {
+    QueueFull,
+    QueueTimeout,
+}
+
+impl Shed {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Shed::QueueFull => "queue_full",
+            Shed::QueueTimeout => "queue_timeout",
+        }
+    }
+}
+
+struct Inner {
+    in_flight: u32,
+    // Adaptive limits move in fractions; the usable limit is its floor
+    limit: f64,
+    // Requests waiting for a slot, oldest first
+    queue: VecDeque<(u64, oneshot::Sender<()>)>,
+    next_waiter: u64,
+    // Gradient's long-term latency average, in seconds
+    long_rtt: Option<f64>,
+}
+
+// Caps concurrent upstream calls, queueing a bounded number of requests
+// while the limit is reached
+pub struct Limiter {
+    pub name: String,
+    settings: ConcurrencySettings,
+    inner: Mutex<Inner>,
+}
+
+// A slot for one upstream call; report the outcome with `record`
+pub struct Permit<'a> {
+    limiter: &'a Limiter,
+    started: Instant,
+}
+
+impl Permit<'_> {
+    // Feed the call's latency and outcome to the adaptive limit, then
+    // release the slot. Dropping a permit releases it without a sample.
+    pub fn record(self, success: bool) {
+        self.limiter.adapt(self.started.elapsed(), success);
+    }
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        let mut inner = self.limiter.inner.lock().unwrap();
+        inner.in_flight -= 1;
+        self.limiter.hand_off(&mut inner);
+    }
+}
+
+// A queued request. Removes itself from the queue when the wait ends, and
+// returns a slot it was handed but never took.
+struct Waiting<'a> {
+    limiter: &'a Limiter,
+    id: u64,
+    rx: oneshot::Receiver<()>,
+}
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        let mut inner = self.limiter.inner.lock().unwrap();
+        inner.queue.retain(|(id, _)| *id != self.id);
+        if self.rx.try_recv().is_ok() {
+            inner.in_flight -= 1;
+            self.limiter.hand_off(&mut inner);
+        }
+    }
+}
+
+// Current state, sampled for the metrics endpoint
+pub struct Snapshot {
+    pub limit: u32,
+    pub in_flight: u32,
+    pub queued: usize,
+}
+
+impl Limiter {
+    pub fn new(name: String, settings: ConcurrencySettings) -> Limiter {
+        let inner = Inner {
+            in_flight: 0,
+            limit: f64::from(settings.max_in_flight),
+            queue: VecDeque::new(),
+            next_waiter: 0,
+            long_rtt: None,
+        };
+        Limiter { name, settings, inner: Mutex::new(inner) }
+    }
+
+    // Take a slot, waiting in the queue if there is room in it
+    pub async fn acquire(&self) -> Result<Permit<'_>, Shed> {
+        let mut waiting = {
+            let mut inner = self.inner.lock().unwrap();
+            if inner.queue.is_empty() && inner.in_flight < inner.limit as u32 {
+                inner.in_flight += 1;
+                return Ok(Permit { limiter: self, started: Instant::now() });
+            }
+            if inner.queue.len() >= self.settings.queue_size as usize {
+                return Err(Shed::QueueFull);
+            }
+            let (tx, rx) = oneshot::channel();
+            let id = inner.next_waiter;
+            inner.next_waiter += 1;
+            inner.queue.push_back((id, tx));
+            Waiting { limiter: self, id, rx }
+        };
+        match tokio::time::timeout(self.settings.queue_timeout, &mut waiting.rx).await {
+            // Receiving took the slot, so `Waiting` has nothing to give back
+            Ok(Ok(())) => Ok(Permit { limiter: self, started: Instant::now() }),
+            _ => Err(Shed::QueueTimeout),
+        }
+    }
+
+    // Pass free slots to queued requests that are still waiting
+    fn hand_off(&self, inner: &mut Inner) {
+        while inner.in_flight < inner.limit as u32 {
+            let Some((_, tx)) = inner.queue.pop_front() else {
+                break;
+            };
+            inner.in_flight += 1;
+            if tx.send(()).is_err() {
+                inner.in_flight -= 1;
+            }
+        }
+    }
+
+    fn adapt(&self, latency: Duration, success: bool) {
+        let s = &self.settings;
+        let (min, max) = (f64::from(s.min_in_flight), f64::from(s.max_in_flight));
+        let mut inner = self.inner.lock().unwrap();
+        // This call still holds its slot
+        let busy = f64::from(inner.in_flight) * 2.0 >= inner.limit;
+        match s.adaptive {
+            Adaptive::None => {}
+            Adaptive::Aimd => {
+                if !success || latency > s.latency_threshold {
+                    inner.limit = (inner.limit * AIMD_BACKOFF).max(min);
+                } else if busy {
+                    inner.limit = (inner.limit + 1.0).min(max);
+                }
+            }
+            Adaptive::Gradient => {
+                let rtt = latency.as_secs_f64().max(1e-6);
+                let long = match inner.long_rtt {
+                    Some(l) => l * (1.0 - GRADIENT_LONG_WEIGHT) + rtt * GRADIENT_LONG_WEIGHT,
+                    None => rtt,
+                };
+                inner.long_rtt = Some(long);
+                // Below 1 when recent calls are slower than usual. Failures
+                // count as a strong signal to back off.
+                let gradient = if success { (long / rtt).clamp(0.5, 1.0) } else { 0.5 };
+                // Headroom so the limit can grow while latency holds steady
+                let target = inner.limit * gradient + inner.limit.sqrt();
+                if target < inner.limit || busy {
+                    let next = inner.limit * (1.0 - GRADIENT_SMOOTHING) + target * GRADIENT_SMOOTHING;
+                    inner.limit = next.clamp(min, max);
+                }
+            }
+        }
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        let inner = self.inner.lock().unwrap();
+        Snapshot { limit: inner.limit as u32, in_flight: inner.in_flight, queued: inner.queue.len() }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(toml: &str) -> Limiter {
+        let settings = ConcurrencySettings::default().merge(&toml::from_str(toml).unwrap()).unwrap();
+        Limiter::new("test".to_string(), settings)
+    }
+
+    // Hold `n` slots
+    async fn take_permits(limiter: &Limiter, n: usize) -> Vec<Permit<'_>> {
+        let mut permits = Vec::new();
+        for _ in 0..n {
+            permits.push(limiter.acquire().await.unwrap());
+        }
+        permits
+    }
+
+    // Poll `fut` once so it joins the queue, without waiting for it
+    async fn enqueue<F: std::future::Future + Unpin>(fut: &mut F) {
+        tokio::select! {
+            biased;
+            _ = fut => panic!("expected the request to wait"),
+            _ = std::future::ready(()) => {}
+        }
+    }
+
+    const FAST: Duration = Duration::from_millis(10);
+    const SLOW: Duration = Duration::from_millis(500);
+
+    #[tokio::test]
+    async fn aimd_shrinks_on_failures_and_grows_while_busy() {
+        let limiter = limiter("max_in_flight = 10\nmin_in_flight = 2\nadaptive = \"aimd\"\nlatency_threshold_ms = 100");
+        let permits = take_permits(&limiter, 8).await;
+        limiter.adapt(FAST, false);
+        assert_eq!(limiter.snapshot().limit, 9);
+        limiter.adapt(SLOW, true);
+        assert_eq!(limiter.snapshot().limit, 8);
+        for _ in 0..30 {
+            limiter.adapt(FAST, false);
+        }
+        assert_eq!(limiter.snapshot().limit, 2);
+
+        limiter.adapt(FAST, true);
+        limiter.adapt(FAST, true);
+        assert_eq!(limiter.snapshot().limit, 4);
+
+        // Fast successes while mostly idle leave the limit alone
+        drop(permits);
+        limiter.adapt(FAST, true);
+        assert_eq!(limiter.snapshot().limit, 4);
+    }
+
+    #[tokio::test]
+    async fn gradient_follows_latency() {
+        let limiter = limiter("max_in_flight = 40\nadaptive = \"gradient\"");
+        let _permits = take_permits(&limiter, 20).await;
+        limiter.adapt(FAST, true);
+        assert_eq!(limiter.snapshot().limit, 40);
+
+        // Calls much slower than the long-term average pull the limit down
+        let mut last = 40;
+        for _ in 0..10 {
+            limiter.adapt(SLOW, true);
+            let limit = limiter.snapshot().limit;
+            assert!(limit <= last, "{} > {}", limit, last);
+            last = limit;
+        }
+        assert!(last < 30, "{}", last);
+
+        // Once latency settles the limit climbs back while busy
+        for _ in 0..200 {
+            limiter.adapt(SLOW, true);
+        }
+        assert!(limiter.snapshot().limit > last);
+    }
+
+    #[tokio::test]
+    async fn full_queue_sheds_at_once_and_waiters_time_out() {
+        let limiter = limiter("max_in_flight = 1\nqueue_size = 1\nqueue_timeout_ms = 50");
+        let _held = limiter.acquire().await.unwrap();
+        let started = Instant::now();
+        let (waiter, extra) = tokio::join!(limiter.acquire(), async {
+            let shed = limiter.acquire().await.err();
+            (shed, started.elapsed())
+        });
+        assert_eq!(extra.0, Some(Shed::QueueFull));
+        assert!(extra.1 < Duration::from_millis(50));
+        assert_eq!(waiter.err(), Some(Shed::QueueTimeout));
+        assert!(started.elapsed() >= Duration::from_millis(50));
+        assert_eq!(limiter.snapshot().queued, 0);
+    }
+
+    #[tokio::test]
+    async fn released_slots_go_to_the_oldest_waiter() {
+        let limiter = limiter("max_in_flight = 1\nqueue_timeout_ms = 5000");
+        let held = limiter.acquire().await.unwrap();
+        let order = Mutex::new(Vec::new());
+        let waiter = |label| {
+            let (limiter, order) = (&limiter, &order);
+            async move {
+                let permit = limiter.acquire().await.unwrap();
+                order.lock().unwrap().push(label);
+                tokio::time::sleep(FAST).await;
+                drop(permit);
+            }
+        };
+        tokio::join!(waiter("first"), waiter("second"), waiter("third"), async {
+            tokio::task::yield_now().await;
+            assert_eq!(limiter.snapshot().queued, 3);
+            drop(held);
+        });
+        assert_eq!(*order.lock().unwrap(), ["first", "second", "third"]);
+        assert_eq!(limiter.snapshot().in_flight, 0);
+    }
+
+    #[tokio::test]
+    async fn cancelled_waiters_do_not_leak_slots() {
+        let limiter = limiter("max_in_flight = 1\nqueue_timeout_ms = 5000");
+        let held = limiter.acquire().await.unwrap();
+
+        // Cancelled while queued
+        let mut waiting = Box::pin(limiter.acquire());
+        enqueue(&mut waiting).await;
+        assert_eq!(limiter.snapshot().queued, 1);
+        drop(waiting);
+        assert_eq!(limiter.snapshot().queued, 0);
+
+        // Cancelled after being handed the slot but before taking it
+        let mut waiting = Box::pin(limiter.acquire());
+        enqueue(&mut waiting).await;
+        drop(held);
+        assert_eq!(limiter.snapshot().in_flight, 1);
+        drop(waiting);
+        let snapshot = limiter.snapshot();
+        assert_eq!((snapshot.in_flight, snapshot.queued), (0, 0));
+        assert!(limiter.acquire().await.is_ok());
+    }
+}
@@ -0,0 +1,825 @@
+use crate::access_log::{AccessLogConfig, FileAccessLog};
+use crate::admin::{AdminConfig, FileAdmin, ADMIN_PREFIX};
+use crate::auth::{ApiKeyConfig, FileApiKeys};
+use crate::breaker::{BreakerSettings, FileBreaker};
+use crate::cache::{CacheConfig, CachePolicy, CacheSettings, FileCache};
+use crate::coalesce::{CoalescePolicy, CoalesceSettings, FileCoalesce};
+use crate::concurrency::{ConcurrencySettings, FileConcurrency};
+use crate::health::{FileHealth, HealthConfig};
+use crate::http2::{FileHttp2, Http2Config, UpstreamHttp2};
+use crate::idempotency::{FileIdempotency, IdempotencyConfig};
+use crate::oauth::{FileOAuth2, OAuth2Config};
+use crate::ratelimit::{FileRateLimit, LimitSettings, RateLimit, RateLimitConfig};
+use crate::redact::{FileRedaction, RedactionConfig};
+use crate::jwt::{FileJwt, JwtConfig};
+use crate::retry::{FileRetry, RetryPolicy};
+use crate::routes::{expects_body, render_template, Params, PathPattern};
+use crate::schema::Schema;
+use crate::shutdown::{FileShutdown, ShutdownConfig};
+use crate::timeouts::{FileTimeouts, Timeouts};
+use crate::tls::{ClientCertRule, FileClientCertRule, FileTls, FileUpstreamTls, TlsConfig, UpstreamTls};
+use crate::trace::{FileTracing, TracingConfig};
+use hyper::header::HeaderName;
+use hyper::Method;
+use reqwest::Url;
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+// Environment variables read at startup (see README for precedence)
+const ENV_CONFIG: &str = "RUST_API_CONFIG";
+const ENV_BIND: &str = "RUST_API_BIND";
+const ENV_UPSTREAM: &str = "RUST_API_UPSTREAM";
+
+const USAGE: &str = "Usage: rust_api [--config <file>] [--bind <addr:port>] [--upstream <url>]";
+
+// Name of the upstream configured by `upstream` / `--upstream`
+pub const DEFAULT_UPSTREAM: &str = "default";
+
+// Raw configuration as it appears in the TOML file
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileConfig {
+    bind: Option<String>,
+    tls: Option<FileTls>,
+    http2: Option<FileHttp2>,
+    upstream: Option<String>,
+    #[serde(default)]
+    deny_response_headers: Vec<String>,
+    #[serde(default)]
+    upstreams: HashMap<String, FileUpstream>,
+    retry: Option<FileRetry>,
+    timeouts: Option<FileTimeouts>,
+    circuit_breaker: Option<FileBreaker>,
+    admin: Option<FileAdmin>,
+    shutdown: Option<FileShutdown>,
+    health: Option<FileHealth>,
+    tracing: Option<FileTracing>,
+    access_log: Option<FileAccessLog>,
+    redaction: Option<FileRedaction>,
+    api_keys: Option<FileApiKeys>,
+    jwt: Option<FileJwt>,
+    rate_limit: Option<FileRateLimit>,
+    concurrency: Option<FileConcurrency>,
+    cache: Option<FileCache>,
+    coalesce: Option<FileCoalesce>,
+    idempotency: Option<FileIdempotency>,
+    routes: Option<Vec<FileRoute>>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileUpstream {
+    url: String,
+    circuit_breaker: Option<FileBreaker>,
+    health_path: Option<String>,
+    oauth2: Option<FileOAuth2>,
+    tls: Option<FileUpstreamTls>,
+    // `auto`, `always` or `never`; defaults to `http2.upstreams`
+    http2: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileRoute {
+    method: String,
+    path: String,
+    upstream: Option<String>,
+    upstream_path: Option<String>,
+    #[serde(default)]
+    pretty: bool,
+    retry: Option<FileRetry>,
+    timeouts: Option<FileTimeouts>,
+    schema: Option<PathBuf>,
+    auth: Option<bool>,
+    scopes: Option<Vec<String>>,
+    claims: Option<BTreeMap<String, serde_json::Value>>,
+    rate_limit: Option<FileRateLimit>,
+    concurrency: Option<FileConcurrency>,
+    client_cert: Option<FileClientCertRule>,
+    cache: Option<FileCache>,
+    coalesce: Option<FileCoalesce>,
+    idempotency: Option<bool>,
+}
+
+// Validated configuration used by the server
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub bind: SocketAddr,
+    // Serve HTTPS instead of plain HTTP
+    pub tls: Option<TlsConfig>,
+    pub http2: Http2Config,
+    // Upstream response headers never copied to the client
+    pub deny_response_headers: Vec<HeaderName>,
+    pub upstreams: HashMap<String, UpstreamConfig>,
+    pub routes: Vec<RouteConfig>,
+    pub admin: AdminConfig,
+    pub shutdown: ShutdownConfig,
+    pub health: HealthConfig,
+    pub tracing: TracingConfig,
+    pub access_log: AccessLogConfig,
+    pub redaction: RedactionConfig,
+    pub api_keys: Option<ApiKeyConfig>,
+    pub jwt: Option<JwtConfig>,
+    pub rate_limit: RateLimitConfig,
+    // Limit on upstream calls across all routes
+    pub concurrency: Option<ConcurrencySettings>,
+    pub cache: CacheConfig,
+    pub idempotency: IdempotencyConfig,
+}
+
+#[derive(Debug, Clone)]
+pub struct UpstreamConfig {
+    pub url: Url,
+    pub circuit_breaker: BreakerSettings,
+    // Path probed for readiness; upstreams without one are not probed
+    pub health_path: Option<String>,
+    // Client credentials used to obtain a bearer token for each call
+    pub oauth2: Option<OAuth2Config>,
+    // Custom CA trust and client certificate for calls to this upstream
+    pub tls: Option<UpstreamTls>,
+    pub http2: UpstreamHttp2,
+}
+
+#[derive(Debug, Clone)]
+pub struct RouteConfig {
+    pub method: Method,
+    // Pattern as written in the config, used as the route label in metrics
+    pub path: String,
+    pub pattern: PathPattern,
+    pub upstream: String,
+    // Path appended to the upstream URL; may reference `{name}` parameters
+    pub upstream_path: String,
+    // Re-indent JSON upstream responses before returning them
+    pub pretty: bool,
+    pub retry: RetryPolicy,
+    pub timeouts: Timeouts,
+    // Schema inbound bodies must satisfy before being forwarded
+    pub schema: Option<Arc<Schema>>,
+    // Callers must present a valid API key or bearer token
+    pub auth: bool,
+    // Scopes the caller's key or token must all hold
+    pub scopes: Vec<String>,
+    // Token claims the caller must have: claim name and accepted values
+    pub claims: Vec<(String, Vec<serde_json::Value>)>,
+    // Requests allowed per caller; unlimited when unset
+    pub rate_limit: Option<RateLimit>,
+    // Limit on this route's upstream calls, on top of the global one
+    pub concurrency: Option<ConcurrencySettings>,
+    // Callers must present a client certificate this rule accepts
+    pub client_cert: Option<ClientCertRule>,
+    // Responses are served from the cache while fresh
+    pub cache: Option<CachePolicy>,
+    // Identical concurrent requests share one upstream call
+    pub coalesce: Option<CoalescePolicy>,
+    // Responses are replayed for a repeated `Idempotency-Key`
+    pub idempotency: bool,
+}
+
+impl UpstreamConfig {
+    // HTTPS client settings for calls to this upstream
+    pub fn client_builder(&self) -> reqwest::ClientBuilder {
+        let builder = self.http2.apply(reqwest::Client::builder().use_rustls_tls());
+        match &self.tls {
+            Some(tls) => tls.apply(builder),
+            None => builder,
+        }
+    }
+}
+
+impl RouteConfig {
+    // `METHOD /pattern`, naming the route's rate limit buckets and limiter
+    pub fn name(&self) -> String {
+        format!("{} {}", self.method, self.path)
+    }
+
+    // Full upstream URL for this route, given the captured path parameters
+    // and the inbound query string
+    pub fn upstream_url(&self, base: &Url, params: &Params, query: Option<&str>) -> Url {
+        let mut url = base.clone();
+        let path = render_template(&self.upstream_path, params);
+        let joined = format!("{}{}", base.path().trim_end_matches('/'), path);
+        url.set_path(&joined);
+        url.set_query(query);
+        url
+    }
+}
+
+// Hop-by-hop headers (RFC 9110 section 7.6.1) plus `content-length`, which
+// hyper recomputes from the body we send back
+const HOP_BY_HOP_HEADERS: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+    "content-length",
+];
+
+// Values supplied on the command line
+#[derive(Debug, Default)]
+struct CliArgs {
+    config: Option<PathBuf>,
+    bind: Option<String>,
+    upstream: Option<String>,
+}
+
+fn parse_args<I: Iterator<Item = String>>(mut args: I) -> Result<CliArgs, String> {
+    let mut cli = CliArgs::default();
+    while let Some(arg) = args.next() {
+        let (flag, inline) = match arg.split_once('=') {
+            Some((f, v)) => (f.to_string(), Some(v.to_string())),
+            None => (arg.clone(), None),
+        };
+        let mut value = || {
+            inline
+                .clone()
+                .or_else(|| args.next())
+                .ok_or_else(|| format!("missing value for {}\n{}", flag, USAGE))
+        };
+        match flag.as_str() {
+            "--config" | "-c" => cli.config = Some(PathBuf::from(value()?)),
+            "--bind" => cli.bind = Some(value()?),
+            "--upstream" => cli.upstream = Some(value()?),
+            "--help" | "-h" => {
+                println!("{}", USAGE);
+                std::process::exit(0);
+            }
+            _ => return Err(format!("unknown argument '{}'\n{}", arg, USAGE)),
+        }
+    }
+    Ok(cli)
+}
+
+fn read_file(path: &PathBuf) -> Result<FileConfig, String> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|e| format!("cannot read config file {}: {}", path.display(), e))?;
+    toml::from_str(&text).map_err(|e| format!("invalid config file {}: {}", path.display(), e))
+}
+
+fn parse_bind(value: &str, source: &str) -> Result<SocketAddr, String> {
+    value
+        .parse()
+        .map_err(|_| format!("{}: invalid bind address '{}' (expected e.g. 127.0.0.1:3000)", source, value))
+}
+
+fn parse_upstream(value: &str, source: &str) -> Result<Url, String> {
+    let url = Url::parse(value).map_err(|e| format!("{}: invalid upstream URL '{}': {}", source, value, e))?;
+    if url.scheme() != "http" && url.scheme() != "https" {
+        return Err(format!("{}: upstream URL '{}' must use http or https", source, value));
+    }
+    if url.query().is_some() || url.fragment().is_some() {
+        return Err(format!("{}: upstream URL '{}' must not contain a query or fragment", source, value));
+    }
+    Ok(url)
+}
+
+fn check_health_path(path: Option<&str>) -> Result<(), String> {
+    match path {
+        Some(p) if !p.starts_with('/') => Err(format!("'{}' must start with '/'", p)),
+        _ => Ok(()),
+    }
+}
+
+// Check that every `{name}` placeholder in an upstream path template is
+// captured by the route pattern
+fn validate_template(template: &str, pattern: &PathPattern) -> Result<(), String> {
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| format!("upstream_path '{}': unclosed '{{'", template))?;
+        let name = rest[start + 1..start + end].trim_start_matches('*');
+        if !pattern.param_names().any(|n| n == name) {
+            return Err(format!("upstream_path '{}': unknown parameter '{}'", template, name));
+        }
+        rest = &rest[start + end + 1..];
+    }
+    Ok(())
+}
+
+// Settings inherited by every route unless it overrides them
+struct RouteDefaults {
+    retry: RetryPolicy,
+    timeouts: Timeouts,
+    // Whether API keys or JWTs are configured, which protects routes by default
+    auth: bool,
+    jwt: bool,
+    rate_limit: LimitSettings,
+    // Whether the listener verifies client certificates
+    client_auth: bool,
+    cache: CacheSettings,
+    coalesce: CoalesceSettings,
+    // Whether POST and PATCH routes honour `Idempotency-Key`
+    idempotency: bool,
+}
+
+fn validate_routes(
+    routes: Vec<FileRoute>,
+    upstreams: &HashMap<String, UpstreamConfig>,
+    defaults: &RouteDefaults,
+    base_dir: &Path,
+) -> Result<Vec<RouteConfig>, String> {
+    let mut seen = HashSet::new();
+    let mut out = Vec::with_capacity(routes.len());
+    for (i, r) in routes.into_iter().enumerate() {
+        let ctx = format!("routes[{}]", i);
+        let method = Method::from_bytes(r.method.to_ascii_uppercase().as_bytes())
+            .map_err(|_| format!("{}: invalid method '{}'", ctx, r.method))?;
+        let pattern = PathPattern::parse(&r.path).map_err(|e| format!("{}: {}", ctx, e))?;
+        // Admin paths are dispatched before the route table is consulted
+        let mut segments = r.path.split('/').filter(|s| !s.is_empty());
+        if segments.next() == Some(ADMIN_PREFIX.trim_matches('/')) && segments.next().is_some() {
+            return Err(format!("{}: path '{}' is under the reserved {} prefix", ctx, r.path, ADMIN_PREFIX));
+        }
+        let upstream_path = r.upstream_path.unwrap_or_else(|| r.path.clone());
+        if !upstream_path.starts_with('/') {
+            return Err(format!("{}: upstream_path '{}' must start with '/'", ctx, upstream_path));
+        }
+        validate_template(&upstream_path, &pattern).map_err(|e| format!("{}: {}", ctx, e))?;
+        let upstream = r.upstream.unwrap_or_else(|| DEFAULT_UPSTREAM.to_string());
+        if !upstreams.contains_key(&upstream) {
+            return Err(format!("{}: unknown upstream '{}'", ctx, upstream));
+        }
+        if !seen.insert((method.clone(), pattern.clone())) {
+            return Err(format!("{}: duplicate route {} {}", ctx, method, r.path));
+        }
+        let retry = match &r.retry {
+            Some(f) => defaults.retry.merge(f).map_err(|e| format!("{}: retry: {}", ctx, e))?,
+            None => defaults.retry.clone(),
+        };
+        let timeouts = match &r.timeouts {
+            Some(f) => defaults.timeouts.merge(f).map_err(|e| format!("{}: timeouts: {}", ctx, e))?,
+            None => defaults.timeouts.clone(),
+        };
+        let schema = match &r.schema {
+            Some(_) if !expects_body(&method) => {
+                return Err(format!("{}: schema is only supported for POST, PUT and PATCH routes", ctx));
+            }
+            // Relative schema paths are resolved against the config file's directory
+            Some(p) => Some(Arc::new(Schema::load(&base_dir.join(p)).map_err(|e| format!("{}: {}", ctx, e))?)),
+            None => None,
+        };
+        let auth = match r.auth {
+            Some(true) if !defaults.auth => {
+                return Err(format!("{}: auth requires an [api_keys] or [jwt] section", ctx));
+            }
+            Some(a) => a,
+            None => defaults.auth,
+        };
+        let scopes = r.scopes.unwrap_or_default();
+        if !scopes.is_empty() && !auth {
+            return Err(format!("{}: scopes require the route to use auth", ctx));
+        }
+        // A list accepts any of its values
+        let claims: Vec<(String, Vec<serde_json::Value>)> = r
+            .claims
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(name, v)| match v {
+                serde_json::Value::Array(items) => (name, items),
+                v => (name, vec![v]),
+            })
+            .collect();
+        if !claims.is_empty() && (!auth || !defaults.jwt) {
+            return Err(format!("{}: claims require a [jwt] section and the route to use auth", ctx));
+        }
+        let rate_limit = match &r.rate_limit {
+            Some(f) if f.redis_url().is_some() => {
+                return Err(format!("{}: rate_limit: redis_url is only allowed in [rate_limit]", ctx));
+            }
+            Some(f) => defaults.rate_limit.merge(f).map_err(|e| format!("{}: rate_limit: {}", ctx, e))?,
+            None => defaults.rate_limit.clone(),
+        };
+        let concurrency = match &r.concurrency {
+            Some(f) => Some(
+                ConcurrencySettings::default()
+                    .merge(f)
+                    .map_err(|e| format!("{}: concurrency: {}", ctx, e))?,
+            ),
+            None => None,
+        };
+        let client_cert = match &r.client_cert {
+            Some(_) if !defaults.client_auth => {
+                return Err(format!("{}: client_cert requires a [tls.client_auth] section", ctx));
+            }
+            Some(f) => Some(ClientCertRule::from_file(f)),
+            None => None,
+        };
+        let cache = match &r.cache {
+            Some(f) if f.has_store_settings() => {
+                return Err(format!("{}: cache: max_bytes and max_entry_bytes are only allowed in [cache]", ctx));
+            }
+            Some(f) => defaults.cache.merge(f).map_err(|e| format!("{}: cache: {}", ctx, e))?,
+            None => defaults.cache.clone(),
+        };
+        let coalesce = match &r.coalesce {
+            Some(f) => defaults.coalesce.merge(f).map_err(|e| format!("{}: coalesce: {}", ctx, e))?,
+            None => defaults.coalesce.clone(),
+        };
+        let replays = method == Method::POST || method == Method::PATCH;
+        let idempotency = match r.idempotency {
+            Some(true) if !replays => {
+                return Err(format!("{}: idempotency is only supported for POST and PATCH routes", ctx));
+            }
+            Some(i) => i,
+            None => defaults.idempotency && replays,
+        };
+        out.push(RouteConfig {
+            method,
+            path: r.path,
+            pattern,
+            upstream,
+            upstream_path,
+            pretty: r.pretty,
+            retry,
+            timeouts,
+            schema,
+            auth,
+            scopes,
+            claims,
+            rate_limit: rate_limit.policy(),
+            concurrency,
+            client_cert,
+            cache: cache.policy(),
+            coalesce: coalesce.policy(),
+            idempotency,
+        });
+    }
+    Ok(out)
+}
+
+// Route table used when the config file does not declare any routes
+fn default_routes(defaults: &RouteDefaults) -> Vec<RouteConfig> {
+    vec![RouteConfig {
+        method: Method::POST,
+        path: "/hello".to_string(),
+        pattern: PathPattern::parse("/hello").expect("valid default route"),
+        upstream: DEFAULT_UPSTREAM.to_string(),
+        upstream_path: "/post".to_string(),
+        pretty: true,
+        retry: defaults.retry.clone(),
+        timeouts: defaults.timeouts.clone(),
+        schema: None,
+        auth: defaults.auth,
+        scopes: Vec::new(),
+        claims: Vec::new(),
+        rate_limit: defaults.rate_limit.policy(),
+        concurrency: None,
+        client_cert: None,
+        cache: defaults.cache.policy(),
+        coalesce: defaults.coalesce.policy(),
+        idempotency: defaults.idempotency,
+    }]
+}
+
+impl Config {
+    // Load configuration. Precedence (highest first): CLI flags, environment
+    // variables, config file, built-in defaults.
+    pub fn load() -> Result<Config, String> {
+        let cli = parse_args(std::env::args().skip(1))?;
+        let env = |key: &str| std::env::var(key).ok().filter(|v| !v.is_empty());
+
+        let path = cli.config.clone().or_else(|| env(ENV_CONFIG).map(PathBuf::from));
+        let file = match &path {
+            Some(p) => read_file(p)?,
+            None => FileConfig::default(),
+        };
+        Config::resolve(cli, &env, path, file)
+    }
+
+    // Configuration from the text of a config file alone
+    #[cfg(test)]
+    pub fn from_toml(text: &str) -> Result<Config, String> {
+        let file = toml::from_str(text).map_err(|e| format!("invalid config: {}", e))?;
+        Config::resolve(CliArgs::default(), &|_| None, None, file)
+    }
+
+    fn resolve(cli: CliArgs, env: &dyn Fn(&str) -> Option<String>, path: Option<PathBuf>, file: FileConfig) -> Result<Config, String> {
+        let file_src = path
+            .as_ref()
+            .map(|p| format!("config file {}", p.display()))
+            .unwrap_or_else(|| "defaults/env".to_string());
+
+        let bind = match (cli.bind, env(ENV_BIND), file.bind) {
+            (Some(v), _, _) => parse_bind(&v, "--bind")?,
+            (None, Some(v), _) => parse_bind(&v, ENV_BIND)?,
+            (None, None, Some(v)) => parse_bind(&v, &format!("{}: bind", file_src))?,
+            (None, None, None) => SocketAddr::from(([127, 0, 0, 1], 3000)),
+        };
+
+        let upstream = match (cli.upstream, env(ENV_UPSTREAM), file.upstream) {
+            (Some(v), _, _) => parse_upstream(&v, "--upstream")?,
+            (None, Some(v), _) => parse_upstream(&v, ENV_UPSTREAM)?,
+            (None, None, Some(v)) => parse_upstream(&v, &format!("{}: upstream", file_src))?,
+            (None, None, None) => parse_upstream("https://postman-echo.com", "default")?,
+        };
+
+        let breaker_defaults = match &file.circuit_breaker {
+            Some(f) => BreakerSettings::default()
+                .merge(f)
+                .map_err(|e| format!("{}: circuit_breaker: {}", file_src, e))?,
+            None => BreakerSettings::default(),
+        };
+        let admin = match &file.admin {
+            Some(f) => AdminConfig::from_file(f).map_err(|e| format!("{}: admin: {}", file_src, e))?,
+            None => AdminConfig::default(),
+        };
+
+        let health = match &file.health {
+            Some(f) => HealthConfig::default()
+                .merge(f)
+                .map_err(|e| format!("{}: health: {}", file_src, e))?,
+            None => HealthConfig::default(),
+        };
+        let default_health_path = file.health.as_ref().and_then(|h| h.default_upstream_path.clone());
+        check_health_path(default_health_path.as_deref())
+            .map_err(|e| format!("{}: health.default_upstream_path: {}", file_src, e))?;
+
+        let http2 = match &file.http2 {
+            Some(f) => Http2Config::default()
+                .merge(f)
+                .map_err(|e| format!("{}: http2: {}", file_src, e))?,
+            None => Http2Config::default(),
+        };
+
+        // Relative paths in the config file are resolved against its directory
+        let base_dir = path.as_deref().and_then(Path::parent).unwrap_or(Path::new("."));
+
+        let mut upstreams = HashMap::new();
+        upstreams.insert(
+            DEFAULT_UPSTREAM.to_string(),
+            UpstreamConfig {
+                url: upstream,
+                circuit_breaker: breaker_defaults.clone(),
+                health_path: default_health_path,
+                oauth2: None,
+                tls: None,
+                http2: http2.upstreams,
+            },
+        );
+        for (name, u) in file.upstreams {
+            if name == DEFAULT_UPSTREAM {
+                return Err(format!(
+                    "{}: upstreams.{} is reserved; set `upstream` instead",
+                    file_src, name
+                ));
+            }
+            let url = parse_upstream(&u.url, &format!("{}: upstreams.{}.url", file_src, name))?;
+            let circuit_breaker = match &u.circuit_breaker {
+                Some(f) => breaker_defaults
+                    .merge(f)
+                    .map_err(|e| format!("{}: upstreams.{}.circuit_breaker: {}", file_src, name, e))?,
+                None => breaker_defaults.clone(),
+            };
+            check_health_path(u.health_path.as_deref())
+                .map_err(|e| format!("{}: upstreams.{}.health_path: {}", file_src, name, e))?;
+            let oauth2 = match &u.oauth2 {
+                Some(f) => Some(
+                    OAuth2Config::from_file(f).map_err(|e| format!("{}: upstreams.{}.oauth2: {}", file_src, name, e))?,
+                ),
+                None => None,
+            };
+            let tls = match &u.tls {
+                Some(f) => Some(
+                    UpstreamTls::from_file(f, base_dir).map_err(|e| format!("{}: upstreams.{}.tls: {}", file_src, name, e))?,
+                ),
+                None => None,
+            };
+            let upstream_http2 = match &u.http2 {
+                Some(s) => UpstreamHttp2::parse(s).map_err(|e| format!("{}: upstreams.{}.http2: {}", file_src, name, e))?,
+                None => http2.upstreams,
+            };
+            upstreams.insert(
+                name,
+                UpstreamConfig { url, circuit_breaker, health_path: u.health_path, oauth2, tls, http2: upstream_http2 },
+            );
+        }
+
+        let retry = match &file.retry {
+            Some(f) => RetryPolicy::default()
+                .merge(f)
+                .map_err(|e| format!("{}: retry: {}", file_src, e))?,
+            None => RetryPolicy::default(),
+        };
+        let timeouts = match &file.timeouts {
+            Some(f) => Timeouts::default()
+                .merge(f)
+                .map_err(|e| format!("{}: timeouts: {}", file_src, e))?,
+            None => Timeouts::default(),
+        };
+        let tls = match &file.tls {
+            Some(f) => Some(TlsConfig::from_file(f, base_dir).map_err(|e| format!("{}: tls: {}", file_src, e))?),
+            None => None,
+        };
+        let api_keys = match &file.api_keys {
+            Some(f) => Some(ApiKeyConfig::from_file(f, base_dir).map_err(|e| format!("{}: api_keys: {}", file_src, e))?),
+            None => None,
+        };
+        let jwt = match &file.jwt {
+            Some(f) => Some(JwtConfig::from_file(f, base_dir).map_err(|e| format!("{}: jwt: {}", file_src, e))?),
+            None => None,
+        };
+        let (rate_limit, rate_limit_defaults) = match &file.rate_limit {
+            Some(f) => (
+                RateLimitConfig::from_file(f).map_err(|e| format!("{}: rate_limit: {}", file_src, e))?,
+                LimitSettings::default()
+                    .merge(f)
+                    .map_err(|e| format!("{}: rate_limit: {}", file_src, e))?,
+            ),
+            None => (RateLimitConfig::default(), LimitSettings::default()),
+        };
+        let (cache, cache_defaults) = match &file.cache {
+            Some(f) => (
+                CacheConfig::from_file(f).map_err(|e| format!("{}: cache: {}", file_src, e))?,
+                CacheSettings::default()
+                    .merge(f)
+                    .map_err(|e| format!("{}: cache: {}", file_src, e))?,
+            ),
+            None => (CacheConfig::default(), CacheSettings::default()),
+        };
+        let coalesce_defaults = match &file.coalesce {
+            Some(f) => CoalesceSettings::default()
+                .merge(f)
+                .map_err(|e| format!("{}: coalesce: {}", file_src, e))?,
+            None => CoalesceSettings::default(),
+        };
+        let idempotency = match &file.idempotency {
+            Some(f) => IdempotencyConfig::from_file(f, base_dir).map_err(|e| format!("{}: idempotency: {}", file_src, e))?,
+            None => IdempotencyConfig::default(),
+        };
+        let defaults = RouteDefaults {
+            retry,
+            timeouts,
+            auth: api_keys.is_some() || jwt.is_some(),
+            jwt: jwt.is_some(),
+            rate_limit: rate_limit_defaults,
+            client_auth: tls.as_ref().is_some_and(|t| t.client_auth.is_some()),
+            cache: cache_defaults,
+            coalesce: coalesce_defaults,
+            idempotency: idempotency.enabled,
+        };
+
+        let routes = match file.routes {
+            Some(r) => validate_routes(r, &upstreams, &defaults, base_dir).map_err(|e| format!("{}: {}", file_src, e))?,
+            None => default_routes(&defaults),
+        };
+
+        let mut deny_response_headers: Vec<HeaderName> = HOP_BY_HOP_HEADERS
+            .iter()
+            .map(|h| HeaderName::from_static(h))
+            .collect();
+        for h in file.deny_response_headers {
+            let name = HeaderName::from_bytes(h.as_bytes())
+                .map_err(|_| format!("{}: deny_response_headers: invalid header name '{}'", file_src, h))?;
+            if !deny_response_headers.contains(&name) {
+                deny_response_headers.push(name);
+            }
+        }
+
+        let shutdown = match &file.shutdown {
+            Some(f) => ShutdownConfig::default().merge(f),
+            None => ShutdownConfig::default(),
+        };
+
+        let tracing = match &file.tracing {
+            Some(f) => TracingConfig::default()
+                .merge(f)
+                .map_err(|e| format!("{}: tracing: {}", file_src, e))?,
+            None => TracingConfig::default(),
+        };
+
+        let access_log = match &file.access_log {
+            Some(f) => AccessLogConfig::default().merge(f),
+            None => AccessLogConfig::default(),
+        };
+
+        let redaction = match &file.redaction {
+            Some(f) => RedactionConfig::from_file(f).map_err(|e| format!("{}: redaction.{}", file_src, e))?,
+            None => RedactionConfig::default(),
+        };
+
+        let concurrency = match &file.concurrency {
+            Some(f) => Some(
+                ConcurrencySettings::default()
+                    .merge(f)
+                    .map_err(|e| format!("{}: concurrency: {}", file_src, e))?,
+            ),
+            None => None,
+        };
+
+        Ok(Config {
+            bind,
+            tls,
+            http2,
+            deny_response_headers,
+            upstreams,
+            routes,
+            admin,
+            shutdown,
+            health,
+            tracing,
+            access_log,
+            redaction,
+            api_keys,
+            jwt,
+            rate_limit,
+            concurrency,
+            cache,
+            idempotency,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(list: &[&str]) -> CliArgs {
+        parse_args(list.iter().map(|s| s.to_string())).unwrap()
+    }
+
+    fn resolve(cli: CliArgs, env: &[(&str, &str)], toml: &str) -> Result<Config, String> {
+        let env = |key: &str| env.iter().find(|(k, _)| *k == key).map(|(_, v)| v.to_string());
+        let path = Some(PathBuf::from("/etc/rust_api/config.toml"));
+        Config::resolve(cli, &env, path, toml::from_str(toml).unwrap())
+    }
+
+    #[test]
+    fn cli_flags_beat_env_which_beats_the_file() {
+        let file = "bind = \"127.0.0.1:1001\"\nupstream = \"http://file.test\"";
+        let env = [(ENV_BIND, "127.0.0.1:1002"), (ENV_UPSTREAM, "http://env.test")];
+
+        let config = resolve(CliArgs::default(), &[], file).unwrap();
+        assert_eq!(config.bind.port(), 1001);
+        assert_eq!(config.upstreams[DEFAULT_UPSTREAM].url.as_str(), "http://file.test/");
+
+        let config = resolve(CliArgs::default(), &env, file).unwrap();
+        assert_eq!(config.bind.port(), 1002);
+        assert_eq!(config.upstreams[DEFAULT_UPSTREAM].url.as_str(), "http://env.test/");
+
+        let cli = args(&["--bind=127.0.0.1:1003", "--upstream", "http://cli.test"]);
+        let config = resolve(cli, &env, file).unwrap();
+        assert_eq!(config.bind.port(), 1003);
+        assert_eq!(config.upstreams[DEFAULT_UPSTREAM].url.as_str(), "http://cli.test/");
+
+        let config = Config::from_toml("").unwrap();
+        assert_eq!(config.bind, SocketAddr::from(([127, 0, 0, 1], 3000)));
+        assert_eq!(config.upstreams[DEFAULT_UPSTREAM].url.as_str(), "https://postman-echo.com/");
+        assert_eq!(config.routes.len(), 1);
+    }
+
+    #[test]
+    fn cli_arguments() {
+        let cli = args(&["-c", "app.toml", "--bind", "0.0.0.0:80"]);
+        assert_eq!(cli.config, Some(PathBuf::from("app.toml")));
+        assert_eq!(cli.bind.as_deref(), Some("0.0.0.0:80"));
+        let parse = |list: &[&str]| parse_args(list.iter().map(|s| s.to_string())).unwrap_err();
+        assert!(parse(&["--bind"]).starts_with("missing value for --bind"));
+        assert!(parse(&["--verbose"]).starts_with("unknown argument '--verbose'"));
+    }
+
+    #[test]
+    fn errors_name_their_source() {
+        let err = |cli, env: &[(&str, &str)], toml| resolve(cli, env, toml).unwrap_err();
+        assert!(err(args(&["--bind", "nowhere"]), &[], "").starts_with("--bind: invalid bind address 'nowhere'"));
+        assert!(err(CliArgs::default(), &[(ENV_UPSTREAM, "ftp://x")], "").starts_with("RUST_API_UPSTREAM: upstream URL"));
+        assert_eq!(
+            err(CliArgs::default(), &[], "upstream = \"http://x/?a=1\""),
+            "config file /etc/rust_api/config.toml: upstream: upstream URL 'http://x/?a=1' must not contain a query or fragment"
+        );
+        // Without a config file, settings come from defaults and the environment
+        let file = toml::from_str("[admin]\ntoken = \"\"").unwrap();
+        let err = Config::resolve(CliArgs::default(), &|_| None, None, file).unwrap_err();
+        assert!(err.starts_with("defaults/env: admin: "), "{}", err);
+    }
+
+    #[test]
+    fn invalid_routes_are_rejected() {
+        let route = |extra: &str| format!("[[routes]]\nmethod = \"GET\"\npath = \"/items/{{id}}\"\n{}", extra);
+        let err = |toml: &str| Config::from_toml(toml).unwrap_err();
+        assert!(err(&route("upstream = \"nope\"")).ends_with("routes[0]: unknown upstream 'nope'"));
+        assert!(err(&route("upstream_path = \"/v2/{other}\"")).ends_with("unknown parameter 'other'"));
+        assert!(err(&route("upstream_path = \"v2\"")).ends_with("upstream_path 'v2' must start with '/'"));
+        assert!(err(&format!("{}\n{}", route(""), route(""))).ends_with("routes[1]: duplicate route GET /items/{id}"));
+        assert!(err(&route("schema = \"item.json\"")).ends_with("schema is only supported for POST, PUT and PATCH routes"));
+        assert!(err("[[routes]]\nmethod = \"GET\"\npath = \"/items\"\nunknown = 1").contains("unknown field `unknown`"));
+    }
+
+    #[test]
+    fn routes_under_admin_are_rejected() {
+        let route = |path: &str| Config::from_toml(&format!("[[routes]]\nmethod = \"GET\"\npath = \"{}\"", path));
+        for path in ["/admin/circuit-breakers", "//admin/{*rest}", "/admin/{id}"] {
+            let err = route(path).unwrap_err();
+            assert!(err.ends_with(&format!("routes[0]: path '{}' is under the reserved /admin/ prefix", path)), "{}", err);
+        }
+        assert!(route("/admin").is_ok());
+        assert!(route("/administrators/{id}").is_ok());
+    }
+}
@@ -0,0 +1,203 @@
+use hyper::header::{HeaderName, HeaderValue};
+use hyper::{Body, Response, StatusCode};
+use serde_json::{Map, Value};
+use std::time::Duration;
+
+pub const PROBLEM_CONTENT_TYPE: &str = "application/problem+json";
+
+// Whole seconds for `Retry-After` and similar headers, rounded up
+pub fn ceil_secs(d: Duration) -> u64 {
+    d.as_secs() + u64::from(d.subsec_nanos() > 0)
+}
+
+// Error returned to clients as an RFC 7807 problem document. The request id
+// is only known at the top of the handler, so it is filled in when rendering.
+#[derive(Debug, Clone)]
+pub struct ApiError {
+    pub status: StatusCode,
+    // Short identifier used to build the problem `type` URI
+    kind: &'static str,
+    detail: String,
+    extensions: Map<String, Value>,
+    headers: Vec<(HeaderName, HeaderValue)>,
+}
+
+impl ApiError {
+    pub fn new(status: StatusCode, kind: &'static str, detail: impl Into<String>) -> ApiError {
+        ApiError {
+            status,
+            kind,
+            detail: detail.into(),
+            extensions: Map::new(),
+            headers: Vec::new(),
+        }
+    }
+
+    pub fn with_header(mut self, name: HeaderName, value: HeaderValue) -> ApiError {
+        self.set_header(name, value);
+        self
+    }
+
+    pub fn set_header(&mut self, name: HeaderName, value: HeaderValue) {
+        self.headers.retain(|(n, _)| *n != name);
+        self.headers.push((name, value));
+    }
+
+    pub fn with_extension(mut self, key: &str, value: impl Into<Value>) -> ApiError {
+        self.extensions.insert(key.to_string(), value.into());
+        self
+    }
+
+    pub fn into_response(self, request_id: &str) -> Response<Body> {
+        let mut problem = Map::new();
+        problem.insert("type".into(), format!("/problems/{}", self.kind).into());
+        problem.insert("title".into(), self.status.canonical_reason().unwrap_or("Error").into());
+        problem.insert("status".into(), self.status.as_u16().into());
+        problem.insert("detail".into(), self.detail.into());
+        problem.insert("request_id".into(), request_id.into());
+        problem.extend(self.extensions);
+
+        let mut response = Response::new(Body::from(Value::Object(problem).to_string()));
+        *response.status_mut() = self.status;
+        response.headers_mut().extend(self.headers);
+        response
+            .headers_mut()
+            .insert(hyper::header::CONTENT_TYPE, HeaderValue::from_static(PROBLEM_CONTENT_TYPE));
+        response
+    }
+
+    pub fn not_found() -> ApiError {
+        ApiError::new(StatusCode::NOT_FOUND, "not-found", "No route matches this path")
+    }
+
+    pub fn method_not_allowed(allow: &str) -> ApiError {
+        ApiError::new(
+            StatusCode::METHOD_NOT_ALLOWED,
+            "method-not-allowed",
+            format!("This path only accepts: {}", allow),
+        )
+        .with_header(
+            hyper::header::ALLOW,
+            HeaderValue::from_str(allow).unwrap_or(HeaderValue::from_static("")),
+        )
+    }
+
+    // A path parameter captured a `.` or `..` segment
+    pub fn invalid_path(param: &str) -> ApiError {
+        ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "invalid-path",
+            format!("Path parameter '{}' must not be '.' or '..'", param),
+        )
+    }
+
+    pub fn unsupported_media_type() -> ApiError {
+        ApiError::new(
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "unsupported-media-type",
+            "Expected application/json",
+        )
+    }
+
+    pub fn unreadable_body() -> ApiError {
+        ApiError::new(StatusCode::BAD_REQUEST, "unreadable-body", "Failed to read body")
+    }
+
+    // Invalid JSON, reporting where serde_json stopped
+    pub fn invalid_json(e: &serde_json::Error) -> ApiError {
+        ApiError::new(StatusCode::BAD_REQUEST, "invalid-json", format!("Invalid JSON format: {}", e))
+            .with_extension("line", e.line())
+            .with_extension("column", e.column())
+    }
+
+    pub fn bad_gateway(detail: impl Into<String>) -> ApiError {
+        ApiError::new(StatusCode::BAD_GATEWAY, "upstream-failure", detail)
+    }
+
+    pub fn gateway_timeout(detail: impl Into<String>) -> ApiError {
+        ApiError::new(StatusCode::GATEWAY_TIMEOUT, "deadline-exceeded", detail)
+    }
+
+    // The upstream's circuit breaker is open; `wait` is reported in
+    // `Retry-After`, rounded up to whole seconds
+    pub fn circuit_open(upstream: &str, wait: Duration) -> ApiError {
+        ApiError::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "circuit-open",
+            format!("Upstream '{}' is unavailable (circuit open)", upstream),
+        )
+        .with_header(hyper::header::RETRY_AFTER, HeaderValue::from(ceil_secs(wait)))
+        .with_extension("upstream", upstream)
+    }
+
+    // The concurrency limiter named `limiter` turned the request away
+    pub fn overloaded(limiter: &str, reason: &str) -> ApiError {
+        ApiError::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "overloaded",
+            "Too many requests are in progress; try again shortly",
+        )
+        .with_header(hyper::header::RETRY_AFTER, HeaderValue::from(1))
+        .with_extension("limiter", limiter)
+        .with_extension("reason", reason)
+    }
+
+    // The caller used up its rate limit; the limiter adds the headers
+    pub fn rate_limited(wait: Duration) -> ApiError {
+        ApiError::new(
+            StatusCode::TOO_MANY_REQUESTS,
+            "rate-limited",
+            format!("Rate limit exceeded; retry in {}s", ceil_secs(wait)),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn problem(response: Response<Body>) -> Value {
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    #[tokio::test]
+    async fn problem_documents() {
+        let response = ApiError::method_not_allowed("GET, DELETE")
+            .with_extension("path", "/users/7")
+            .into_response("req-1");
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(response.headers()[hyper::header::CONTENT_TYPE], PROBLEM_CONTENT_TYPE);
+        assert_eq!(response.headers()[hyper::header::ALLOW], "GET, DELETE");
+        assert_eq!(
+            problem(response).await,
+            serde_json::json!({
+                "type": "/problems/method-not-allowed",
+                "title": "Method Not Allowed",
+                "status": 405,
+                "detail": "This path only accepts: GET, DELETE",
+                "request_id": "req-1",
+                "path": "/users/7",
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn headers_are_replaced_not_repeated() {
+        let mut err = ApiError::circuit_open("users", Duration::from_millis(1500));
+        err.set_header(hyper::header::RETRY_AFTER, HeaderValue::from(5));
+        let response = err.into_response("req-2");
+        assert_eq!(response.headers().get_all(hyper::header::RETRY_AFTER).iter().count(), 1);
+        assert_eq!(response.headers()[hyper::header::RETRY_AFTER], "5");
+        let body = problem(response).await;
+        assert_eq!(body["type"], "/problems/circuit-open");
+        assert_eq!(body["upstream"], "users");
+    }
+
+    #[test]
+    fn retry_after_rounds_up() {
+        assert_eq!(ceil_secs(Duration::ZERO), 0);
+        assert_eq!(ceil_secs(Duration::from_secs(2)), 2);
+        assert_eq!(ceil_secs(Duration::from_millis(2001)), 3);
+    }
+}
@@ -0,0 +1,312 @@
+use crate::config::Config;
+use crate::AppState;
+use hyper::{Body, Response, StatusCode};
+use reqwest::{Client, Url};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+pub const HEALTHZ_PATH: &str = "/healthz";
+pub const READYZ_PATH: &str = "/readyz";
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FileHealth {
+    probe_interval_ms: Option<u64>,
+    probe_timeout_ms: Option<u64>,
+    // Probe path for the upstream set with `upstream` / `--upstream`
+    pub default_upstream_path: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct HealthConfig {
+    pub probe_interval: Duration,
+    pub probe_timeout: Duration,
+}
+
+impl Default for HealthConfig {
+    fn default() -> HealthConfig {
+        HealthConfig {
+            probe_interval: Duration::from_secs(10),
+            probe_timeout: Duration::from_secs(2),
+        }
+    }
+}
+
+impl HealthConfig {
+    pub fn merge(&self, file: &FileHealth) -> Result<HealthConfig, String> {
+        let mut c = self.clone();
+        if let Some(ms) = file.probe_interval_ms {
+            if ms == 0 {
+                return Err("probe_interval_ms must be greater than 0".to_string());
+            }
+            c.probe_interval = Duration::from_millis(ms);
+        }
+        if let Some(ms) = file.probe_timeout_ms {
+            if ms == 0 {
+                return Err("probe_timeout_ms must be greater than 0".to_string());
+            }
+            c.probe_timeout = Duration::from_millis(ms);
+        }
+        Ok(c)
+    }
+}
+
+// Result of the most recent probe of one upstream
+#[derive(Debug, Clone, Default)]
+struct ProbeResult {
+    // `None` until the first probe completes
+    healthy: Option<bool>,
+    checked_at: Option<SystemTime>,
+    latency: Option<Duration>,
+    status: Option<u16>,
+    // Coarse failure kind; `/readyz` is unauthenticated, so the error text,
+    // which names the upstream URL, only goes to the server log
+    error: Option<&'static str>,
+}
+
+// Latest probe results, for upstreams that declare a `health_path`
+pub struct Probes {
+    results: HashMap<String, Mutex<ProbeResult>>,
+}
+
+impl Probes {
+    pub fn new(config: &Config) -> Probes {
+        let results = config
+            .upstreams
+            .iter()
+            .filter(|(_, u)| u.health_path.is_some())
+            .map(|(name, _)| (name.clone(), Mutex::new(ProbeResult::default())))
+            .collect();
+        Probes { results }
+    }
+
+    fn all_healthy(&self) -> bool {
+        self.results.values().all(|r| r.lock().unwrap().healthy == Some(true))
+    }
+}
+
+fn error_kind(e: &reqwest::Error) -> &'static str {
+    if e.is_timeout() {
+        "timeout"
+    } else if e.is_connect() {
+        "connect"
+    } else {
+        "request"
+    }
+}
+
+// Probe once, returning the error text separately for the log
+async fn probe(client: &Client, url: Url) -> (ProbeResult, Option<String>) {
+    let started = Instant::now();
+    let outcome = client.get(url).send().await;
+    let mut result = ProbeResult {
+        checked_at: Some(SystemTime::now()),
+        latency: Some(started.elapsed()),
+        ..ProbeResult::default()
+    };
+    match outcome {
+        Ok(resp) => {
+            result.healthy = Some(resp.status().is_success());
+            result.status = Some(resp.status().as_u16());
+        }
+        Err(e) => {
+            result.healthy = Some(false);
+            result.error = Some(error_kind(&e));
+            return (result, Some(e.to_string()));
+        }
+    }
+    (result, None)
+}
+
+// Probe every upstream with a `health_path` now and then on each interval,
+// one task per upstream so a slow dependency does not delay the others
+pub fn spawn_probes(state: Arc<AppState>) {
+    if state.probes.results.is_empty() {
+        return;
+    }
+    let health = &state.config.health;
+    for name in state.probes.results.keys() {
+        let upstream = &state.config.upstreams[name];
+        // Probes present the same client certificate as proxied calls
+        let client = upstream
+            .client_builder()
+            .timeout(health.probe_timeout)
+            .build()
+            .expect("Failed to build reqwest client");
+        let mut url = upstream.url.clone();
+        let path = upstream.health_path.as_deref().unwrap_or("/");
+        url.set_path(&format!("{}{}", url.path().trim_end_matches('/'), path));
+
+        let mut interval = tokio::time::interval(health.probe_interval);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        let (state, name) = (state.clone(), name.clone());
+        tokio::spawn(async move {
+            loop {
+                interval.tick().await;
+                let (result, error) = probe(&client, url.clone()).await;
+                let mut slot = state.probes.results[&name].lock().unwrap();
+                // Report a failure when it starts rather than on every probe
+                if let (Some(e), false) = (error, slot.error.is_some()) {
+                    eprintln!("Health probe of upstream '{}' failed: {}", name, e);
+                }
+                *slot = result;
+            }
+        });
+    }
+}
+
+fn json_response(status: StatusCode, value: serde_json::Value) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(hyper::header::CONTENT_TYPE, "application/json")
+        .header(hyper::header::CACHE_CONTROL, "no-store")
+        .body(Body::from(value.to_string()))
+        .unwrap()
+}
+
+// Liveness: the process is up and serving requests
+pub fn healthz() -> Response<Body> {
+    json_response(StatusCode::OK, serde_json::json!({ "status": "ok" }))
+}
+
+// Readiness: not shutting down and every probed upstream answered its last probe
+pub fn readyz(state: &AppState) -> Response<Body> {
+    let accepting = state.ready.load(Ordering::SeqCst);
+    let ready = accepting && state.probes.all_healthy();
+    let mut dependencies = serde_json::Map::new();
+    for name in state.config.upstreams.keys() {
+        let entry = match state.probes.results.get(name) {
+            None => serde_json::json!({ "status": "unchecked" }),
+            Some(slot) => {
+                let r = slot.lock().unwrap().clone();
+                let status = match r.healthy {
+                    None => "unknown",
+                    Some(true) => "up",
+                    Some(false) => "down",
+                };
+                serde_json::json!({
+                    "status": status,
+                    "checked_at": r.checked_at
+                        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                        .map(|d| d.as_secs()),
+                    "latency_ms": r.latency.map(|d| d.as_millis() as u64),
+                    "http_status": r.status,
+                    "error": r.error,
+                })
+            }
+        };
+        dependencies.insert(name.clone(), entry);
+    }
+    let body = serde_json::json!({
+        "status": if ready { "ready" } else { "not_ready" },
+        "shutting_down": !accepting,
+        "dependencies": dependencies,
+    });
+    let status = if ready { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+    json_response(status, body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::build_state;
+
+    fn state() -> Arc<AppState> {
+        let config = Config::from_toml(
+            r#"
+            upstream = "http://127.0.0.1:9"
+            [access_log]
+            enabled = false
+            [health]
+            default_upstream_path = "/status"
+            [upstreams.users]
+            url = "http://127.0.0.1:9"
+            health_path = "/health"
+            [upstreams.billing]
+            url = "http://127.0.0.1:9"
+            "#,
+        )
+        .unwrap();
+        build_state(config).unwrap().0
+    }
+
+    fn set(state: &AppState, upstream: &str, healthy: bool) {
+        *state.probes.results[upstream].lock().unwrap() = ProbeResult {
+            healthy: Some(healthy),
+            checked_at: Some(UNIX_EPOCH + Duration::from_secs(1_800_000_000)),
+            latency: Some(Duration::from_millis(12)),
+            status: healthy.then_some(200),
+            error: (!healthy).then_some("connect"),
+        };
+    }
+
+    async fn readiness(state: &AppState) -> (StatusCode, serde_json::Value) {
+        let response = readyz(state);
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn not_ready_until_every_probe_succeeds() {
+        let state = state();
+        let (status, body) = readiness(&state).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body["dependencies"]["default"]["status"], "unknown");
+        assert_eq!(body["dependencies"]["billing"], serde_json::json!({ "status": "unchecked" }));
+
+        set(&state, "default", true);
+        set(&state, "users", false);
+        let (status, body) = readiness(&state).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body["status"], "not_ready");
+        assert_eq!(body["dependencies"]["users"]["status"], "down");
+
+        set(&state, "users", true);
+        let (status, body) = readiness(&state).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            body,
+            serde_json::json!({
+                "status": "ready",
+                "shutting_down": false,
+                "dependencies": {
+                    "default": {"status": "up", "checked_at": 1_800_000_000, "latency_ms": 12, "http_status": 200, "error": null},
+                    "users": {"status": "up", "checked_at": 1_800_000_000, "latency_ms": 12, "http_status": 200, "error": null},
+                    "billing": {"status": "unchecked"},
+                },
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn not_ready_during_shutdown() {
+        let state = state();
+        set(&state, "default", true);
+        set(&state, "users", true);
+        state.ready.store(false, Ordering::SeqCst);
+        let (status, body) = readiness(&state).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body["status"], "not_ready");
+        assert_eq!(body["shutting_down"], true);
+    }
+
+    #[tokio::test]
+    async fn probe_errors_are_reported_by_kind_only() {
+        let client = Client::builder().timeout(Duration::from_secs(2)).build().unwrap();
+        let url = Url::parse("http://127.0.0.1:9/internal/status?token=s3cret").unwrap();
+        let (result, detail) = probe(&client, url).await;
+        assert_eq!(result.healthy, Some(false));
+        assert_eq!(result.error, Some("connect"));
+        assert!(detail.unwrap().contains("127.0.0.1:9"));
+
+        let state = state();
+        *state.probes.results["users"].lock().unwrap() = result;
+        let (_, body) = readiness(&state).await;
+        assert_eq!(body["dependencies"]["users"]["error"], "connect");
+        assert!(!body.to_string().contains("127.0.0.1"));
+    }
+}
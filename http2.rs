@@ -0,0 +1,565 @@
+use hyper::header::{HeaderMap, HeaderName, CONNECTION, CONTENT_LENGTH, HOST, TE, TRANSFER_ENCODING, UPGRADE};
+use hyper::server::conn::Http;
+use hyper::{Body, Request, Version};
+use serde::Deserialize;
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+// Largest window RFC 7540 allows
+const MAX_WINDOW_SIZE: u32 = (1 << 31) - 1;
+
+// Connection preface every HTTP/2 client starts with, before its SETTINGS
+const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+const FRAME_HEADER_LEN: usize = 9;
+const FRAME_HEADERS: u8 = 0x1;
+const FRAME_SETTINGS: u8 = 0x4;
+const FRAME_CONTINUATION: u8 = 0x9;
+const FLAG_END_STREAM: u8 = 0x1;
+const FLAG_END_HEADERS: u8 = 0x4;
+// Frame payload every endpoint accepts, whatever its settings
+const MIN_MAX_FRAME_SIZE: usize = 16384;
+
+// Request headers that describe the HTTP/1.1 connection, not the request
+const UPGRADE_DROPPED_HEADERS: &[&str] = &[
+    "connection",
+    "http2-settings",
+    "keep-alive",
+    "proxy-connection",
+    "transfer-encoding",
+    "upgrade",
+];
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FileHttp2 {
+    enabled: Option<bool>,
+    h2c: Option<bool>,
+    max_concurrent_streams: Option<u32>,
+    initial_stream_window_size: Option<u32>,
+    initial_connection_window_size: Option<u32>,
+    adaptive_window: Option<bool>,
+    keepalive_interval_ms: Option<u64>,
+    keepalive_timeout_ms: Option<u64>,
+    // Default for `http2` on upstreams
+    upstreams: Option<String>,
+}
+
+// How calls to an upstream pick the HTTP version
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UpstreamHttp2 {
+    // Negotiated through ALPN over HTTPS; HTTP/1.1 over plain HTTP
+    Auto,
+    // HTTP/2 with prior knowledge, also over plain HTTP
+    Always,
+    // HTTP/1.1 only
+    Never,
+}
+
+impl UpstreamHttp2 {
+    pub fn parse(s: &str) -> Result<UpstreamHttp2, String> {
+        match s {
+            "auto" => Ok(UpstreamHttp2::Auto),
+            "always" => Ok(UpstreamHttp2::Always),
+            "never" => Ok(UpstreamHttp2::Never),
+            _ => Err(format!("unknown http2 mode '{}' (expected auto, always or never)", s)),
+        }
+    }
+
+    pub fn apply(self, builder: reqwest::ClientBuilder) -> reqwest::ClientBuilder {
+        match self {
+            UpstreamHttp2::Auto => builder,
+            UpstreamHttp2::Always => builder.http2_prior_knowledge(),
+            UpstreamHttp2::Never => builder.http1_only(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Http2Config {
+    // Offer HTTP/2 at all; HTTP/1.1 is always available
+    pub enabled: bool,
+    // Accept cleartext HTTP/2 on a plain HTTP listener
+    pub h2c: bool,
+    pub max_concurrent_streams: u32,
+    pub initial_stream_window_size: u32,
+    pub initial_connection_window_size: u32,
+    // Size windows from the measured bandwidth-delay product instead
+    pub adaptive_window: bool,
+    // Ping idle connections this often; off when unset
+    pub keepalive_interval: Option<Duration>,
+    // Close the connection when a ping is not answered in time
+    pub keepalive_timeout: Duration,
+    pub upstreams: UpstreamHttp2,
+}
+
+impl Default for Http2Config {
+    fn default() -> Http2Config {
+        Http2Config {
+            enabled: true,
+            h2c: true,
+            max_concurrent_streams: 200,
+            initial_stream_window_size: 1024 * 1024,
+            initial_connection_window_size: 1024 * 1024,
+            adaptive_window: false,
+            keepalive_interval: None,
+            keepalive_timeout: Duration::from_secs(20),
+            upstreams: UpstreamHttp2::Auto,
+        }
+    }
+}
+
+impl Http2Config {
+    pub fn merge(&self, file: &FileHttp2) -> Result<Http2Config, String> {
+        let mut c = self.clone();
+        if let Some(b) = file.enabled {
+            c.enabled = b;
+        }
+        if let Some(b) = file.h2c {
+            c.h2c = b;
+        }
+        if let Some(n) = file.max_concurrent_streams {
+            if n == 0 {
+                return Err("max_concurrent_streams must be at least 1".to_string());
+            }
+            c.max_concurrent_streams = n;
+        }
+        if let Some(n) = file.initial_stream_window_size {
+            c.initial_stream_window_size = window_size("initial_stream_window_size", n)?;
+        }
+        if let Some(n) = file.initial_connection_window_size {
+            c.initial_connection_window_size = window_size("initial_connection_window_size", n)?;
+        }
+        if let Some(b) = file.adaptive_window {
+            c.adaptive_window = b;
+        }
+        if let Some(ms) = file.keepalive_interval_ms {
+            c.keepalive_interval = (ms > 0).then(|| Duration::from_millis(ms));
+        }
+        if let Some(ms) = file.keepalive_timeout_ms {
+            c.keepalive_timeout = Duration::from_millis(ms);
+        }
+        if let Some(s) = &file.upstreams {
+            c.upstreams = UpstreamHttp2::parse(s)?;
+        }
+        Ok(c)
+    }
+
+    // Whether a listener, with or without TLS, speaks HTTP/2
+    pub fn serves(&self, tls: bool) -> bool {
+        self.enabled && (tls || self.h2c)
+    }
+
+    // Connection settings for the listener. With HTTP/2 on, each connection
+    // starts as HTTP/1.1 and switches when the client opens with the HTTP/2
+    // preface, after ALPN or with prior knowledge.
+    pub fn protocol(&self, tls: bool) -> Http {
+        let mut http = Http::new();
+        if !self.serves(tls) {
+            http.http1_only(true);
+            return http;
+        }
+        http.http2_max_concurrent_streams(self.max_concurrent_streams)
+            .http2_initial_stream_window_size(self.initial_stream_window_size)
+            .http2_initial_connection_window_size(self.initial_connection_window_size)
+            .http2_adaptive_window(self.adaptive_window)
+            .http2_keep_alive_interval(self.keepalive_interval)
+            .http2_keep_alive_timeout(self.keepalive_timeout);
+        http
+    }
+}
+
+fn window_size(name: &str, n: u32) -> Result<u32, String> {
+    if n == 0 || n > MAX_WINDOW_SIZE {
+        return Err(format!("{} must be between 1 and {}", name, MAX_WINDOW_SIZE));
+    }
+    Ok(n)
+}
+
+// Whether `name` lists `token`, in any of its values
+fn has_token(headers: &HeaderMap, name: HeaderName, token: &str) -> bool {
+    headers
+        .get_all(name)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|t| t.trim().eq_ignore_ascii_case(token))
+}
+
+// An HTTP/1.1 request asking to continue in cleartext HTTP/2. Requests with a
+// body stay on HTTP/1.1; the upgraded stream could not carry it.
+pub fn wants_h2c(req: &Request<Body>) -> bool {
+    let headers = req.headers();
+    req.version() == Version::HTTP_11
+        && has_token(headers, UPGRADE, "h2c")
+        && has_token(headers, CONNECTION, "upgrade")
+        && headers.get_all("http2-settings").iter().count() == 1
+        && !headers.contains_key(TRANSFER_ENCODING)
+        && headers.get(CONTENT_LENGTH).is_none_or(|v| v == "0")
+}
+
+// HPACK integer with a `prefix_bits`-bit prefix (RFC 7541 section 5.1)
+fn hpack_int(out: &mut Vec<u8>, first: u8, prefix_bits: u32, mut n: usize) {
+    let max = (1 << prefix_bits) - 1;
+    if n < max {
+        out.push(first | n as u8);
+        return;
+    }
+    out.push(first | max as u8);
+    n -= max;
+    while n >= 0x80 {
+        out.push((n & 0x7f) as u8 | 0x80);
+        n >>= 7;
+    }
+    out.push(n as u8);
+}
+
+// Literal header field without indexing, so the decoder's table is untouched
+// and the client's encoder stays in step (RFC 7541 section 6.2.2)
+fn hpack_field(out: &mut Vec<u8>, name: &[u8], value: &[u8]) {
+    out.push(0);
+    for s in [name, value] {
+        hpack_int(out, 0, 7, s.len());
+        out.extend_from_slice(s);
+    }
+}
+
+fn frame(out: &mut Vec<u8>, kind: u8, flags: u8, stream: u32, payload: &[u8]) {
+    out.extend_from_slice(&(payload.len() as u32).to_be_bytes()[1..]);
+    out.push(kind);
+    out.push(flags);
+    out.extend_from_slice(&stream.to_be_bytes());
+    out.extend_from_slice(payload);
+}
+
+// The upgraded request as the frames opening stream 1, which RFC 7540
+// section 3.2 reserves for it and on which the response is sent
+pub fn upgrade_frames(req: &Request<Body>) -> Vec<u8> {
+    let mut block = Vec::new();
+    let path = req.uri().path_and_query().map_or("/", |p| p.as_str());
+    hpack_field(&mut block, b":method", req.method().as_str().as_bytes());
+    hpack_field(&mut block, b":scheme", b"http");
+    if let Some(host) = req.headers().get(HOST) {
+        hpack_field(&mut block, b":authority", host.as_bytes());
+    } else if let Some(authority) = req.uri().authority() {
+        hpack_field(&mut block, b":authority", authority.as_str().as_bytes());
+    }
+    hpack_field(&mut block, b":path", path.as_bytes());
+    for (name, value) in req.headers() {
+        let dropped = name == HOST || UPGRADE_DROPPED_HEADERS.contains(&name.as_str()) || (name == TE && value != "trailers");
+        if !dropped {
+            hpack_field(&mut block, name.as_str().as_bytes(), value.as_bytes());
+        }
+    }
+    let chunks: Vec<&[u8]> = block.chunks(MIN_MAX_FRAME_SIZE).collect();
+    let mut out = Vec::new();
+    for (i, chunk) in chunks.iter().enumerate() {
+        let (kind, mut flags) = if i == 0 { (FRAME_HEADERS, FLAG_END_STREAM) } else { (FRAME_CONTINUATION, 0) };
+        if i == chunks.len() - 1 {
+            flags |= FLAG_END_HEADERS;
+        }
+        frame(&mut out, kind, flags, 1, chunk);
+    }
+    out
+}
+
+enum Preface {
+    Incomplete,
+    // Length of the preface and the client's first SETTINGS frame
+    Complete(usize),
+    Invalid,
+}
+
+fn preface(head: &[u8]) -> Preface {
+    let n = head.len().min(PREFACE.len());
+    if head[..n] != PREFACE[..n] {
+        return Preface::Invalid;
+    }
+    let Some(header) = head.get(PREFACE.len()..PREFACE.len() + FRAME_HEADER_LEN) else {
+        return Preface::Incomplete;
+    };
+    if header[3] != FRAME_SETTINGS {
+        return Preface::Invalid;
+    }
+    let len = u32::from_be_bytes([0, header[0], header[1], header[2]]) as usize;
+    let end = PREFACE.len() + FRAME_HEADER_LEN + len;
+    if head.len() >= end {
+        Preface::Complete(end)
+    } else {
+        Preface::Incomplete
+    }
+}
+
+// A connection upgraded to h2c. Inserts the frames for the upgraded request
+// right after the client's preface, so the HTTP/2 server sees it as the first
+// stream.
+pub struct H2cIo<T> {
+    io: T,
+    // Frames still to be inserted
+    request: Option<Vec<u8>>,
+    // Client bytes read while looking for the end of its preface
+    head: Vec<u8>,
+    // Bytes to hand out before reading from `io` again
+    pending: Vec<u8>,
+    pos: usize,
+}
+
+impl<T> H2cIo<T> {
+    pub fn new(io: T, request: Vec<u8>) -> H2cIo<T> {
+        H2cIo { io, request: Some(request), head: Vec::new(), pending: Vec::new(), pos: 0 }
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for H2cIo<T> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if this.pos < this.pending.len() {
+                let n = buf.remaining().min(this.pending.len() - this.pos);
+                buf.put_slice(&this.pending[this.pos..this.pos + n]);
+                this.pos += n;
+                return Poll::Ready(Ok(()));
+            }
+            let Some(request) = &this.request else {
+                return Pin::new(&mut this.io).poll_read(cx, buf);
+            };
+            match preface(&this.head) {
+                Preface::Complete(end) => {
+                    let rest = this.head.split_off(end);
+                    this.pending = std::mem::take(&mut this.head);
+                    this.pending.extend_from_slice(request);
+                    this.pending.extend_from_slice(&rest);
+                    this.pos = 0;
+                    this.request = None;
+                }
+                // Not HTTP/2 after all; let the server reject it
+                Preface::Invalid => {
+                    this.pending = std::mem::take(&mut this.head);
+                    this.pos = 0;
+                    this.request = None;
+                }
+                Preface::Incomplete => {
+                    let mut chunk = [0; 4096];
+                    let mut read = ReadBuf::new(&mut chunk);
+                    ready!(Pin::new(&mut this.io).poll_read(cx, &mut read))?;
+                    if read.filled().is_empty() {
+                        // Closed mid-preface; pass on what arrived, then EOF
+                        this.pending = std::mem::take(&mut this.head);
+                        this.pos = 0;
+                        this.request = None;
+                    }
+                    this.head.extend_from_slice(read.filled());
+                }
+            }
+        }
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for H2cIo<T> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().io).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().io).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().io).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    fn int(prefix_bits: u32, n: usize) -> Vec<u8> {
+        let mut out = Vec::new();
+        hpack_int(&mut out, 0, prefix_bits, n);
+        out
+    }
+
+    // Reads back what `hpack_field` writes: literals without Huffman coding
+    fn decode_int(block: &[u8], pos: &mut usize, prefix_bits: u32) -> usize {
+        let max = (1 << prefix_bits) - 1;
+        let mut n = (block[*pos] & max as u8) as usize;
+        *pos += 1;
+        if n < max {
+            return n;
+        }
+        let mut shift = 0;
+        loop {
+            let b = block[*pos];
+            *pos += 1;
+            n += ((b & 0x7f) as usize) << shift;
+            shift += 7;
+            if b & 0x80 == 0 {
+                return n;
+            }
+        }
+    }
+
+    fn decode_fields(block: &[u8]) -> Vec<(String, String)> {
+        let mut fields = Vec::new();
+        let mut pos = 0;
+        while pos < block.len() {
+            assert_eq!(block[pos], 0, "literal without indexing, new name");
+            pos += 1;
+            let mut field = Vec::new();
+            for _ in 0..2 {
+                let len = decode_int(block, &mut pos, 7);
+                field.push(String::from_utf8(block[pos..pos + len].to_vec()).unwrap());
+                pos += len;
+            }
+            fields.push((field.remove(0), field.remove(0)));
+        }
+        fields
+    }
+
+    // (type, flags, stream, payload) for each frame in `bytes`
+    fn split_frames(mut bytes: &[u8]) -> Vec<(u8, u8, u32, Vec<u8>)> {
+        let mut frames = Vec::new();
+        while !bytes.is_empty() {
+            let len = u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]) as usize;
+            let stream = u32::from_be_bytes([bytes[5], bytes[6], bytes[7], bytes[8]]);
+            let end = FRAME_HEADER_LEN + len;
+            frames.push((bytes[3], bytes[4], stream, bytes[FRAME_HEADER_LEN..end].to_vec()));
+            bytes = &bytes[end..];
+        }
+        frames
+    }
+
+    fn settings_frame() -> Vec<u8> {
+        let mut out = PREFACE.to_vec();
+        // SETTINGS_MAX_CONCURRENT_STREAMS = 100
+        frame(&mut out, FRAME_SETTINGS, 0, 0, &[0, 3, 0, 0, 0, 100]);
+        out
+    }
+
+    #[test]
+    fn hpack_int_prefix_boundaries() {
+        assert_eq!(int(7, 0), [0]);
+        assert_eq!(int(7, 126), [126]);
+        // A value filling the prefix needs a continuation byte of 0
+        assert_eq!(int(7, 127), [127, 0]);
+        assert_eq!(int(7, 128), [127, 1]);
+        assert_eq!(int(7, 127 + 127), [127, 127]);
+        assert_eq!(int(7, 127 + 128), [127, 0x80, 1]);
+        // RFC 7541 appendix C.1
+        assert_eq!(int(5, 10), [10]);
+        assert_eq!(int(5, 1337), [31, 154, 10]);
+        assert_eq!(int(8, 42), [42]);
+        assert_eq!(int(8, 255), [255, 0]);
+    }
+
+    #[test]
+    fn hpack_int_keeps_the_leading_bits() {
+        let mut out = Vec::new();
+        hpack_int(&mut out, 0x80, 7, 200);
+        assert_eq!(out, [0xff, 73]);
+        for n in [0, 1, 126, 127, 128, 16383, 16384, 1 << 20] {
+            let bytes = int(7, n);
+            assert_eq!(decode_int(&bytes, &mut 0, 7), n);
+        }
+    }
+
+    #[test]
+    fn upgrade_frames_carry_the_request() {
+        let req = Request::get("/users?page=2")
+            .header(HOST, "api.example.com")
+            .header(CONNECTION, "Upgrade, HTTP2-Settings")
+            .header(UPGRADE, "h2c")
+            .header("http2-settings", "AAMAAABkAAQAAP__")
+            .header(TE, "gzip")
+            .header("accept", "application/json")
+            .body(Body::empty())
+            .unwrap();
+        let frames = split_frames(&upgrade_frames(&req));
+        assert_eq!(frames.len(), 1);
+        let (kind, flags, stream, block) = &frames[0];
+        assert_eq!((*kind, *flags, *stream), (FRAME_HEADERS, FLAG_END_STREAM | FLAG_END_HEADERS, 1));
+        let fields = decode_fields(block);
+        let expected = [
+            (":method", "GET"),
+            (":scheme", "http"),
+            (":authority", "api.example.com"),
+            (":path", "/users?page=2"),
+            ("accept", "application/json"),
+        ];
+        let fields: Vec<(&str, &str)> = fields.iter().map(|(n, v)| (n.as_str(), v.as_str())).collect();
+        assert_eq!(fields, expected);
+    }
+
+    #[test]
+    fn large_header_blocks_continue_in_further_frames() {
+        let big = "a".repeat(40000);
+        let req = Request::get("/")
+            .header(HOST, "localhost")
+            .header("x-big", big.as_str())
+            .body(Body::empty())
+            .unwrap();
+        let frames = split_frames(&upgrade_frames(&req));
+        assert_eq!(frames.len(), 3);
+        assert_eq!((frames[0].0, frames[0].1), (FRAME_HEADERS, FLAG_END_STREAM));
+        assert_eq!((frames[1].0, frames[1].1), (FRAME_CONTINUATION, 0));
+        assert_eq!((frames[2].0, frames[2].1), (FRAME_CONTINUATION, FLAG_END_HEADERS));
+        assert!(frames.iter().all(|f| f.2 == 1 && f.3.len() <= MIN_MAX_FRAME_SIZE));
+        assert_eq!(frames[0].3.len(), MIN_MAX_FRAME_SIZE);
+        assert_eq!(frames[1].3.len(), MIN_MAX_FRAME_SIZE);
+
+        let block: Vec<u8> = frames.into_iter().flat_map(|f| f.3).collect();
+        let fields = decode_fields(&block);
+        assert_eq!(fields.last().unwrap(), &("x-big".to_string(), big));
+    }
+
+    #[test]
+    fn header_block_of_exactly_one_frame_needs_no_continuation() {
+        // Room left after the fixed fields for the `x` field: its flags byte,
+        // one-byte name with its length and a three-byte value length
+        let req = Request::get("/").header(HOST, "h").body(Body::empty()).unwrap();
+        let fixed = upgrade_frames(&req).len() - FRAME_HEADER_LEN;
+        let overhead = 1 + 1 + 1 + 3;
+        let value = "v".repeat(MIN_MAX_FRAME_SIZE - fixed - overhead);
+        let req = Request::get("/").header(HOST, "h").header("x", value).body(Body::empty()).unwrap();
+        let frames = split_frames(&upgrade_frames(&req));
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].3.len(), MIN_MAX_FRAME_SIZE);
+        assert_eq!(frames[0].1, FLAG_END_STREAM | FLAG_END_HEADERS);
+    }
+
+    async fn read_through(client_bytes: Vec<Vec<u8>>, request: Vec<u8>) -> Vec<u8> {
+        let (mut client, server) = tokio::io::duplex(64);
+        tokio::spawn(async move {
+            for chunk in client_bytes {
+                client.write_all(&chunk).await.unwrap();
+                tokio::task::yield_now().await;
+            }
+        });
+        let mut out = Vec::new();
+        H2cIo::new(server, request).read_to_end(&mut out).await.unwrap();
+        out
+    }
+
+    #[tokio::test]
+    async fn request_is_inserted_after_the_client_settings() {
+        let settings = settings_frame();
+        let mut ping = Vec::new();
+        frame(&mut ping, 0x6, 0, 0, &[0; 8]);
+        // The preface arrives in pieces and the next frame with its end
+        let chunks = vec![settings[..10].to_vec(), settings[10..30].to_vec(), [&settings[30..], &ping[..]].concat()];
+        let out = read_through(chunks, b"REQUEST".to_vec()).await;
+        assert_eq!(out, [&settings[..], b"REQUEST", &ping[..]].concat());
+    }
+
+    #[tokio::test]
+    async fn other_protocols_pass_through() {
+        let out = read_through(vec![b"GET / HTTP/1.1\r\n\r\n".to_vec()], b"REQUEST".to_vec()).await;
+        assert_eq!(out, b"GET / HTTP/1.1\r\n\r\n");
+        // Closed partway through the preface
+        let out = read_through(vec![PREFACE[..5].to_vec()], b"REQUEST".to_vec()).await;
+        assert_eq!(out, &PREFACE[..5]);
+    }
+}
@@ -0,0 +1,620 @@
+use crate::auth::Principal;
+use crate::cache::{canonical_json, UNCACHED_HEADERS, X_CACHE};
+use crate::errors::ApiError;
+use crate::AppState;
+use bytes::Bytes;
+use hyper::header::{HeaderMap, HeaderName, HeaderValue, RETRY_AFTER};
+use hyper::{Body, Response, StatusCode};
+use reqwest::Url;
+use rusqlite::OptionalExtension;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub const IDEMPOTENT_REPLAYED: &str = "idempotent-replayed";
+
+// Longest key accepted, as in most payment APIs
+const MAX_KEY_LEN: usize = 255;
+
+// How often expired responses are dropped
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+// The `[idempotency]` section turns replays on for POST and PATCH routes
+// unless it sets `enabled = false`
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FileIdempotency {
+    // Default for POST and PATCH routes; a route's `idempotency` overrides it
+    enabled: Option<bool>,
+    ttl_secs: Option<u64>,
+    // Keep responses in this database instead of in memory, so they survive
+    // a restart
+    sqlite: Option<PathBuf>,
+    max_entries: Option<usize>,
+    // Only applies to the in-memory store
+    max_bytes: Option<usize>,
+    max_entry_bytes: Option<usize>,
+}
+
+#[derive(Debug, Clone)]
+pub struct IdempotencyConfig {
+    pub enabled: bool,
+    // How long a response is replayed for
+    pub ttl: Duration,
+    pub sqlite: Option<PathBuf>,
+    // Beyond these the oldest responses are dropped
+    pub max_entries: usize,
+    pub max_bytes: usize,
+    // Larger responses are not stored, so a retry repeats the call
+    pub max_entry_bytes: usize,
+}
+
+impl Default for IdempotencyConfig {
+    fn default() -> IdempotencyConfig {
+        IdempotencyConfig {
+            enabled: false,
+            ttl: Duration::from_secs(24 * 60 * 60),
+            sqlite: None,
+            max_entries: 10_000,
+            max_bytes: 64 * 1024 * 1024,
+            max_entry_bytes: 1024 * 1024,
+        }
+    }
+}
+
+impl IdempotencyConfig {
+    // The database path is resolved against `base_dir`, the config file's
+    // directory
+    pub fn from_file(file: &FileIdempotency, base_dir: &Path) -> Result<IdempotencyConfig, String> {
+        let mut c = IdempotencyConfig { enabled: file.enabled.unwrap_or(true), ..IdempotencyConfig::default() };
+        match file.ttl_secs {
+            Some(0) => return Err("ttl_secs must be greater than 0".to_string()),
+            Some(secs) => c.ttl = Duration::from_secs(secs),
+            None => {}
+        }
+        if let Some(p) = &file.sqlite {
+            c.sqlite = Some(base_dir.join(p));
+        }
+        if let Some(n) = file.max_entries {
+            if n == 0 {
+                return Err("max_entries must be greater than 0".to_string());
+            }
+            c.max_entries = n;
+        }
+        if let Some(n) = file.max_bytes {
+            c.max_bytes = n;
+        }
+        match file.max_entry_bytes {
+            Some(n) => c.max_entry_bytes = n,
+            None => c.max_entry_bytes = c.max_entry_bytes.min(c.max_bytes),
+        }
+        if c.max_entry_bytes > c.max_bytes {
+            return Err(format!("max_entry_bytes ({}) must not exceed max_bytes ({})", c.max_entry_bytes, c.max_bytes));
+        }
+        Ok(c)
+    }
+}
+
+fn in_progress() -> ApiError {
+    ApiError::new(
+        StatusCode::CONFLICT,
+        "idempotency-key-in-progress",
+        "A request with this Idempotency-Key is still in progress",
+    )
+    .with_header(RETRY_AFTER, HeaderValue::from(1))
+}
+
+fn key_reused() -> ApiError {
+    ApiError::new(
+        StatusCode::UNPROCESSABLE_ENTITY,
+        "idempotency-key-reused",
+        "This Idempotency-Key was used with a different request",
+    )
+}
+
+fn anonymous_caller() -> ApiError {
+    ApiError::new(
+        StatusCode::BAD_REQUEST,
+        "invalid-idempotency-key",
+        "Idempotency-Key needs a token with a `sub` claim, so keys cannot be shared between callers",
+    )
+}
+
+fn invalid_key() -> ApiError {
+    ApiError::new(
+        StatusCode::BAD_REQUEST,
+        "invalid-idempotency-key",
+        format!("Idempotency-Key must be 1 to {} printable ASCII characters", MAX_KEY_LEN),
+    )
+}
+
+fn store_failed(e: impl std::fmt::Display) -> ApiError {
+    eprintln!("Idempotency store failed: {}", e);
+    ApiError::new(
+        StatusCode::SERVICE_UNAVAILABLE,
+        "idempotency-unavailable",
+        "Idempotency keys cannot be checked right now; try again shortly",
+    )
+    .with_header(RETRY_AFTER, HeaderValue::from(1))
+}
+
+// Identifies a key: the route, the authenticated caller and the key itself,
+// so callers cannot replay each other's responses
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Key([u8; 32]);
+
+impl Key {
+    pub fn new(route: &str, principal: Option<&Principal>, key: &HeaderValue) -> Result<Key, ApiError> {
+        let key = key.to_str().map_err(|_| invalid_key())?;
+        if key.is_empty() || key.len() > MAX_KEY_LEN || !key.bytes().all(|b| b.is_ascii_graphic() || b == b' ') {
+            return Err(invalid_key());
+        }
+        let caller = match principal {
+            Some(Principal::Jwt { subject: None }) => return Err(anonymous_caller()),
+            Some(p) => p.caller(),
+            None => String::new(),
+        };
+        let mut hasher = Sha256::new();
+        for part in [route, &caller, key] {
+            hasher.update(part.as_bytes());
+            hasher.update([0]);
+        }
+        Ok(Key(hasher.finalize().into()))
+    }
+}
+
+// What a retry must repeat: the upstream URL and the body as sent upstream,
+// with object keys sorted
+pub fn fingerprint(url: &Url, body: Option<&serde_json::Value>) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(url.as_str().as_bytes());
+    hasher.update([0]);
+    if let Some(body) = body {
+        let mut canonical = String::new();
+        canonical_json(body, &mut canonical);
+        hasher.update(canonical.as_bytes());
+    }
+    hasher.finalize().into()
+}
+
+// A response kept for replay
+#[derive(Clone)]
+struct Stored {
+    fingerprint: [u8; 32],
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+    // Unix seconds
+    expires: u64,
+}
+
+// Responses kept in memory, dropped oldest first beyond the configured
+// limits. Every entry has the same TTL, so the oldest expires first too.
+#[derive(Default)]
+struct Memory {
+    entries: HashMap<Key, (Stored, u64, usize)>,
+    // Keys by insertion order
+    order: BTreeMap<u64, Key>,
+    bytes: usize,
+    next: u64,
+}
+
+impl Memory {
+    fn remove(&mut self, key: &Key) {
+        if let Some((_, seq, size)) = self.entries.remove(key) {
+            self.order.remove(&seq);
+            self.bytes -= size;
+        }
+    }
+
+    fn insert(&mut self, key: Key, stored: Stored, size: usize, config: &IdempotencyConfig) {
+        self.remove(&key);
+        while self.entries.len() >= config.max_entries || self.bytes + size > config.max_bytes {
+            let Some((_, oldest)) = self.order.pop_first() else {
+                break;
+            };
+            if let Some((_, _, evicted)) = self.entries.remove(&oldest) {
+                self.bytes -= evicted;
+            }
+        }
+        let seq = self.next;
+        self.next += 1;
+        self.order.insert(seq, key);
+        self.entries.insert(key, (stored, seq, size));
+        self.bytes += size;
+    }
+
+    fn sweep(&mut self, now: u64) {
+        let expired: Vec<Key> = self.entries.iter().filter(|(_, (s, _, _))| s.expires <= now).map(|(k, _)| *k).collect();
+        for key in &expired {
+            self.remove(key);
+        }
+    }
+}
+
+impl Stored {
+    fn response(&self) -> Response<Body> {
+        let mut response = Response::new(Body::from(self.body.clone()));
+        *response.status_mut() = self.status;
+        *response.headers_mut() = self.headers.clone();
+        response
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+enum Backend {
+    Memory(Mutex<Memory>),
+    // Table `idempotency(key BLOB, fingerprint BLOB, status INTEGER,
+    // headers TEXT, body BLOB, expires INTEGER)`; `headers` is a JSON array
+    // of name and value pairs
+    Sqlite(Arc<Mutex<rusqlite::Connection>>),
+}
+
+fn open_sqlite(path: &Path) -> Result<rusqlite::Connection, String> {
+    let ctx = |e: rusqlite::Error| format!("{}: {}", path.display(), e);
+    let conn = rusqlite::Connection::open(path).map_err(ctx)?;
+    conn.execute_batch(
+        "CREATE TABLE IF NOT EXISTS idempotency (
+            key BLOB PRIMARY KEY,
+            fingerprint BLOB NOT NULL,
+            status INTEGER NOT NULL,
+            headers TEXT NOT NULL,
+            body BLOB NOT NULL,
+            expires INTEGER NOT NULL
+        );
+        CREATE INDEX IF NOT EXISTS idempotency_expires ON idempotency (expires)",
+    )
+    .map_err(ctx)?;
+    Ok(conn)
+}
+
+fn get_sqlite(conn: &rusqlite::Connection, key: &Key) -> rusqlite::Result<Option<Stored>> {
+    let row = conn
+        .query_row(
+            "SELECT fingerprint, status, headers, body, expires FROM idempotency WHERE key = ?1 AND expires > ?2",
+            rusqlite::params![&key.0[..], unix_now() as i64],
+            |row| {
+                Ok((
+                    row.get::<_, Vec<u8>>(0)?,
+                    row.get::<_, u16>(1)?,
+                    row.get::<_, String>(2)?,
+                    row.get::<_, Vec<u8>>(3)?,
+                    row.get::<_, i64>(4)?,
+                ))
+            },
+        )
+        .optional()?;
+    let Some((fingerprint, status, headers, body, expires)) = row else {
+        return Ok(None);
+    };
+    // Rows that do not decode are treated as absent
+    let pairs: Vec<(String, String)> = serde_json::from_str(&headers).unwrap_or_default();
+    let headers = pairs
+        .iter()
+        .filter_map(|(n, v)| Some((HeaderName::from_bytes(n.as_bytes()).ok()?, HeaderValue::from_str(v).ok()?)))
+        .collect();
+    let (Ok(fingerprint), Ok(status)) = (fingerprint.try_into(), StatusCode::from_u16(status)) else {
+        return Ok(None);
+    };
+    Ok(Some(Stored { fingerprint, status, headers, body: Bytes::from(body), expires: expires.max(0) as u64 }))
+}
+
+fn put_sqlite(conn: &rusqlite::Connection, key: &Key, stored: &Stored, max_entries: usize) -> rusqlite::Result<()> {
+    let headers: Vec<(&str, &str)> = stored
+        .headers
+        .iter()
+        .filter_map(|(n, v)| Some((n.as_str(), v.to_str().ok()?)))
+        .collect();
+    conn.execute(
+        "INSERT OR REPLACE INTO idempotency (key, fingerprint, status, headers, body, expires) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        rusqlite::params![
+            &key.0[..],
+            &stored.fingerprint[..],
+            stored.status.as_u16(),
+            serde_json::to_string(&headers).unwrap_or_default(),
+            &stored.body[..],
+            stored.expires as i64,
+        ],
+    )?;
+    // Keep only the newest `max_entries` rows
+    conn.execute(
+        "DELETE FROM idempotency WHERE key IN (SELECT key FROM idempotency ORDER BY expires DESC LIMIT -1 OFFSET ?1)",
+        [max_entries as i64],
+    )?;
+    Ok(())
+}
+
+// Responses replayed for repeated `Idempotency-Key`s, and the keys whose
+// first request is still being handled
+pub struct IdempotencyStore {
+    config: IdempotencyConfig,
+    backend: Backend,
+    // Keys in progress with the fingerprint of their request. Kept in memory
+    // even with SQLite, so a crash never leaves a key stuck.
+    pending: Mutex<HashMap<Key, [u8; 32]>>,
+}
+
+pub enum Begin<'a> {
+    // A response was stored for this key and request
+    Replay(Response<Body>),
+    // The caller goes ahead and hands the result to `Claim::finish`
+    Claimed(Claim<'a>),
+}
+
+impl IdempotencyStore {
+    pub fn open(config: IdempotencyConfig) -> Result<IdempotencyStore, String> {
+        let backend = match &config.sqlite {
+            Some(path) => Backend::Sqlite(Arc::new(Mutex::new(open_sqlite(path)?))),
+            None => Backend::Memory(Mutex::new(Memory::default())),
+        };
+        Ok(IdempotencyStore { config, backend, pending: Mutex::new(HashMap::new()) })
+    }
+
+    // Replay the stored response for `key`, or claim it for this request.
+    // The claim is taken before the lookup so a request finishing meanwhile
+    // is either seen as in progress or found stored.
+    pub async fn begin(&self, key: Key, fingerprint: [u8; 32]) -> Result<Begin<'_>, ApiError> {
+        {
+            let mut pending = self.pending.lock().unwrap();
+            match pending.get(&key) {
+                Some(f) if *f != fingerprint => return Err(key_reused()),
+                Some(_) => return Err(in_progress()),
+                None => pending.insert(key, fingerprint),
+            };
+        }
+        let claim = Claim { store: self, key, fingerprint };
+        match self.get(key).await? {
+            Some(stored) if stored.fingerprint != fingerprint => Err(key_reused()),
+            Some(stored) => {
+                let mut response = stored.response();
+                response.headers_mut().insert(IDEMPOTENT_REPLAYED, HeaderValue::from_static("true"));
+                Ok(Begin::Replay(response))
+            }
+            None => Ok(Begin::Claimed(claim)),
+        }
+    }
+
+    async fn get(&self, key: Key) -> Result<Option<Stored>, ApiError> {
+        match &self.backend {
+            Backend::Memory(memory) => {
+                let memory = memory.lock().unwrap();
+                Ok(memory.entries.get(&key).map(|(s, _, _)| s).filter(|s| s.expires > unix_now()).cloned())
+            }
+            Backend::Sqlite(conn) => {
+                let conn = conn.clone();
+                tokio::task::spawn_blocking(move || get_sqlite(&conn.lock().unwrap(), &key))
+                    .await
+                    .map_err(store_failed)?
+                    .map_err(store_failed)
+            }
+        }
+    }
+
+    async fn put(&self, key: Key, stored: Stored, size: usize) {
+        match &self.backend {
+            Backend::Memory(memory) => memory.lock().unwrap().insert(key, stored, size, &self.config),
+            Backend::Sqlite(conn) => {
+                let conn = conn.clone();
+                let max_entries = self.config.max_entries;
+                let result =
+                    tokio::task::spawn_blocking(move || put_sqlite(&conn.lock().unwrap(), &key, &stored, max_entries)).await;
+                match result {
+                    Ok(Ok(())) => {}
+                    Ok(Err(e)) => eprintln!("Idempotency store failed: {}", e),
+                    Err(e) => eprintln!("Idempotency store failed: {}", e),
+                }
+            }
+        }
+    }
+
+    // Drop responses past their TTL
+    pub async fn sweep(&self) {
+        let now = unix_now();
+        match &self.backend {
+            Backend::Memory(memory) => memory.lock().unwrap().sweep(now),
+            Backend::Sqlite(conn) => {
+                let conn = conn.clone();
+                let result = tokio::task::spawn_blocking(move || {
+                    conn.lock()
+                        .unwrap()
+                        .execute("DELETE FROM idempotency WHERE expires <= ?1", [now as i64])
+                })
+                .await;
+                if let Ok(Err(e)) = result {
+                    eprintln!("Idempotency store sweep failed: {}", e);
+                }
+            }
+        }
+    }
+}
+
+// A key held by the request handling it. Dropping the claim without
+// finishing, as happens when the client disconnects, releases the key.
+pub struct Claim<'a> {
+    store: &'a IdempotencyStore,
+    key: Key,
+    fingerprint: [u8; 32],
+}
+
+impl Claim<'_> {
+    // Store the upstream's answer for replay. Failures the client may retry,
+    // `5xx` answers and problems raised here, release the key instead.
+    pub async fn finish(self, result: Result<Response<Body>, ApiError>) -> Result<Response<Body>, ApiError> {
+        let response = match result {
+            Ok(resp) if !resp.status().is_server_error() => resp,
+            other => return other,
+        };
+        let (parts, body) = response.into_parts();
+        // Relayed bodies are already in memory
+        let body = match hyper::body::to_bytes(body).await {
+            Ok(b) => b,
+            Err(_) => return Ok(Response::from_parts(parts, Body::empty())),
+        };
+        // Replays describe the first exchange, not a new one
+        let mut headers = parts.headers.clone();
+        for name in UNCACHED_HEADERS.iter().copied().chain([X_CACHE]) {
+            headers.remove(name);
+        }
+        let size = body.len() + headers.iter().map(|(n, v)| n.as_str().len() + v.len()).sum::<usize>();
+        if size <= self.store.config.max_entry_bytes {
+            let stored = Stored {
+                fingerprint: self.fingerprint,
+                status: parts.status,
+                headers,
+                body: body.clone(),
+                expires: unix_now() + self.store.config.ttl.as_secs(),
+            };
+            self.store.put(self.key, stored, size).await;
+        }
+        Ok(Response::from_parts(parts, Body::from(body)))
+    }
+}
+
+impl Drop for Claim<'_> {
+    fn drop(&mut self) {
+        self.store.pending.lock().unwrap().remove(&self.key);
+    }
+}
+
+pub fn spawn_sweep(state: Arc<AppState>) {
+    let mut interval = tokio::time::interval(SWEEP_INTERVAL);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    tokio::spawn(async move {
+        loop {
+            interval.tick().await;
+            state.idempotency.sweep().await;
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stored(body: &'static str) -> Stored {
+        Stored {
+            fingerprint: [0; 32],
+            status: StatusCode::OK,
+            headers: HeaderMap::new(),
+            body: Bytes::from_static(body.as_bytes()),
+            expires: unix_now() + 60,
+        }
+    }
+
+    fn key(name: &str) -> Key {
+        Key::new("POST /hello", None, &HeaderValue::from_str(name).unwrap()).unwrap()
+    }
+
+    fn memory_store() -> IdempotencyStore {
+        IdempotencyStore::open(IdempotencyConfig { enabled: true, ..IdempotencyConfig::default() }).unwrap()
+    }
+
+    #[test]
+    fn fingerprint_ignores_key_order() {
+        let url = Url::parse("http://upstream/post").unwrap();
+        let a = serde_json::json!({"a": 1, "b": {"c": [1, 2], "d": null}});
+        let b: serde_json::Value = serde_json::from_str(r#"{ "b": {"d": null, "c": [1, 2]}, "a": 1 }"#).unwrap();
+        assert_eq!(fingerprint(&url, Some(&a)), fingerprint(&url, Some(&b)));
+        let c = serde_json::json!({"a": 1, "b": {"c": [2, 1], "d": null}});
+        assert_ne!(fingerprint(&url, Some(&a)), fingerprint(&url, Some(&c)));
+        let other = Url::parse("http://upstream/post?x=1").unwrap();
+        assert_ne!(fingerprint(&url, Some(&a)), fingerprint(&other, Some(&a)));
+    }
+
+    #[test]
+    fn keys_are_scoped_to_the_caller() {
+        let value = HeaderValue::from_static("k1");
+        let alice = Principal::ApiKey { id: "alice".to_string(), owner: None };
+        let bob = Principal::ApiKey { id: "bob".to_string(), owner: None };
+        let a = Key::new("POST /hello", Some(&alice), &value).unwrap();
+        let b = Key::new("POST /hello", Some(&bob), &value).unwrap();
+        assert_ne!(a, b);
+        assert_ne!(a, Key::new("POST /other", Some(&alice), &value).unwrap());
+    }
+
+    #[test]
+    fn tokens_without_subject_are_rejected() {
+        let anonymous = Principal::Jwt { subject: None };
+        let err = Key::new("POST /hello", Some(&anonymous), &HeaderValue::from_static("k1")).unwrap_err();
+        assert_eq!(err.status, StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn invalid_keys_are_rejected() {
+        for bad in ["", "tab\there", &"x".repeat(MAX_KEY_LEN + 1)] {
+            assert!(Key::new("POST /hello", None, &HeaderValue::from_str(bad).unwrap()).is_err(), "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn memory_evicts_oldest_beyond_max_entries() {
+        let config = IdempotencyConfig { max_entries: 2, ..IdempotencyConfig::default() };
+        let mut memory = Memory::default();
+        for name in ["a", "b", "c"] {
+            memory.insert(key(name), stored("x"), 10, &config);
+        }
+        assert_eq!(memory.entries.len(), 2);
+        assert!(!memory.entries.contains_key(&key("a")));
+        assert_eq!(memory.bytes, 20);
+    }
+
+    #[test]
+    fn memory_evicts_oldest_beyond_max_bytes() {
+        let config = IdempotencyConfig { max_bytes: 25, ..IdempotencyConfig::default() };
+        let mut memory = Memory::default();
+        memory.insert(key("a"), stored("x"), 10, &config);
+        memory.insert(key("b"), stored("x"), 10, &config);
+        memory.insert(key("c"), stored("x"), 10, &config);
+        assert!(!memory.entries.contains_key(&key("a")));
+        assert!(memory.bytes <= 25);
+        // Replacing a key does not count it twice
+        memory.insert(key("c"), stored("x"), 10, &config);
+        assert_eq!(memory.bytes, 20);
+    }
+
+    #[test]
+    fn disabled_unless_configured() {
+        assert!(!IdempotencyConfig::default().enabled);
+        let file = FileIdempotency::default();
+        assert!(IdempotencyConfig::from_file(&file, Path::new(".")).unwrap().enabled);
+    }
+
+    #[tokio::test]
+    async fn replays_conflicts_and_mismatches() {
+        let store = memory_store();
+        let Begin::Claimed(claim) = store.begin(key("k"), [1; 32]).await.unwrap() else {
+            panic!("expected a claim");
+        };
+        let err = store.begin(key("k"), [1; 32]).await.err().unwrap();
+        assert_eq!(err.status, StatusCode::CONFLICT);
+        let err = store.begin(key("k"), [2; 32]).await.err().unwrap();
+        assert_eq!(err.status, StatusCode::UNPROCESSABLE_ENTITY);
+
+        claim.finish(Ok(Response::new(Body::from("first")))).await.unwrap();
+        let Begin::Replay(replay) = store.begin(key("k"), [1; 32]).await.unwrap() else {
+            panic!("expected a replay");
+        };
+        assert_eq!(replay.headers()[IDEMPOTENT_REPLAYED], "true");
+        assert_eq!(hyper::body::to_bytes(replay.into_body()).await.unwrap(), "first");
+        let err = store.begin(key("k"), [2; 32]).await.err().unwrap();
+        assert_eq!(err.status, StatusCode::UNPROCESSABLE_ENTITY);
+    }
+
+    #[tokio::test]
+    async fn server_errors_release_the_key() {
+        let store = memory_store();
+        let Begin::Claimed(claim) = store.begin(key("k"), [1; 32]).await.unwrap() else {
+            panic!("expected a claim");
+        };
+        let mut failed = Response::new(Body::empty());
+        *failed.status_mut() = StatusCode::BAD_GATEWAY;
+        claim.finish(Ok(failed)).await.unwrap();
+        assert!(matches!(store.begin(key("k"), [1; 32]).await, Ok(Begin::Claimed(_))));
+    }
+}
@@ -0,0 +1,468 @@
+use crate::config::RouteConfig;
+use crate::errors::ApiError;
+use crate::AppState;
+use hyper::header::HeaderValue;
+use hyper::{Body, Request, StatusCode};
+use jsonwebtoken::jwk::{AlgorithmParameters, JwkSet};
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use reqwest::Url;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+
+// Unknown `kid`s trigger an early JWKS refresh, at most this often
+const MIN_REFRESH_SPACING: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FileJwt {
+    issuer: Option<String>,
+    audience: Option<Vec<String>>,
+    algorithms: Option<Vec<String>>,
+    leeway_secs: Option<u64>,
+    // Exactly one of `jwks_file` and `jwks_url` provides the verification keys
+    jwks_file: Option<PathBuf>,
+    jwks_url: Option<String>,
+    refresh_interval_secs: Option<u64>,
+}
+
+#[derive(Debug, Clone)]
+pub enum JwksSource {
+    File(PathBuf),
+    Url(Url),
+}
+
+#[derive(Debug, Clone)]
+pub struct JwtConfig {
+    // Required `iss`, when set
+    pub issuer: Option<String>,
+    // The token's `aud` must contain one of these, when set
+    pub audience: Vec<String>,
+    pub algorithms: Vec<Algorithm>,
+    // Clock skew allowed when checking `exp` and `nbf`
+    pub leeway: Duration,
+    pub source: JwksSource,
+    pub refresh_interval: Duration,
+}
+
+impl JwtConfig {
+    // A relative `jwks_file` is resolved against `base_dir`, the config file's directory
+    pub fn from_file(file: &FileJwt, base_dir: &Path) -> Result<JwtConfig, String> {
+        let source = match (&file.jwks_file, &file.jwks_url) {
+            (Some(p), None) => JwksSource::File(base_dir.join(p)),
+            (None, Some(u)) => {
+                JwksSource::Url(Url::parse(u).map_err(|e| format!("invalid jwks_url '{}': {}", u, e))?)
+            }
+            _ => return Err("exactly one of `jwks_file` and `jwks_url` must be set".to_string()),
+        };
+        let algorithms = match &file.algorithms {
+            Some(names) if names.is_empty() => return Err("algorithms must not be empty".to_string()),
+            Some(names) => names
+                .iter()
+                .map(|n| Algorithm::from_str(n).map_err(|_| format!("unknown algorithm '{}'", n)))
+                .collect::<Result<_, _>>()?,
+            None => vec![Algorithm::RS256, Algorithm::ES256],
+        };
+        let refresh_interval = match file.refresh_interval_secs {
+            Some(0) => return Err("refresh_interval_secs must be greater than 0".to_string()),
+            Some(s) => Duration::from_secs(s),
+            None => Duration::from_secs(300),
+        };
+        Ok(JwtConfig {
+            issuer: file.issuer.clone(),
+            audience: file.audience.clone().unwrap_or_default(),
+            algorithms,
+            leeway: Duration::from_secs(file.leeway_secs.unwrap_or(60)),
+            source,
+            refresh_interval,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum KeyFamily {
+    Hmac,
+    Rsa,
+    Ec,
+    Ed,
+}
+
+fn family(alg: Algorithm) -> KeyFamily {
+    match alg {
+        Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512 => KeyFamily::Hmac,
+        Algorithm::ES256 | Algorithm::ES384 => KeyFamily::Ec,
+        Algorithm::EdDSA => KeyFamily::Ed,
+        _ => KeyFamily::Rsa,
+    }
+}
+
+struct VerificationKey {
+    kid: Option<String>,
+    // The JWK's own `alg`, if it pins one
+    alg: Option<Algorithm>,
+    family: KeyFamily,
+    key: DecodingKey,
+}
+
+fn parse_jwks(text: &str) -> Result<Vec<VerificationKey>, String> {
+    let set: JwkSet = serde_json::from_str(text).map_err(|e| format!("invalid JWKS: {}", e))?;
+    let mut keys = Vec::new();
+    for jwk in &set.keys {
+        let family = match &jwk.algorithm {
+            AlgorithmParameters::RSA(_) => KeyFamily::Rsa,
+            AlgorithmParameters::EllipticCurve(_) => KeyFamily::Ec,
+            AlgorithmParameters::OctetKeyPair(_) => KeyFamily::Ed,
+            AlgorithmParameters::OctetKey(_) => KeyFamily::Hmac,
+        };
+        let key = DecodingKey::from_jwk(jwk).map_err(|e| format!("invalid JWK {:?}: {}", jwk.common.key_id, e))?;
+        let alg = jwk.common.key_algorithm.and_then(|a| Algorithm::from_str(&a.to_string()).ok());
+        keys.push(VerificationKey { kid: jwk.common.key_id.clone(), alg, family, key });
+    }
+    Ok(keys)
+}
+
+// Checks bearer tokens against the configured issuer, audience and keys
+pub struct JwtValidator {
+    config: JwtConfig,
+    keys: RwLock<Vec<VerificationKey>>,
+    // Woken when a token names a key we do not have
+    refresh: Notify,
+}
+
+impl JwtValidator {
+    // A JWKS file is read now; a JWKS URL is fetched by `spawn_refresh`
+    pub fn new(config: JwtConfig) -> Result<JwtValidator, String> {
+        let keys = match &config.source {
+            JwksSource::File(p) => {
+                let text = std::fs::read_to_string(p).map_err(|e| format!("cannot read {}: {}", p.display(), e))?;
+                parse_jwks(&text).map_err(|e| format!("{}: {}", p.display(), e))?
+            }
+            JwksSource::Url(_) => Vec::new(),
+        };
+        Ok(JwtValidator { config, keys: RwLock::new(keys), refresh: Notify::new() })
+    }
+
+    // Verify signature, `exp`/`nbf`, `iss` and `aud`, returning the claims
+    pub fn verify(&self, token: &str) -> Result<Map<String, Value>, ApiError> {
+        let header = jsonwebtoken::decode_header(token).map_err(|_| invalid_token("Malformed token"))?;
+        if !self.config.algorithms.contains(&header.alg) {
+            return Err(invalid_token(&format!("Algorithm {:?} is not accepted", header.alg)));
+        }
+        let mut validation = Validation::new(header.alg);
+        validation.leeway = self.config.leeway.as_secs();
+        validation.validate_nbf = true;
+        if let Some(iss) = &self.config.issuer {
+            validation.set_issuer(&[iss]);
+        }
+        if self.config.audience.is_empty() {
+            validation.validate_aud = false;
+        } else {
+            validation.set_audience(&self.config.audience);
+        }
+
+        let keys = self.keys.read().unwrap();
+        let candidates: Vec<&VerificationKey> = keys
+            .iter()
+            .filter(|k| k.family == family(header.alg))
+            .filter(|k| k.alg.is_none_or(|a| a == header.alg))
+            .filter(|k| header.kid.is_none() || k.kid == header.kid)
+            .collect();
+        if candidates.is_empty() {
+            self.refresh.notify_one();
+            return Err(invalid_token("Unknown signing key"));
+        }
+        let mut last_error = None;
+        for key in candidates {
+            match jsonwebtoken::decode::<Map<String, Value>>(token, &key.key, &validation) {
+                Ok(data) => return Ok(data.claims),
+                Err(e) => last_error = Some(e),
+            }
+        }
+        use jsonwebtoken::errors::ErrorKind;
+        let detail = match last_error.as_ref().map(|e| e.kind()) {
+            Some(ErrorKind::ExpiredSignature) => "Token has expired".to_string(),
+            Some(ErrorKind::ImmatureSignature) => "Token is not valid yet".to_string(),
+            Some(ErrorKind::InvalidIssuer) => "Token issuer is not accepted".to_string(),
+            Some(ErrorKind::InvalidAudience) => "Token audience is not accepted".to_string(),
+            Some(ErrorKind::MissingRequiredClaim(c)) => format!("Token is missing the '{}' claim", c),
+            Some(ErrorKind::InvalidSignature) => "Token signature is invalid".to_string(),
+            _ => "Invalid token".to_string(),
+        };
+        Err(invalid_token(&detail))
+    }
+
+    async fn reload(&self, client: &reqwest::Client) -> Result<(), String> {
+        let keys = match &self.config.source {
+            JwksSource::File(p) => {
+                let text = tokio::fs::read_to_string(p).await.map_err(|e| format!("cannot read {}: {}", p.display(), e))?;
+                parse_jwks(&text).map_err(|e| format!("{}: {}", p.display(), e))?
+            }
+            JwksSource::Url(url) => {
+                let resp = client.get(url.clone()).send().await.map_err(|e| format!("{}: {}", url, e))?;
+                if !resp.status().is_success() {
+                    return Err(format!("{}: status {}", url, resp.status()));
+                }
+                let text = resp.text().await.map_err(|e| format!("{}: {}", url, e))?;
+                parse_jwks(&text).map_err(|e| format!("{}: {}", url, e))?
+            }
+        };
+        *self.keys.write().unwrap() = keys;
+        Ok(())
+    }
+}
+
+// Fetch the keys on start (for a JWKS URL), then on every interval, and early
+// when a token names an unknown key; a failed refresh keeps the old keys
+pub fn spawn_refresh(state: Arc<AppState>) {
+    let Some(validator) = &state.jwt else {
+        return;
+    };
+    let mut interval = tokio::time::interval(validator.config.refresh_interval);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    let client = reqwest::Client::builder()
+        .use_rustls_tls()
+        .timeout(Duration::from_secs(10))
+        .build()
+        .expect("Failed to build reqwest client");
+    tokio::spawn(async move {
+        let validator = state.jwt.as_ref().expect("validator present");
+        if let JwksSource::File(_) = validator.config.source {
+            // Already read at startup
+            interval.tick().await;
+        }
+        let mut last = None::<Instant>;
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = validator.refresh.notified() => {
+                    if last.is_some_and(|t| t.elapsed() < MIN_REFRESH_SPACING) {
+                        continue;
+                    }
+                }
+            }
+            last = Some(Instant::now());
+            if let Err(e) = validator.reload(&client).await {
+                eprintln!("Failed to refresh JWKS: {}", e);
+            }
+        }
+    });
+}
+
+// The token from `Authorization: Bearer <token>`, if any
+pub fn bearer_token(req: &Request<Body>) -> Option<&str> {
+    let value = req.headers().get(hyper::header::AUTHORIZATION)?.to_str().ok()?;
+    let (scheme, token) = value.split_once(' ')?;
+    scheme.eq_ignore_ascii_case("bearer").then(|| token.trim()).filter(|t| !t.is_empty())
+}
+
+fn invalid_token(detail: &str) -> ApiError {
+    ApiError::new(StatusCode::UNAUTHORIZED, "invalid-credentials", detail).with_header(
+        hyper::header::WWW_AUTHENTICATE,
+        HeaderValue::from_static("Bearer error=\"invalid_token\""),
+    )
+}
+
+fn forbidden(kind: &'static str, detail: String) -> ApiError {
+    ApiError::new(StatusCode::FORBIDDEN, kind, detail).with_header(
+        hyper::header::WWW_AUTHENTICATE,
+        HeaderValue::from_static("Bearer error=\"insufficient_scope\""),
+    )
+}
+
+// Look up a claim by name; dots step into nested objects (`realm.roles`)
+fn claim<'a>(claims: &'a Map<String, Value>, name: &str) -> Option<&'a Value> {
+    let mut parts = name.split('.');
+    let mut value = claims.get(parts.next()?)?;
+    for part in parts {
+        value = value.get(part)?;
+    }
+    Some(value)
+}
+
+// Scopes from `scope` (space-separated) or `scp` (string or array)
+fn token_scopes(claims: &Map<String, Value>) -> Vec<&str> {
+    let mut scopes = Vec::new();
+    for name in ["scope", "scp"] {
+        match claims.get(name) {
+            Some(Value::String(s)) => scopes.extend(s.split_whitespace()),
+            Some(Value::Array(items)) => scopes.extend(items.iter().filter_map(Value::as_str)),
+            _ => {}
+        }
+    }
+    scopes
+}
+
+// Check the route's required scopes and claims against a verified token
+pub fn authorize(claims: &Map<String, Value>, route: &RouteConfig) -> Result<(), ApiError> {
+    let scopes = token_scopes(claims);
+    let missing: Vec<&str> = route.scopes.iter().map(String::as_str).filter(|s| !scopes.contains(s)).collect();
+    if !missing.is_empty() {
+        return Err(forbidden("insufficient-scope", "Token lacks the scopes this route requires".to_string())
+            .with_extension("missing_scopes", missing));
+    }
+    for (name, accepted) in &route.claims {
+        let ok = match claim(claims, name) {
+            Some(Value::Array(items)) => items.iter().any(|v| accepted.contains(v)),
+            Some(v) => accepted.contains(v),
+            None => false,
+        };
+        if !ok {
+            return Err(forbidden("insufficient-claims", format!("Token claim '{}' does not permit this route", name))
+                .with_extension("claim", name.as_str()));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::Response;
+    use jsonwebtoken::{EncodingKey, Header};
+    use serde_json::json;
+    use std::convert::Infallible;
+    use std::sync::Mutex;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    const FIRST: (&str, &[u8], &str) = ("k1", b"first-secret-for-signing-tokens!", "Zmlyc3Qtc2VjcmV0LWZvci1zaWduaW5nLXRva2VucyE");
+    const SECOND: (&str, &[u8], &str) = ("k2", b"second-secret-for-signing-token!", "c2Vjb25kLXNlY3JldC1mb3Itc2lnbmluZy10b2tlbiE");
+
+    fn jwks(keys: &[(&str, &[u8], &str)]) -> String {
+        let keys: Vec<Value> = keys.iter().map(|(kid, _, k)| json!({ "kty": "oct", "kid": kid, "k": k })).collect();
+        json!({ "keys": keys }).to_string()
+    }
+
+    // JWKS endpoint serving whatever `keys` holds at the time
+    fn jwks_server(keys: Arc<Mutex<String>>) -> Url {
+        let make_svc = make_service_fn(move |_| {
+            let keys = keys.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |_| {
+                    let body = keys.lock().unwrap().clone();
+                    async move { Ok::<_, Infallible>(Response::new(Body::from(body))) }
+                }))
+            }
+        });
+        let server = hyper::Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_svc);
+        let url = Url::parse(&format!("http://{}/.well-known/jwks.json", server.local_addr())).unwrap();
+        tokio::spawn(server);
+        url
+    }
+
+    fn validator(url: &Url) -> JwtValidator {
+        let file = FileJwt {
+            issuer: Some("https://issuer.test".to_string()),
+            audience: Some(vec!["rust_api".to_string()]),
+            algorithms: Some(vec!["HS256".to_string()]),
+            leeway_secs: Some(30),
+            jwks_url: Some(url.to_string()),
+            ..Default::default()
+        };
+        JwtValidator::new(JwtConfig::from_file(&file, Path::new(".")).unwrap()).unwrap()
+    }
+
+    fn now() -> i64 {
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64
+    }
+
+    fn token(key: (&str, &[u8], &str), overrides: Value) -> String {
+        let mut claims = json!({ "iss": "https://issuer.test", "aud": "rust_api", "sub": "alice", "exp": now() + 300 });
+        for (k, v) in overrides.as_object().unwrap() {
+            claims[k] = v.clone();
+        }
+        let header = Header { kid: Some(key.0.to_string()), ..Header::new(Algorithm::HS256) };
+        jsonwebtoken::encode(&header, &claims, &EncodingKey::from_secret(key.1)).unwrap()
+    }
+
+    fn detail(result: Result<Map<String, Value>, ApiError>) -> String {
+        format!("{:?}", result.unwrap_err())
+    }
+
+    #[tokio::test]
+    async fn keys_come_from_the_jwks_url() {
+        let keys = Arc::new(Mutex::new(jwks(&[FIRST])));
+        let url = jwks_server(keys.clone());
+        let validator = validator(&url);
+        let client = reqwest::Client::new();
+        // Nothing is trusted before the first fetch
+        assert!(detail(validator.verify(&token(FIRST, json!({})))).contains("Unknown signing key"));
+        validator.reload(&client).await.unwrap();
+        let claims = validator.verify(&token(FIRST, json!({}))).unwrap();
+        assert_eq!(claims["sub"], "alice");
+
+        // A rotated key is unknown until the next fetch, which is requested
+        *keys.lock().unwrap() = jwks(&[FIRST, SECOND]);
+        assert!(detail(validator.verify(&token(SECOND, json!({})))).contains("Unknown signing key"));
+        tokio::time::timeout(Duration::from_secs(1), validator.refresh.notified()).await.unwrap();
+        validator.reload(&client).await.unwrap();
+        assert!(validator.verify(&token(SECOND, json!({}))).is_ok());
+
+        // A failed fetch keeps the keys we have
+        *keys.lock().unwrap() = "not json".to_string();
+        assert!(validator.reload(&client).await.is_err());
+        assert!(validator.verify(&token(FIRST, json!({}))).is_ok());
+    }
+
+    #[tokio::test]
+    async fn registered_claims_are_checked() {
+        let url = jwks_server(Arc::new(Mutex::new(jwks(&[FIRST]))));
+        let validator = validator(&url);
+        validator.reload(&reqwest::Client::new()).await.unwrap();
+        let check = |claims: Value| detail(validator.verify(&token(FIRST, claims)));
+        assert!(check(json!({ "exp": now() - 120 })).contains("Token has expired"));
+        // Within the leeway
+        assert!(validator.verify(&token(FIRST, json!({ "exp": now() - 10 }))).is_ok());
+        assert!(check(json!({ "nbf": now() + 120 })).contains("Token is not valid yet"));
+        assert!(check(json!({ "iss": "https://evil.test" })).contains("Token issuer is not accepted"));
+        assert!(check(json!({ "aud": "other" })).contains("Token audience is not accepted"));
+
+        let forged = token((FIRST.0, SECOND.1, FIRST.2), json!({}));
+        assert!(detail(validator.verify(&forged)).contains("Token signature is invalid"));
+        let header = Header { kid: Some("k1".to_string()), ..Header::new(Algorithm::HS384) };
+        let hs384 = jsonwebtoken::encode(&header, &json!({ "exp": now() + 60 }), &EncodingKey::from_secret(FIRST.1)).unwrap();
+        assert!(detail(validator.verify(&hs384)).contains("Algorithm HS384 is not accepted"));
+        assert!(detail(validator.verify("not.a.token")).contains("Malformed token"));
+    }
+
+    #[test]
+    fn route_scopes_and_claims() {
+        let config = Config::from_toml(
+            r#"
+            upstream = "http://127.0.0.1:9"
+            [jwt]
+            jwks_url = "http://127.0.0.1:9/jwks.json"
+            [[routes]]
+            method = "GET"
+            path = "/admin"
+            scopes = ["users:read"]
+            claims = { "realm.roles" = "admin" }
+            "#,
+        )
+        .unwrap();
+        let route = &config.routes[0];
+        let claims = |v: Value| v.as_object().unwrap().clone();
+        assert!(authorize(&claims(json!({ "scope": "users:read", "realm": { "roles": ["dev", "admin"] } })), route).is_ok());
+        assert!(authorize(&claims(json!({ "scp": ["users:read"], "realm": { "roles": "admin" } })), route).is_ok());
+        let err = authorize(&claims(json!({ "scope": "users:write", "realm": { "roles": ["admin"] } })), route).unwrap_err();
+        assert_eq!(err.status, StatusCode::FORBIDDEN);
+        assert!(format!("{:?}", err).contains("Token lacks the scopes"));
+        let err = authorize(&claims(json!({ "scope": "users:read", "realm": { "roles": ["dev"] } })), route).unwrap_err();
+        assert!(format!("{:?}", err).contains("Token claim 'realm.roles' does not permit this route"));
+        assert!(authorize(&claims(json!({ "scope": "users:read" })), route).is_err());
+    }
+
+    #[test]
+    fn bearer_tokens() {
+        let req = |value: &str| Request::get("/").header("authorization", value).body(Body::empty()).unwrap();
+        assert_eq!(bearer_token(&req("Bearer abc")), Some("abc"));
+        assert_eq!(bearer_token(&req("bearer  abc ")), Some("abc"));
+        assert_eq!(bearer_token(&req("Basic abc")), None);
+        assert_eq!(bearer_token(&req("Bearer ")), None);
+    }
+}
@@ -0,0 +1,139 @@
+use crate::tls::ClientCert;
+use hyper::server::accept::Accept;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+
+// Longest a client may take to complete the TLS handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+// Accepted connections waiting for the server to pick them up
+const BACKLOG: usize = 128;
+
+enum Stream {
+    Plain(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+}
+
+// An accepted client connection, after the TLS handshake if there is one
+pub struct Conn {
+    stream: Stream,
+    remote: SocketAddr,
+}
+
+// Who is on the other end of a connection
+#[derive(Debug, Clone)]
+pub struct Peer {
+    pub addr: SocketAddr,
+    // Present when the client authenticated with a certificate
+    pub client_cert: Option<Arc<ClientCert>>,
+}
+
+impl Conn {
+    pub fn peer(&self) -> Peer {
+        let client_cert = match &self.stream {
+            Stream::Tls(s) => s
+                .get_ref()
+                .1
+                .peer_certificates()
+                .and_then(|chain| chain.first())
+                .and_then(|leaf| ClientCert::parse(&leaf.0))
+                .map(Arc::new),
+            Stream::Plain(_) => None,
+        };
+        Peer { addr: self.remote, client_cert }
+    }
+}
+
+impl AsyncRead for Conn {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        match &mut self.get_mut().stream {
+            Stream::Plain(s) => Pin::new(s).poll_read(cx, buf),
+            Stream::Tls(s) => Pin::new(s).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Conn {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match &mut self.get_mut().stream {
+            Stream::Plain(s) => Pin::new(s).poll_write(cx, buf),
+            Stream::Tls(s) => Pin::new(s).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match &mut self.get_mut().stream {
+            Stream::Plain(s) => Pin::new(s).poll_flush(cx),
+            Stream::Tls(s) => Pin::new(s).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match &mut self.get_mut().stream {
+            Stream::Plain(s) => Pin::new(s).poll_shutdown(cx),
+            Stream::Tls(s) => Pin::new(s).poll_shutdown(cx),
+        }
+    }
+}
+
+// Connections for hyper to serve, fed by the accept loop
+pub struct Incoming {
+    rx: mpsc::Receiver<Conn>,
+}
+
+impl Accept for Incoming {
+    type Conn = Conn;
+    type Error = io::Error;
+
+    fn poll_accept(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<io::Result<Conn>>> {
+        self.rx.poll_recv(cx).map(|conn| conn.map(Ok))
+    }
+}
+
+// Accept connections on `listener`, performing TLS handshakes in their own
+// tasks so a slow client cannot hold up the others. The loop ends once the
+// server stops taking connections.
+pub fn spawn_accept(listener: TcpListener, tls: Option<TlsAcceptor>) -> Incoming {
+    let (tx, rx) = mpsc::channel(BACKLOG);
+    tokio::spawn(async move {
+        loop {
+            let (tcp, remote) = tokio::select! {
+                accepted = listener.accept() => match accepted {
+                    Ok(a) => a,
+                    Err(e) => {
+                        // Typically out of file descriptors; give some a chance to close
+                        eprintln!("Failed to accept connection: {}", e);
+                        tokio::time::sleep(Duration::from_secs(1)).await;
+                        continue;
+                    }
+                },
+                _ = tx.closed() => return,
+            };
+            let Some(acceptor) = tls.clone() else {
+                if tx.send(Conn { stream: Stream::Plain(tcp), remote }).await.is_err() {
+                    return;
+                }
+                continue;
+            };
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(tcp)).await {
+                    Ok(Ok(stream)) => {
+                        tx.send(Conn { stream: Stream::Tls(Box::new(stream)), remote }).await.ok();
+                    }
+                    Ok(Err(e)) => eprintln!("TLS handshake with {} failed: {}", remote, e),
+                    Err(_) => eprintln!("TLS handshake with {} timed out", remote),
+                }
+            });
+        }
+    });
+    Incoming { rx }
+}
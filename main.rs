@@ -1,9 +1,123 @@
-use hyper::{Body, Request, Response, Server, Method, StatusCode};
+use bytes::Bytes;
+use hyper::header::{HeaderMap, HeaderName, HeaderValue, AUTHORIZATION};
+use hyper::{Body, Method, Request, Response, StatusCode};
 use hyper::service::{make_service_fn, service_fn};
-use reqwest::Client;
+use reqwest::{Client, Url};
 use std::convert::Infallible;
-use std::net::SocketAddr;
-use bytes::Bytes;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::Instant;
+
+mod access_log;
+mod admin;
+mod auth;
+mod breaker;
+mod cache;
+mod coalesce;
+mod concurrency;
+mod config;
+mod errors;
+mod health;
+mod http2;
+mod idempotency;
+mod jwt;
+mod listener;
+mod metrics;
+mod oauth;
+mod ratelimit;
+mod redact;
+mod retry;
+mod routes;
+mod schema;
+mod shutdown;
+mod timeouts;
+mod tls;
+mod trace;
+
+use breaker::CircuitBreaker;
+use config::{Config, RouteConfig};
+use errors::ApiError;
+use std::collections::HashMap;
+use retry::ErrorKind;
+use routes::{expects_body, Params, RouteMatch, Router};
+use trace::SpanContext;
+
+// Inbound header marking a non-idempotent request as safe to retry; it is
+// forwarded to the upstream
+const IDEMPOTENCY_KEY: &str = "idempotency-key";
+
+// Response header reporting how many upstream retries were made
+const X_RETRY_COUNT: &str = "x-retry-count";
+
+// Shared state handed to every request
+struct AppState {
+    // Outgoing clients keyed by upstream name and connect timeout
+    clients: HashMap<(String, Duration), Client>,
+    config: Config,
+    router: Router,
+    // One circuit breaker per upstream name
+    breakers: HashMap<String, CircuitBreaker>,
+    // Cleared as soon as shutdown starts
+    ready: AtomicBool,
+    // Requests currently being handled
+    in_flight: AtomicUsize,
+    // Connections upgraded to h2c, outside the server's graceful shutdown
+    detached: shutdown::Detached,
+    // Latest upstream readiness probe results
+    probes: health::Probes,
+    metrics: metrics::Metrics,
+    tracer: trace::Tracer,
+    access_log: access_log::AccessLog,
+    // Present when `[api_keys]` is configured
+    api_keys: Option<auth::KeyStore>,
+    // Present when `[jwt]` is configured
+    jwt: Option<jwt::JwtValidator>,
+    // Access tokens for upstreams configured with `oauth2`
+    tokens: HashMap<String, oauth::TokenSource>,
+    // Buckets for routes with a `rate_limit`
+    rate_limiter: ratelimit::RateLimiter,
+    // Present when `[concurrency]` is configured
+    global_limiter: Option<concurrency::Limiter>,
+    // Keyed by route name, for routes with a `concurrency` table
+    route_limiters: HashMap<String, concurrency::Limiter>,
+    // Certificates served when `[tls]` is configured
+    tls: Option<Arc<tls::Certificates>>,
+    // Responses of routes with caching enabled
+    cache: cache::Cache,
+    // Upstream calls shared by identical concurrent requests
+    coalescer: coalesce::Coalescer,
+    // Responses replayed for repeated `Idempotency-Key`s
+    idempotency: idempotency::IdempotencyStore,
+}
+
+// Facts about a request gathered while handling it, for metrics and the
+// access log
+#[derive(Default)]
+struct RequestInfo {
+    request_id: String,
+    // Route pattern, internal endpoint path, or `metrics::UNMATCHED_ROUTE`
+    route: String,
+    // Inbound request body size, for routes that read one
+    bytes_in: usize,
+    // Upstream name and URL, once the request is forwarded
+    upstream: Option<String>,
+    upstream_url: Option<String>,
+    attempts: u32,
+    // Time spent waiting on upstream attempts
+    upstream_time: Duration,
+    // Request body as logged, when `access_log.include_body` is set
+    body: Option<serde_json::Value>,
+    // Authenticated caller, on routes that require credentials
+    principal: Option<auth::Principal>,
+    // Whether the response came from a call made for another request
+    coalesced: bool,
+}
+
+// Helper: Random request identifier, used when the caller did not send one
+fn new_request_id() -> String {
+    format!("{:032x}", rand::random::<u128>())
+}
 
 // Helper: Validate content type
 fn is_json_content_type(req: &Request<Body>) -> bool {
@@ -11,113 +125,1080 @@ fn is_json_content_type(req: &Request<Body>) -> bool {
 }
 
 // Helper: Read body
-async fn read_body(req: Request<Body>) -> Result<bytes::Bytes, Response<Body>> {
+async fn read_body(req: Request<Body>) -> Result<Bytes, ApiError> {
     match hyper::body::to_bytes(req.into_body()).await {
         Ok(b) => Ok(b),
-        Err(_) => Err(Response::builder()
-            .status(StatusCode::BAD_REQUEST)
-            .body(Body::from("Failed to read body"))
-            .unwrap()),
+        Err(_) => Err(ApiError::unreadable_body()),
     }
 }
 
 // Helper: Parse JSON as Value (accepts any fields)
-fn parse_json(body: &[u8]) -> Result<serde_json::Value, Response<Body>> {
+fn parse_json(body: &[u8]) -> Result<serde_json::Value, ApiError> {
     match serde_json::from_slice(body) {
         Ok(v) => Ok(v),
-        Err(_) => Err(Response::builder()
-            .status(StatusCode::BAD_REQUEST)
-            .body(Body::from("Invalid JSON format"))
-            .unwrap()),
+        Err(e) => Err(ApiError::invalid_json(&e)),
     }
 }
 
-// Helper: Forward to external API
-async fn forward_to_external_api(client: &Client, data: &serde_json::Value) -> Result<Response<Body>, Response<Body>> {
-    match client.post("https://postman-echo.com/post")
-        .json(data)
-        .send()
-        .await
-    {
-        Ok(resp) => {
-            match resp.json::<serde_json::Value>().await {
-                Ok(json_resp) => {
-                    let body = match serde_json::to_string_pretty(&json_resp) {
-                        Ok(s) => s,
-                        Err(_) => "Failed to serialize response".to_string(),
-                    };
-                    Ok(Response::new(Body::from(body)))
+// Helper: Copy upstream response headers, skipping hop-by-hop and denied ones
+fn copy_response_headers(from: &HeaderMap, to: &mut HeaderMap, deny: &[HeaderName]) {
+    // Headers named in `Connection` are hop-by-hop for this response only
+    let connection: Vec<String> = from
+        .get_all(hyper::header::CONNECTION)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|t| t.trim().to_ascii_lowercase())
+        .collect();
+    for (name, value) in from {
+        if deny.contains(name) || connection.iter().any(|c| c == name.as_str()) {
+            continue;
+        }
+        to.append(name.clone(), value.clone());
+    }
+}
+
+// Helper: Pretty-print a JSON body, returning it unchanged if it is not JSON
+fn pretty_print_json(body: Bytes) -> Bytes {
+    match serde_json::from_slice::<serde_json::Value>(&body) {
+        Ok(v) => match serde_json::to_vec_pretty(&v) {
+            Ok(pretty) => Bytes::from(pretty),
+            Err(_) => body,
+        },
+        Err(_) => body,
+    }
+}
+
+// Helper: Take a concurrency slot, turning a shed request into a 503
+async fn acquire_slot<'a>(state: &AppState, limiter: Option<&'a concurrency::Limiter>) -> Result<Option<concurrency::Permit<'a>>, ApiError> {
+    let Some(limiter) = limiter else {
+        return Ok(None);
+    };
+    match limiter.acquire().await {
+        Ok(permit) => Ok(Some(permit)),
+        Err(shed) => {
+            state.metrics.shed(&limiter.name, shed.as_str());
+            Err(ApiError::overloaded(&limiter.name, shed.as_str()))
+        }
+    }
+}
+
+// Request to send upstream; rebuilt for every attempt
+#[derive(Clone)]
+struct UpstreamRequest {
+    method: Method,
+    url: Url,
+    // Inbound headers passed on to the upstream
+    headers: HeaderMap,
+    body: Option<serde_json::Value>,
+    // Point by which the whole exchange, retries included, must be done
+    deadline: Instant,
+    // Time allowed up to `deadline`, as reported when it passes
+    budget: Duration,
+    // Server span of the inbound request; each attempt gets a child span
+    trace: SpanContext,
+}
+
+// Helper: The error for a request that ran out of its `budget`
+fn deadline_exceeded(budget: Duration) -> ApiError {
+    ApiError::gateway_timeout(format!("Request did not complete within {}ms", budget.as_millis()))
+        .with_extension("timeout_ms", budget.as_millis() as u64)
+}
+
+// Helper: Turn an upstream reply into the response sent to our client
+async fn relay_response(state: &AppState, route: &RouteConfig, resp: reqwest::Response) -> Result<Response<Body>, ApiError> {
+    let status = resp.status();
+    let headers = resp.headers().clone();
+    let is_json = headers
+        .get(hyper::header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.starts_with("application/json") || v.contains("+json"))
+        .unwrap_or(false);
+    let mut resp = resp;
+    let mut buf = Vec::new();
+    loop {
+        match tokio::time::timeout(route.timeouts.read, resp.chunk()).await {
+            Ok(Ok(Some(chunk))) => buf.extend_from_slice(&chunk),
+            Ok(Ok(None)) => break,
+            Ok(Err(e)) if e.is_timeout() => {
+                state.metrics.upstream_error(&route.upstream, "timeout");
+                return Err(ApiError::gateway_timeout("Upstream response timed out"));
+            }
+            Ok(Err(e)) => {
+                state.metrics.upstream_error(&route.upstream, "decode");
+                return Err(ApiError::bad_gateway(format!("Failed to read API response: {}", e)));
+            }
+            Err(_) => {
+                state.metrics.upstream_error(&route.upstream, "timeout");
+                return Err(ApiError::gateway_timeout("Upstream read timed out"));
+            }
+        }
+    }
+    let body = Bytes::from(buf);
+    let body = if route.pretty && is_json { pretty_print_json(body) } else { body };
+    let mut response = Response::new(Body::from(body));
+    *response.status_mut() = status;
+    copy_response_headers(&headers, response.headers_mut(), &state.config.deny_response_headers);
+    Ok(response)
+}
+
+// Helper: Forward to external API, retrying according to the route policy
+async fn forward_to_external_api(state: &AppState, route: &RouteConfig, upstream: &UpstreamRequest, info: &mut RequestInfo) -> Result<Response<Body>, ApiError> {
+    let policy = &route.retry;
+    let can_retry = policy.allows(&upstream.method, upstream.headers.contains_key(IDEMPOTENCY_KEY));
+    let mut max_attempts = if can_retry { policy.max_attempts } else { 1 };
+    let client = &state.clients[&(route.upstream.clone(), route.timeouts.connect)];
+    let token_source = state.tokens.get(&route.upstream);
+    let mut token_refreshed = false;
+    let mut attempt = 1;
+    let result = loop {
+        // Upstreams with client credentials get a bearer token
+        let token = match token_source {
+            Some(source) => match source.token().await {
+                Ok(t) => Some(t),
+                Err(e) => break Err(ApiError::bad_gateway(format!("Failed to obtain an access token: {}", e))),
+            },
+            None => None,
+        };
+        let remaining = upstream.deadline.saturating_duration_since(Instant::now());
+        let mut span = state.tracer.client_span(upstream.method.to_string(), &upstream.trace);
+        span.set_attribute("http.request.method", upstream.method.as_str());
+        span.set_attribute("url.full", upstream.url.as_str());
+        span.set_attribute("server.address", upstream.url.host_str().unwrap_or_default());
+        if attempt > 1 {
+            span.set_attribute("http.request.resend_count", attempt - 1);
+        }
+        let mut request = client
+            .request(upstream.method.clone(), upstream.url.clone())
+            .headers(upstream.headers.clone())
+            // Let the upstream know how long we are prepared to wait
+            .header(timeouts::X_REQUEST_TIMEOUT, format!("{}ms", remaining.as_millis()))
+            // Make the upstream's spans children of this attempt
+            .header(trace::TRACEPARENT, span.context().traceparent())
+            .timeout(remaining);
+        if let Some(tracestate) = &span.context().tracestate {
+            request = request.header(trace::TRACESTATE, tracestate.as_str());
+        }
+        if let Some(token) = &token {
+            request = request.bearer_auth(token);
+        }
+        if let Some(data) = &upstream.body {
+            request = request.json(data);
+        }
+        // The read timeout bounds the wait for the response headers
+        let started = Instant::now();
+        let sent = match tokio::time::timeout(route.timeouts.read, request.send()).await {
+            Ok(Ok(resp)) => Ok(resp),
+            Ok(Err(e)) => Err((ErrorKind::classify(&e), e.to_string())),
+            Err(_) => Err((ErrorKind::Timeout, "timed out waiting for response headers".to_string())),
+        };
+        state.metrics.upstream_attempt(&route.upstream, &route.path, started.elapsed());
+        info.attempts = attempt;
+        info.upstream_time += started.elapsed();
+        match &sent {
+            Ok(resp) => {
+                span.set_attribute("http.response.status_code", resp.status().as_u16());
+                if resp.status().is_server_error() {
+                    span.set_attribute("error.type", resp.status().as_str());
+                    span.set_error();
+                }
+            }
+            Err((kind, _)) => {
+                state.metrics.upstream_error(&route.upstream, kind.as_str());
+                span.set_attribute("error.type", kind.as_str());
+                span.set_error();
+            }
+        }
+        span.finish(&state.tracer);
+        let (delay, reason) = match sent {
+            // A rejected token is replaced and the call repeated once, on top
+            // of the retry policy
+            Ok(resp) if resp.status() == StatusCode::UNAUTHORIZED && token.is_some() && !token_refreshed => {
+                if let (Some(source), Some(token)) = (token_source, &token) {
+                    source.invalidate(token).await;
+                }
+                token_refreshed = true;
+                max_attempts += 1;
+                (Duration::ZERO, "upstream rejected the access token".to_string())
+            }
+            Ok(resp) => {
+                let status = resp.status();
+                if attempt >= max_attempts || !policy.retries_status(status) {
+                    break relay_response(state, route, resp).await;
+                }
+                match policy.delay(attempt, Some(resp.headers())) {
+                    Some(d) if Instant::now() + d < upstream.deadline => (d, format!("status {}", status.as_u16())),
+                    // Upstream wants us to back off longer than we are willing to wait
+                    _ => break relay_response(state, route, resp).await,
                 }
-                Err(_) => Err(Response::builder()
-                    .status(StatusCode::BAD_GATEWAY)
-                    .body(Body::from("Failed to decode API response"))
-                    .unwrap()),
             }
+            Err((kind, message)) => {
+                let retryable = policy.retries_error(kind) && attempt < max_attempts;
+                match policy.delay(attempt, None) {
+                    Some(d) if retryable && Instant::now() + d < upstream.deadline => (d, message),
+                    _ if kind == ErrorKind::Timeout => break Err(ApiError::gateway_timeout(format!("API request failed: {}", message))),
+                    _ => break Err(ApiError::bad_gateway(format!("API request failed: {}", message))),
+                }
+            }
+        };
+        eprintln!(
+            "Retrying {} {} (attempt {}/{}) in {}ms: {}",
+            upstream.method,
+            state.config.redaction.log.redact_text(upstream.url.as_str()),
+            attempt + 1,
+            max_attempts,
+            delay.as_millis(),
+            reason
+        );
+        tokio::time::sleep(delay).await;
+        attempt += 1;
+    };
+
+    // Tell the caller how many retries were needed
+    let retries = HeaderValue::from(attempt - 1);
+    match result {
+        Ok(mut resp) => {
+            resp.headers_mut().insert(X_RETRY_COUNT, retries);
+            Ok(resp)
         }
-        Err(e) => Err(Response::builder()
-            .status(StatusCode::BAD_GATEWAY)
-            .body(Body::from(format!("API request failed: {}", e)))
-            .unwrap()),
+        Err(mut err) => {
+            err.set_header(HeaderName::from_static(X_RETRY_COUNT), retries);
+            Err(err)
+        }
+    }
+}
+
+async fn handle_request(req: Request<Body>, state: Arc<AppState>, peer: listener::Peer) -> Result<Response<Body>, Infallible> {
+    let _in_flight = shutdown::InFlight::start(&state.in_flight);
+    let started = Instant::now();
+    let method = req.method().clone();
+    let version = req.version();
+    let path = req.uri().path().to_string();
+    let request_id = access_log::inbound_request_id(req.headers()).unwrap_or_else(new_request_id);
+    let mut span = state.tracer.server_span(method.to_string(), req.headers());
+    span.set_attribute("http.request.method", method.as_str());
+    span.set_attribute("url.path", req.uri().path());
+    let mut info = RequestInfo { request_id, ..RequestInfo::default() };
+    let mut response = match route_request(req, &state, &peer, &mut info, span.context()).await {
+        Ok(resp) => resp,
+        Err(err) => err.into_response(&info.request_id),
+    };
+    if let Ok(value) = HeaderValue::from_str(&info.request_id) {
+        response.headers_mut().insert(access_log::X_REQUEST_ID, value);
+    }
+    let elapsed = started.elapsed();
+    state.metrics.request_finished(&info.route, &method, &response, elapsed);
+    let redact = &state.config.redaction.log;
+    state.access_log.write(serde_json::json!({
+        "request_id": info.request_id,
+        "remote_addr": peer.addr.to_string(),
+        "method": method.as_str(),
+        "protocol": format!("{:?}", version),
+        "path": redact.redact_text(&path),
+        "route": info.route,
+        "status": response.status().as_u16(),
+        "duration_ms": elapsed.as_secs_f64() * 1000.0,
+        "upstream_ms": info.upstream.as_ref().map(|_| info.upstream_time.as_secs_f64() * 1000.0),
+        "bytes_in": info.bytes_in,
+        "bytes_out": hyper::body::HttpBody::size_hint(response.body()).exact(),
+        "upstream": info.upstream,
+        "upstream_url": info.upstream_url.as_deref().map(|u| redact.redact_text(u)),
+        "attempts": info.attempts,
+        "trace_id": format!("{:032x}", span.context().trace_id),
+        "body": info.body,
+        "api_key": match &info.principal {
+            Some(auth::Principal::ApiKey { id, .. }) => Some(id.as_str()),
+            _ => None,
+        },
+        "api_key_owner": match &info.principal {
+            Some(auth::Principal::ApiKey { owner: Some(owner), .. }) => Some(redact.redact_text(owner)),
+            _ => None,
+        },
+        "jwt_sub": match &info.principal {
+            Some(auth::Principal::Jwt { subject: Some(sub) }) => Some(redact.redact_text(sub)),
+            _ => None,
+        },
+        "client_cert": peer.client_cert.as_ref().map(|c| redact.redact_text(&c.subject)),
+        "cache": response.headers().get(cache::X_CACHE).and_then(|v| v.to_str().ok()),
+        "coalesced": info.coalesced,
+    }));
+
+    span.set_name(format!("{} {}", method, info.route));
+    span.set_attribute("http.route", info.route.as_str());
+    span.set_attribute("http.response.status_code", response.status().as_u16());
+    if response.status().is_server_error() {
+        span.set_attribute("error.type", response.status().as_str());
+        span.set_error();
     }
+    span.finish(&state.tracer);
+    Ok(response)
 }
 
-async fn handle_request(req: Request<Body>, client: Client) -> Result<Response<Body>, Infallible> {
-    if req.method() == Method::POST && req.uri().path() == "/hello" {
+// Helper: accept an `Upgrade: h2c` request and serve the rest of the
+// connection, starting with that request, over HTTP/2
+fn upgrade_h2c(mut req: Request<Body>, state: Arc<AppState>, peer: listener::Peer) -> Response<Body> {
+    let frames = http2::upgrade_frames(&req);
+    let on_upgrade = hyper::upgrade::on(&mut req);
+    let mut stop = state.detached.track();
+    tokio::spawn(async move {
+        let io = match on_upgrade.await {
+            Ok(io) => io,
+            Err(e) => {
+                eprintln!("h2c upgrade from {} failed: {}", peer.addr, e);
+                return;
+            }
+        };
+        let mut protocol = state.config.http2.protocol(false);
+        protocol.http2_only(true);
+        let addr = peer.addr;
+        let svc = service_fn(move |req| handle_request(req, state.clone(), peer.clone()));
+        let conn = protocol.serve_connection(http2::H2cIo::new(io, frames), svc);
+        tokio::pin!(conn);
+        // Finish the streams already open once shutdown starts, as the
+        // server does for its own connections
+        let result = tokio::select! {
+            res = conn.as_mut() => res,
+            _ = shutdown::stopped(&mut stop) => {
+                conn.as_mut().graceful_shutdown();
+                conn.await
+            }
+        };
+        if let Err(e) = result {
+            eprintln!("HTTP/2 connection from {} failed: {}", addr, e);
+        }
+    });
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = StatusCode::SWITCHING_PROTOCOLS;
+    response.headers_mut().insert(hyper::header::CONNECTION, HeaderValue::from_static("upgrade"));
+    response.headers_mut().insert(hyper::header::UPGRADE, HeaderValue::from_static("h2c"));
+    response
+}
+
+// Dispatch to the internal endpoints or the matching route
+async fn route_request(req: Request<Body>, state: &Arc<AppState>, peer: &listener::Peer, info: &mut RequestInfo, trace: &SpanContext) -> Result<Response<Body>, ApiError> {
+    let path = req.uri().path();
+    match path {
+        health::HEALTHZ_PATH | health::READYZ_PATH | metrics::METRICS_PATH => info.route = path.to_string(),
+        _ if path.starts_with(admin::ADMIN_PREFIX) => info.route = admin::ADMIN_PREFIX.to_string(),
+        _ => info.route = metrics::UNMATCHED_ROUTE.to_string(),
+    }
+    match path {
+        health::HEALTHZ_PATH => return Ok(health::healthz()),
+        health::READYZ_PATH => return Ok(health::readyz(state)),
+        metrics::METRICS_PATH => {
+            let limiters = state.global_limiter.iter().chain(state.route_limiters.values());
+            return Ok(state.metrics.render(state.in_flight.load(Ordering::SeqCst), limiters));
+        }
+        _ => {}
+    }
+    if path.starts_with(admin::ADMIN_PREFIX) {
+        return admin::handle_admin(&req, state);
+    }
+
+    let (route, params) = match state.router.lookup(req.method(), req.uri().path()) {
+        RouteMatch::Found(route, params) => (route, params),
+        RouteMatch::MethodNotAllowed(allowed) => {
+            let allow = allowed.iter().map(Method::as_str).collect::<Vec<_>>().join(", ");
+            return Err(ApiError::method_not_allowed(&allow));
+        }
+        RouteMatch::DotSegment(name) => return Err(ApiError::invalid_path(&name)),
+        RouteMatch::NotFound => return Err(ApiError::not_found()),
+    };
+    info.route = route.path.clone();
+
+    // Reject unauthenticated callers before reading the body
+    if let Some(rule) = &route.client_cert {
+        rule.authorize(peer.client_cert.as_deref())?;
+    }
+    if route.auth {
+        info.principal = Some(auth::authenticate(state, &req, route)?);
+    }
+
+    // Limits are counted after authentication so they can follow the API key
+    let limited = match &route.rate_limit {
+        Some(limit) => Some(
+            state
+                .rate_limiter
+                .check(&route.name(), limit, req.headers(), peer.addr.ip(), info.principal.as_ref())
+                .await
+                .into_result()?,
+        ),
+        None => None,
+    };
+
+    // The route's total timeout, shortened if the caller asked for less
+    let budget = match timeouts::inbound_budget(req.headers()) {
+        Some(b) => b.min(route.timeouts.total),
+        None => route.timeouts.total,
+    };
+    let deadline = Instant::now() + budget;
+    // The upstream call enforces the deadline itself, so its outcome is
+    // recorded; this covers the time spent before and after it
+    let result = match tokio::time::timeout_at(deadline, proxy_request(req, state, route, params, (deadline, budget), trace, info)).await {
+        Ok(result) => result,
+        Err(_) => Err(deadline_exceeded(budget)),
+    };
+
+    // Tell the caller where it stands against the limit
+    let Some(decision) = limited else {
+        return result;
+    };
+    match result {
+        Ok(mut resp) => {
+            resp.headers_mut().extend(decision.headers());
+            Ok(resp)
+        }
+        Err(mut err) => {
+            for (name, value) in decision.headers() {
+                err.set_header(name, value);
+            }
+            Err(err)
+        }
+    }
+}
+
+// Validate the inbound request for a matched route and forward it upstream
+async fn proxy_request(req: Request<Body>, state: &Arc<AppState>, route: &RouteConfig, params: Params, (deadline, budget): (Instant, Duration), trace: &SpanContext, info: &mut RequestInfo) -> Result<Response<Body>, ApiError> {
+    let upstream = &state.config.upstreams[&route.upstream];
+    // An API key sent as a query parameter is not passed on
+    let query = match state.api_keys.as_ref().and_then(|k| k.query_param()) {
+        Some(name) => auth::strip_query_param(req.uri().query(), name),
+        None => req.uri().query().map(str::to_string),
+    };
+    let url = route.upstream_url(&upstream.url, &params, query.as_deref());
+    let method = req.method().clone();
+    // Cache and coalescing keys may use inbound headers, which are gone once
+    // the body is read
+    let inbound = match route.cache.is_some() || route.coalesce.is_some() {
+        true => req.headers().clone(),
+        false => HeaderMap::new(),
+    };
+    let mut headers = HeaderMap::new();
+    if let Some(key) = req.headers().get(IDEMPOTENCY_KEY) {
+        headers.insert(IDEMPOTENCY_KEY, key.clone());
+    }
+    if let Ok(id) = HeaderValue::from_str(&info.request_id) {
+        headers.insert(access_log::X_REQUEST_ID, id);
+    }
+
+    let body = if expects_body(&method) {
         if !is_json_content_type(&req) {
-            return Ok(Response::builder()
-                .status(StatusCode::UNSUPPORTED_MEDIA_TYPE)
-                .body(Body::from("Expected application/json"))
-                .unwrap());
+            return Err(ApiError::unsupported_media_type());
         }
 
         // Read body
-        let body = match read_body(req).await {
-            Ok(b) => b,
-            Err(resp) => return Ok(resp),
-        };
+        let body = read_body(req).await?;
+        state.metrics.request_body(&route.path, body.len());
+        info.bytes_in = body.len();
 
-        // Parse JSON (accept any fields)
-        let data = match parse_json(&body) {
-            Ok(v) => v,
-            Err(resp) => return Ok(resp),
-        };
+        // Parse JSON, then check it against the route schema if there is one
+        let mut data = parse_json(&body)?;
+        if let Some(schema) = &route.schema {
+            schema.validate(&data)?;
+        }
 
-        // Forward to external API
-        match forward_to_external_api(&client, &data).await {
-            Ok(resp) => Ok(resp),
-            Err(resp) => Ok(resp),
+        // Log and upstream redaction are configured separately
+        if state.config.access_log.include_body {
+            let mut logged = data.clone();
+            state.config.redaction.log.redact(&mut logged);
+            info.body = Some(logged);
         }
+        state.config.redaction.upstream.redact(&mut data);
+        Some(data)
     } else {
-        Ok(Response::builder()
-            .status(StatusCode::NOT_FOUND)
-            .body(Body::from("Not Found"))
-            .unwrap())
+        None
+    };
+
+    let upstream = UpstreamRequest { method, url, headers, body, deadline, budget, trace: trace.clone() };
+    // A repeated key replays the first response instead of calling again
+    let claim = match upstream.headers.get(IDEMPOTENCY_KEY) {
+        Some(key) if route.idempotency => {
+            let key = idempotency::Key::new(&route.name(), info.principal.as_ref(), key)?;
+            let fingerprint = idempotency::fingerprint(&upstream.url, upstream.body.as_ref());
+            match state.idempotency.begin(key, fingerprint).await? {
+                idempotency::Begin::Replay(resp) => return Ok(resp),
+                idempotency::Begin::Claimed(claim) => Some(claim),
+            }
+        }
+        _ => None,
+    };
+    let result = cached_call(state, route, upstream, &inbound, info).await;
+    match claim {
+        Some(claim) => claim.finish(result).await,
+        None => result,
+    }
+}
+
+// Helper: Answer from the route's cache if it has one, calling the upstream
+// on a miss
+async fn cached_call(state: &Arc<AppState>, route: &RouteConfig, upstream: UpstreamRequest, inbound: &HeaderMap, info: &mut RequestInfo) -> Result<Response<Body>, ApiError> {
+    let Some(policy) = &route.cache else {
+        return coalesced_call(state, route, &upstream, inbound, info).await;
+    };
+
+    // Serve from the cache when the caller allows it
+    let (use_cached, store) = cache::request_allows(inbound);
+    let key = cache::CacheKey::new(&route.name(), &upstream.url, upstream.body.as_ref(), &policy.vary_headers, inbound);
+    let lookup = if use_cached { state.cache.lookup(&key) } else { cache::Lookup::Miss };
+    let stale = match lookup {
+        cache::Lookup::Fresh(resp) => {
+            state.metrics.cache_lookup(&route.path, "hit");
+            return Ok(resp);
+        }
+        cache::Lookup::Stale { response, revalidate } => {
+            state.metrics.cache_lookup(&route.path, "stale");
+            if revalidate {
+                spawn_revalidation(state.clone(), route.clone(), upstream, key);
+            }
+            return Ok(response);
+        }
+        cache::Lookup::StaleIfError(response) => Some(response),
+        cache::Lookup::Miss => None,
+    };
+    let result = coalesced_call(state, route, &upstream, inbound, info).await;
+    match (result, stale) {
+        (Ok(resp), _) if !resp.status().is_server_error() => {
+            state.metrics.cache_lookup(&route.path, "miss");
+            match store {
+                true => Ok(state.cache.store(key, &route.path, policy, resp).await),
+                false => Ok(resp),
+            }
+        }
+        // The upstream failed; a stale copy is better than an error
+        (_, Some(stale)) => {
+            state.metrics.cache_lookup(&route.path, "stale");
+            Ok(stale)
+        }
+        (result, None) => {
+            state.metrics.cache_lookup(&route.path, "miss");
+            result
+        }
     }
 }
 
+// Helper: Refresh a stale cache entry without holding up the request that
+// found it
+fn spawn_revalidation(state: Arc<AppState>, route: RouteConfig, mut upstream: UpstreamRequest, key: cache::CacheKey) {
+    upstream.deadline = Instant::now() + route.timeouts.total;
+    upstream.budget = route.timeouts.total;
+    tokio::spawn(async move {
+        let mut info = RequestInfo::default();
+        let call = call_upstream(&state, &route, &upstream, &mut info);
+        match tokio::time::timeout_at(upstream.deadline, call).await {
+            Ok(Ok(resp)) if !resp.status().is_server_error() => {
+                if let Some(policy) = &route.cache {
+                    state.cache.store(key, &route.path, policy, resp).await;
+                }
+            }
+            _ => state.cache.finish_revalidation(&key),
+        }
+    });
+}
+
+// Helper: Call the upstream, or wait for an identical call already in
+// progress when the route coalesces requests. The key is the cache key plus
+// `Idempotency-Key`, since requests with different keys are separate
+// operations, and the caller's credentials, so no caller is handed a
+// response made for another.
+async fn coalesced_call(state: &AppState, route: &RouteConfig, upstream: &UpstreamRequest, inbound: &HeaderMap, info: &mut RequestInfo) -> Result<Response<Body>, ApiError> {
+    let Some(policy) = &route.coalesce else {
+        return call_upstream(state, route, upstream, info).await;
+    };
+    let mut vary = route.cache.as_ref().map(|c| c.vary_headers.clone()).unwrap_or_default();
+    vary.push(HeaderName::from_static(IDEMPOTENCY_KEY));
+    vary.push(AUTHORIZATION);
+    let scope = match &info.principal {
+        Some(p) => format!("{} {}", route.name(), p.caller()),
+        None => route.name(),
+    };
+    let key = cache::CacheKey::new(&scope, &upstream.url, upstream.body.as_ref(), &vary, inbound);
+    match state.coalescer.join(key) {
+        coalesce::Join::Leader(flight) => flight.finish(call_upstream(state, route, upstream, info).await).await,
+        coalesce::Join::Follower(waiter) => match waiter.wait(policy.max_wait).await {
+            Some(result) => {
+                state.metrics.coalesced(&route.path);
+                info.coalesced = true;
+                info.upstream = Some(route.upstream.clone());
+                info.upstream_url = Some(upstream.url.to_string());
+                result
+            }
+            // Too slow, or the leading request was cancelled
+            None => call_upstream(state, route, upstream, info).await,
+        },
+    }
+}
+
+// Call the route's upstream once a concurrency slot and the circuit breaker
+// allow it
+async fn call_upstream(state: &AppState, route: &RouteConfig, upstream: &UpstreamRequest, info: &mut RequestInfo) -> Result<Response<Body>, ApiError> {
+    // Wait for a free upstream slot. The route's own limit is taken first so
+    // a request queued on it does not hold a global slot meanwhile.
+    let route_slot = acquire_slot(state, state.route_limiters.get(&route.name())).await?;
+    let global_slot = acquire_slot(state, state.global_limiter.as_ref()).await?;
+
+    // Fail fast while the upstream's circuit breaker is open
+    let permit = state.breakers[&route.upstream]
+        .acquire()
+        .map_err(|wait| ApiError::circuit_open(&route.upstream, wait))?;
+
+    // Forward to external API
+    info.upstream = Some(route.upstream.clone());
+    info.upstream_url = Some(upstream.url.to_string());
+    // Running out of time counts as a failed call, so the breaker and the
+    // limiters learn about an upstream too slow to answer
+    let call = forward_to_external_api(state, route, upstream, info);
+    let result = match tokio::time::timeout_at(upstream.deadline, call).await {
+        Ok(result) => result,
+        Err(_) => Err(deadline_exceeded(upstream.budget)),
+    };
+    let success = matches!(&result, Ok(resp) if !resp.status().is_server_error());
+    permit.record(success);
+    for slot in [route_slot, global_slot].into_iter().flatten() {
+        slot.record(success);
+    }
+    result
+}
+
+// Helper: Shared state for `config`, and the TLS acceptor when `[tls]` is
+// configured
+fn build_state(config: Config) -> Result<(Arc<AppState>, Option<tokio_rustls::TlsAcceptor>), String> {
+    // HTTPS clients (for outgoing requests only), one per upstream and
+    // connect timeout
+    let mut clients = HashMap::new();
+    for route in &config.routes {
+        let connect = route.timeouts.connect;
+        clients.entry((route.upstream.clone(), connect)).or_insert_with(|| {
+            config.upstreams[&route.upstream]
+                .client_builder()
+                .connect_timeout(connect)
+                .build()
+                .expect("Failed to build reqwest client")
+        });
+    }
+
+    let router = Router::new(config.routes.clone());
+    let breakers = config.upstreams.iter()
+        .map(|(name, u)| (name.clone(), CircuitBreaker::new(u.circuit_breaker.clone())))
+        .collect();
+    let probes = health::Probes::new(&config);
+    let tracer = trace::Tracer::new(config.tracing.clone());
+    let access_log = access_log::AccessLog::open(&config.access_log)?;
+    let api_keys = config.api_keys.clone().map(auth::KeyStore::open).transpose()
+        .map_err(|e| format!("api_keys: {}", e))?;
+    let jwt = config.jwt.clone().map(jwt::JwtValidator::new).transpose()
+        .map_err(|e| format!("jwt: {}", e))?;
+    let idempotency = idempotency::IdempotencyStore::open(config.idempotency.clone())
+        .map_err(|e| format!("idempotency: {}", e))?;
+    let rate_limiter = ratelimit::RateLimiter::new(&config.rate_limit);
+    let response_cache = cache::Cache::new(config.cache.clone());
+    let global_limiter = config.concurrency.clone()
+        .map(|settings| concurrency::Limiter::new("global".to_string(), settings));
+    let route_limiters = config.routes.iter()
+        .filter_map(|r| Some((r.name(), concurrency::Limiter::new(r.name(), r.concurrency.clone()?))))
+        .collect();
+    let certs = config.tls.clone().map(tls::Certificates::load).transpose()
+        .map_err(|e| format!("tls: {}", e))?;
+    let acceptor = certs.as_ref().map(|c| c.server_config(config.http2.serves(true))).transpose()
+        .map_err(|e| format!("tls: {}", e))?
+        .map(|s| tokio_rustls::TlsAcceptor::from(Arc::new(s)));
+    let tokens = config.upstreams.iter()
+        .filter_map(|(name, u)| Some((name.clone(), oauth::TokenSource::new(u.oauth2.clone()?))))
+        .collect();
+    let state = Arc::new(AppState {
+        clients,
+        config,
+        router,
+        breakers,
+        ready: AtomicBool::new(true),
+        in_flight: AtomicUsize::new(0),
+        detached: shutdown::Detached::default(),
+        probes,
+        metrics: metrics::Metrics::new(),
+        tracer,
+        access_log,
+        api_keys,
+        jwt,
+        tokens,
+        rate_limiter,
+        global_limiter,
+        route_limiters,
+        tls: certs,
+        cache: response_cache,
+        coalescer: coalesce::Coalescer::default(),
+        idempotency,
+    });
+    Ok((state, acceptor))
+}
+
 #[tokio::main]
 async fn main() {
-    let addr = ([127, 0, 0, 1], 3000).into();
-
-    // HTTPS client (for outgoing requests only)
-    let client = reqwest::Client::builder()
-        .use_rustls_tls()
-        .build()
-        .expect("Failed to build reqwest client");
+    let config = match Config::load() {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("Configuration error: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let addr = config.bind;
+    let (state, acceptor) = match build_state(config) {
+        Ok(built) => built,
+        Err(e) => {
+            eprintln!("Configuration error: {}", e);
+            std::process::exit(1);
+        }
+    };
+    health::spawn_probes(state.clone());
+    auth::spawn_reload(state.clone());
+    jwt::spawn_refresh(state.clone());
+    oauth::spawn_refresh(state.clone());
+    ratelimit::spawn_sweep(state.clone());
+    cache::spawn_sweep(state.clone());
+    idempotency::spawn_sweep(state.clone());
+    tls::spawn_reload(state.clone());
 
-    let make_svc = make_service_fn(move |_| {
-        let client = client.clone();
+    let svc_state = state.clone();
+    let make_svc = make_service_fn(move |conn: &listener::Conn| {
+        let state = svc_state.clone();
+        let peer = conn.peer();
         async move {
             Ok::<_, Infallible>(service_fn(move |req| {
-                handle_request(req, client.clone())
+                let (state, peer) = (state.clone(), peer.clone());
+                async move {
+                    if state.tls.is_none() && state.config.http2.serves(false) && http2::wants_h2c(&req) {
+                        return Ok(upgrade_h2c(req, state, peer));
+                    }
+                    handle_request(req, state, peer).await
+                }
             }))
         }
     });
-    println!("Listening on http://{}", addr);
+    let tcp = match tokio::net::TcpListener::bind(addr).await {
+        Ok(l) => l,
+        Err(e) => {
+            eprintln!("Failed to bind {}: {}", addr, e);
+            std::process::exit(1);
+        }
+    };
+    let scheme = if acceptor.is_some() { "https" } else { "http" };
+    let incoming = listener::spawn_accept(tcp, acceptor);
+    println!("Listening on {}://{}", scheme, addr);
 
-    let server = Server::bind(&addr).serve(make_svc);
+    let (stop_tx, stop_rx) = tokio::sync::oneshot::channel::<()>();
+    let protocol = state.config.http2.protocol(state.tls.is_some());
+    let server = hyper::server::Builder::new(incoming, protocol)
+        .serve(make_svc)
+        .with_graceful_shutdown(async {
+            stop_rx.await.ok();
+        });
+    let mut server = tokio::spawn(server);
 
-    if let Err(e) = server.await {
-        eprintln!("Server error: {}", e);
+    let signal = tokio::select! {
+        res = &mut server => {
+            if let Ok(Err(e)) = res {
+                eprintln!("Server error: {}", e);
+            }
+            return;
+        }
+        signal = shutdown::signal() => signal,
+    };
+
+    // Fail readiness first so load balancers stop sending traffic, then stop
+    // accepting connections and give in-flight requests time to finish
+    let drain = &state.config.shutdown;
+    println!("Received {}, shutting down", signal);
+    state.ready.store(false, Ordering::SeqCst);
+    tokio::time::sleep(drain.readiness_delay).await;
+    let pending = state.in_flight.load(Ordering::SeqCst);
+    stop_tx.send(()).ok();
+    state.detached.stop();
+
+    // Upgraded connections are drained alongside the server's own
+    let drained = async {
+        let res = (&mut server).await;
+        state.detached.closed().await;
+        res
+    };
+    match tokio::time::timeout(drain.drain_timeout, drained).await {
+        Ok(res) => {
+            if let Ok(Err(e)) = res {
+                eprintln!("Server error: {}", e);
+            }
+            println!("Shutdown complete: drained {} in-flight request(s), aborted 0", pending);
+        }
+        Err(_) => {
+            let aborted = state.in_flight.load(Ordering::SeqCst);
+            server.abort();
+            println!(
+                "Shutdown drain timed out after {}ms: drained {} in-flight request(s), aborted {}",
+                drain.drain_timeout.as_millis(),
+                pending.saturating_sub(aborted),
+                aborted
+            );
+        }
+    }
+    // Send spans still waiting for the next export
+    state.tracer.flush().await;
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::future::{ready, Future};
+    use std::net::SocketAddr;
+
+    // Local HTTP server answering every request with `handler`
+    fn serve<F, R>(handler: F) -> SocketAddr
+    where
+        F: Fn(Request<Body>) -> R + Clone + Send + Sync + 'static,
+        R: Future<Output = Response<Body>> + Send + 'static,
+    {
+        let make_svc = make_service_fn(move |_| {
+            let handler = handler.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req| {
+                    let response = handler(req);
+                    async move { Ok::<_, Infallible>(response.await) }
+                }))
+            }
+        });
+        let server = hyper::Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_svc);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        addr
+    }
+
+    fn state(toml: &str) -> Arc<AppState> {
+        let config = Config::from_toml(toml).unwrap();
+        build_state(config).unwrap().0
     }
-}
\ No newline at end of file
+
+    async fn send(state: &Arc<AppState>, req: Request<Body>) -> Response<Body> {
+        let peer = listener::Peer { addr: ([127, 0, 0, 1], 40000).into(), client_cert: None };
+        handle_request(req, state.clone(), peer).await.unwrap()
+    }
+
+    fn get(path: &str) -> Request<Body> {
+        Request::get(path).body(Body::empty()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn rejected_token_is_replaced_once() {
+        let issued = Arc::new(AtomicUsize::new(0));
+        let counter = issued.clone();
+        let token_server = serve(move |_| {
+            let n = counter.fetch_add(1, Ordering::SeqCst) + 1;
+            ready(Response::new(Body::from(format!(r#"{{"access_token":"t{}","expires_in":3600}}"#, n))))
+        });
+        // The upstream only accepts the second token
+        let calls = Arc::new(AtomicUsize::new(0));
+        let seen = calls.clone();
+        let upstream = serve(move |req| {
+            seen.fetch_add(1, Ordering::SeqCst);
+            let mut response = Response::new(Body::from("{}"));
+            if req.headers().get("authorization").is_none_or(|v| v != "Bearer t2") {
+                *response.status_mut() = StatusCode::UNAUTHORIZED;
+            }
+            ready(response)
+        });
+        let state = state(&format!(
+            r#"
+            upstream = "http://{upstream}"
+            [access_log]
+            enabled = false
+            [[routes]]
+            method = "GET"
+            path = "/get"
+            upstream = "api"
+            [upstreams.api]
+            url = "http://{upstream}"
+            oauth2 = {{ token_url = "http://{token_server}/token", client_id = "rust_api", client_secret = "s3cret" }}
+            "#
+        ));
+
+        let response = send(&state, get("/get")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(issued.load(Ordering::SeqCst), 2);
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        // The replacement is kept for later requests
+        let response = send(&state, get("/get")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(issued.load(Ordering::SeqCst), 2);
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn token_is_refreshed_only_once_per_request() {
+        let issued = Arc::new(AtomicUsize::new(0));
+        let counter = issued.clone();
+        let token_server = serve(move |_| {
+            let n = counter.fetch_add(1, Ordering::SeqCst) + 1;
+            ready(Response::new(Body::from(format!(r#"{{"access_token":"t{}"}}"#, n))))
+        });
+        let upstream = serve(|_| {
+            let mut response = Response::new(Body::empty());
+            *response.status_mut() = StatusCode::UNAUTHORIZED;
+            ready(response)
+        });
+        let state = state(&format!(
+            r#"
+            upstream = "http://{upstream}"
+            [access_log]
+            enabled = false
+            [[routes]]
+            method = "GET"
+            path = "/get"
+            upstream = "api"
+            [upstreams.api]
+            url = "http://{upstream}"
+            oauth2 = {{ token_url = "http://{token_server}/token", client_id = "rust_api", client_secret = "s3cret" }}
+            "#
+        ));
+        let response = send(&state, get("/get")).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(issued.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn deadline_counts_against_the_breaker() {
+        // The deadline passes while the upstream call waits on its token
+        let token_server = serve(|_| async {
+            tokio::time::sleep(Duration::from_secs(2)).await;
+            Response::new(Body::from(r#"{"access_token":"t1"}"#))
+        });
+        let upstream = serve(|_| ready(Response::new(Body::from("{}"))));
+        let state = state(&format!(
+            r#"
+            upstream = "http://{upstream}"
+            [access_log]
+            enabled = false
+            [[routes]]
+            method = "GET"
+            path = "/slow"
+            upstream = "api"
+            timeouts = {{ total_ms = 200 }}
+            [upstreams.api]
+            url = "http://{upstream}"
+            circuit_breaker = {{ minimum_requests = 1 }}
+            oauth2 = {{ token_url = "http://{token_server}/token", client_id = "rust_api", client_secret = "s3cret" }}
+            "#
+        ));
+
+        let response = send(&state, get("/slow")).await;
+        assert_eq!(response.status(), StatusCode::GATEWAY_TIMEOUT);
+        let snapshot = state.breakers["api"].snapshot();
+        assert_eq!(snapshot["window_failures"], 1);
+        assert_eq!(snapshot["state"], "open");
+
+        let response = send(&state, get("/slow")).await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[tokio::test]
+    async fn identical_requests_share_one_upstream_call() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let seen = calls.clone();
+        let upstream = serve(move |req: Request<Body>| {
+            let n = seen.fetch_add(1, Ordering::SeqCst) + 1;
+            async move {
+                let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+                tokio::time::sleep(Duration::from_millis(200)).await;
+                Response::new(Body::from(format!(r#"{{"call":{},"echo":{}}}"#, n, String::from_utf8_lossy(&body))))
+            }
+        });
+        let state = state(&format!(
+            r#"
+            upstream = "http://{upstream}"
+            [access_log]
+            enabled = false
+            [[routes]]
+            method = "POST"
+            path = "/items"
+            coalesce = {{ max_wait_ms = 5000 }}
+            "#
+        ));
+        let post = |body: &str, authorization: &str| {
+            let req = Request::post("/items")
+                .header("content-type", "application/json")
+                .header("authorization", authorization)
+                .body(Body::from(body.to_string()))
+                .unwrap();
+            let state = state.clone();
+            async move {
+                let response = send(&state, req).await;
+                assert_eq!(response.status(), StatusCode::OK);
+                hyper::body::to_bytes(response.into_body()).await.unwrap()
+            }
+        };
+
+        // Key order does not make requests different
+        let (a, b, c) = tokio::join!(
+            post(r#"{"x":1,"y":2}"#, "Bearer a"),
+            post(r#"{"y":2,"x":1}"#, "Bearer a"),
+            post(r#"{"x":1,"y":2}"#, "Bearer a")
+        );
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert_eq!(a, b);
+        assert_eq!(a, c);
+
+        // Another body or other credentials make their own calls
+        let (a, b, c) = tokio::join!(
+            post(r#"{"x":1}"#, "Bearer a"),
+            post(r#"{"x":2}"#, "Bearer a"),
+            post(r#"{"x":1}"#, "Bearer b")
+        );
+        assert_eq!(calls.load(Ordering::SeqCst), 4);
+        assert!(a != b && a != c && b != c);
+    }
+
+    #[tokio::test]
+    async fn bodies_are_checked_before_forwarding() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let seen = calls.clone();
+        let upstream = serve(move |_| {
+            seen.fetch_add(1, Ordering::SeqCst);
+            ready(Response::new(Body::from("{}")))
+        });
+        let schema = std::env::temp_dir().join(format!("rust_api-{}-name.json", std::process::id()));
+        std::fs::write(&schema, r#"{"type":"object","required":["name"]}"#).unwrap();
+        let state = state(&format!(
+            r#"
+            upstream = "http://{upstream}"
+            [access_log]
+            enabled = false
+            [[routes]]
+            method = "POST"
+            path = "/items"
+            schema = "{}"
+            "#,
+            schema.display()
+        ));
+        std::fs::remove_file(&schema).ok();
+        let post = |body: &str| {
+            let req = Request::post("/items")
+                .header("content-type", "application/json")
+                .header("x-request-id", "req-1")
+                .body(Body::from(body.to_string()))
+                .unwrap();
+            let state = state.clone();
+            async move {
+                let response = send(&state, req).await;
+                let status = response.status();
+                assert_eq!(response.headers()["content-type"], errors::PROBLEM_CONTENT_TYPE);
+                let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+                (status, serde_json::from_slice::<serde_json::Value>(&body).unwrap())
+            }
+        };
+
+        let (status, problem) = post(r#"{"name": "#).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(problem["type"], "/problems/invalid-json");
+        assert_eq!(problem["request_id"], "req-1");
+        assert_eq!(problem["line"], 1);
+
+        let (status, problem) = post(r#"{"count": 1}"#).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(problem["type"], "/problems/schema-violation");
+        assert_eq!(problem["errors"][0]["keyword"], "required");
+        assert_eq!(calls.load(Ordering::SeqCst), 0);
+    }
+}
@@ -0,0 +1,253 @@
+use crate::concurrency::Limiter;
+use hyper::{Body, Method, Response, StatusCode};
+use prometheus::{
+    exponential_buckets, Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, IntGaugeVec, Opts,
+    Registry, TextEncoder,
+};
+use std::time::Duration;
+
+pub const METRICS_PATH: &str = "/metrics";
+
+// Route label for requests that matched no route
+pub const UNMATCHED_ROUTE: &str = "unmatched";
+
+// Prometheus collectors for inbound traffic and upstream calls
+pub struct Metrics {
+    registry: Registry,
+    requests: IntCounterVec,
+    request_duration: HistogramVec,
+    in_flight: IntGauge,
+    request_bytes: HistogramVec,
+    response_bytes: HistogramVec,
+    upstream_duration: HistogramVec,
+    upstream_errors: IntCounterVec,
+    concurrency_limit: IntGaugeVec,
+    concurrency_in_flight: IntGaugeVec,
+    concurrency_queue_depth: IntGaugeVec,
+    concurrency_shed: IntCounterVec,
+    cache_lookups: IntCounterVec,
+    coalesced: IntCounterVec,
+}
+
+fn bytes_buckets() -> Vec<f64> {
+    // 64 B .. 16 MiB
+    exponential_buckets(64.0, 4.0, 10).expect("valid buckets")
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        let registry = Registry::new();
+        let requests = IntCounterVec::new(
+            Opts::new("http_requests_total", "Inbound requests by route, method and status"),
+            &["route", "method", "status"],
+        )
+        .unwrap();
+        let request_duration = HistogramVec::new(
+            HistogramOpts::new("http_request_duration_seconds", "Time to handle inbound requests"),
+            &["route", "method"],
+        )
+        .unwrap();
+        let in_flight = IntGauge::new("http_requests_in_flight", "Inbound requests being handled").unwrap();
+        let request_bytes = HistogramVec::new(
+            HistogramOpts::new("http_request_body_bytes", "Size of inbound request bodies")
+                .buckets(bytes_buckets()),
+            &["route"],
+        )
+        .unwrap();
+        let response_bytes = HistogramVec::new(
+            HistogramOpts::new("http_response_body_bytes", "Size of response bodies sent to clients")
+                .buckets(bytes_buckets()),
+            &["route"],
+        )
+        .unwrap();
+        let upstream_duration = HistogramVec::new(
+            HistogramOpts::new("upstream_request_duration_seconds", "Duration of each upstream attempt"),
+            &["upstream", "route"],
+        )
+        .unwrap();
+        let upstream_errors = IntCounterVec::new(
+            Opts::new("upstream_errors_total", "Failed upstream attempts by failure kind"),
+            &["upstream", "kind"],
+        )
+        .unwrap();
+        let concurrency_limit = IntGaugeVec::new(
+            Opts::new("concurrency_limit", "Current limit on concurrent upstream calls"),
+            &["limiter"],
+        )
+        .unwrap();
+        let concurrency_in_flight = IntGaugeVec::new(
+            Opts::new("concurrency_in_flight", "Upstream calls holding a concurrency slot"),
+            &["limiter"],
+        )
+        .unwrap();
+        let concurrency_queue_depth = IntGaugeVec::new(
+            Opts::new("concurrency_queue_depth", "Requests waiting for a concurrency slot"),
+            &["limiter"],
+        )
+        .unwrap();
+        let concurrency_shed = IntCounterVec::new(
+            Opts::new("concurrency_shed_total", "Requests rejected by a concurrency limiter"),
+            &["limiter", "reason"],
+        )
+        .unwrap();
+        let cache_lookups = IntCounterVec::new(
+            Opts::new("cache_lookups_total", "Requests on cached routes by outcome"),
+            &["route", "result"],
+        )
+        .unwrap();
+        let coalesced = IntCounterVec::new(
+            Opts::new("coalesced_requests_total", "Requests answered by an upstream call made for an identical request"),
+            &["route"],
+        )
+        .unwrap();
+
+        registry.register(Box::new(requests.clone())).unwrap();
+        registry.register(Box::new(request_duration.clone())).unwrap();
+        registry.register(Box::new(in_flight.clone())).unwrap();
+        registry.register(Box::new(request_bytes.clone())).unwrap();
+        registry.register(Box::new(response_bytes.clone())).unwrap();
+        registry.register(Box::new(upstream_duration.clone())).unwrap();
+        registry.register(Box::new(upstream_errors.clone())).unwrap();
+        registry.register(Box::new(concurrency_limit.clone())).unwrap();
+        registry.register(Box::new(concurrency_in_flight.clone())).unwrap();
+        registry.register(Box::new(concurrency_queue_depth.clone())).unwrap();
+        registry.register(Box::new(concurrency_shed.clone())).unwrap();
+        registry.register(Box::new(cache_lookups.clone())).unwrap();
+        registry.register(Box::new(coalesced.clone())).unwrap();
+
+        Metrics {
+            registry,
+            requests,
+            request_duration,
+            in_flight,
+            request_bytes,
+            response_bytes,
+            upstream_duration,
+            upstream_errors,
+            concurrency_limit,
+            concurrency_in_flight,
+            concurrency_queue_depth,
+            concurrency_shed,
+            cache_lookups,
+            coalesced,
+        }
+    }
+
+    // Record a finished inbound request
+    pub fn request_finished(&self, route: &str, method: &Method, response: &Response<Body>, elapsed: Duration) {
+        let status = response.status();
+        self.requests
+            .with_label_values(&[route, method.as_str(), status.as_str()])
+            .inc();
+        self.request_duration
+            .with_label_values(&[route, method.as_str()])
+            .observe(elapsed.as_secs_f64());
+        if let Some(len) = hyper::body::HttpBody::size_hint(response.body()).exact() {
+            self.response_bytes.with_label_values(&[route]).observe(len as f64);
+        }
+    }
+
+    pub fn request_body(&self, route: &str, len: usize) {
+        self.request_bytes.with_label_values(&[route]).observe(len as f64);
+    }
+
+    pub fn upstream_attempt(&self, upstream: &str, route: &str, elapsed: Duration) {
+        self.upstream_duration
+            .with_label_values(&[upstream, route])
+            .observe(elapsed.as_secs_f64());
+    }
+
+    // `kind` is one of `connect`, `timeout`, `decode` or `other`
+    pub fn upstream_error(&self, upstream: &str, kind: &str) {
+        self.upstream_errors.with_label_values(&[upstream, kind]).inc();
+    }
+
+    // `reason` is `queue_full` or `queue_timeout`
+    pub fn shed(&self, limiter: &str, reason: &str) {
+        self.concurrency_shed.with_label_values(&[limiter, reason]).inc();
+    }
+
+    // `result` is `hit`, `stale` or `miss`
+    pub fn cache_lookup(&self, route: &str, result: &str) {
+        self.cache_lookups.with_label_values(&[route, result]).inc();
+    }
+
+    pub fn coalesced(&self, route: &str) {
+        self.coalesced.with_label_values(&[route]).inc();
+    }
+
+    // Text exposition format for `GET /metrics`. The in-flight count and the
+    // concurrency limiters are sampled here from their own state, which also
+    // covers requests cancelled by a client disconnect.
+    pub fn render<'a>(&self, in_flight: usize, limiters: impl Iterator<Item = &'a Limiter>) -> Response<Body> {
+        self.in_flight.set(in_flight as i64);
+        for limiter in limiters {
+            let s = limiter.snapshot();
+            let labels = [limiter.name.as_str()];
+            self.concurrency_limit.with_label_values(&labels).set(i64::from(s.limit));
+            self.concurrency_in_flight.with_label_values(&labels).set(i64::from(s.in_flight));
+            self.concurrency_queue_depth.with_label_values(&labels).set(s.queued as i64);
+        }
+        let encoder = TextEncoder::new();
+        let mut buf = Vec::new();
+        match encoder.encode(&self.registry.gather(), &mut buf) {
+            Ok(()) => Response::builder()
+                .header(hyper::header::CONTENT_TYPE, encoder.format_type())
+                .body(Body::from(buf))
+                .unwrap(),
+            Err(e) => Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .body(Body::from(format!("Failed to encode metrics: {}", e)))
+                .unwrap(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::concurrency::ConcurrencySettings;
+
+    async fn exposition(metrics: &Metrics, in_flight: usize, limiters: &[&Limiter]) -> String {
+        let response = metrics.render(in_flight, limiters.iter().copied());
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(response.headers()[hyper::header::CONTENT_TYPE].to_str().unwrap().starts_with("text/plain"));
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        String::from_utf8(body.to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn render_exposes_requests_and_limiters() {
+        let metrics = Metrics::new();
+        let ok = Response::new(Body::from("{}"));
+        let mut missing = Response::new(Body::empty());
+        *missing.status_mut() = StatusCode::NOT_FOUND;
+        metrics.request_finished("/items", &Method::GET, &ok, Duration::from_millis(5));
+        metrics.request_finished("/items", &Method::GET, &ok, Duration::from_millis(5));
+        metrics.request_finished(UNMATCHED_ROUTE, &Method::POST, &missing, Duration::from_millis(1));
+        metrics.shed("global", "queue_full");
+
+        let global = Limiter::new("global".to_string(), ConcurrencySettings::default());
+        let permit = global.acquire().await.unwrap();
+        let text = exposition(&metrics, 3, &[&global]).await;
+        for line in [
+            r#"http_requests_total{method="GET",route="/items",status="200"} 2"#,
+            r#"http_requests_total{method="POST",route="unmatched",status="404"} 1"#,
+            r#"http_request_duration_seconds_count{method="GET",route="/items"} 2"#,
+            r#"http_response_body_bytes_sum{route="/items"} 4"#,
+            "http_requests_in_flight 3",
+            r#"concurrency_limit{limiter="global"} 100"#,
+            r#"concurrency_in_flight{limiter="global"} 1"#,
+            r#"concurrency_queue_depth{limiter="global"} 0"#,
+            r#"concurrency_shed_total{limiter="global",reason="queue_full"} 1"#,
+        ] {
+            assert!(text.lines().any(|l| l == line), "missing {}\n{}", line, text);
+        }
+
+        // Gauges are sampled again on every scrape
+        drop(permit);
+        let text = exposition(&metrics, 0, &[&global]).await;
+        assert!(text.lines().any(|l| l == r#"concurrency_in_flight{limiter="global"} 0"#));
+        assert!(text.lines().any(|l| l == "http_requests_in_flight 0"));
+    }
+}
@@ -0,0 +1,336 @@
+use crate::AppState;
+use reqwest::Url;
+use serde::Deserialize;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tokio::time::Instant;
+
+// Lifetime assumed when the token endpoint omits `expires_in`
+const DEFAULT_TOKEN_LIFETIME: Duration = Duration::from_secs(3600);
+// Longer `expires_in` values are not trusted, so a bogus one cannot overflow
+const MAX_TOKEN_LIFETIME: Duration = Duration::from_secs(24 * 60 * 60);
+// Shorter ones (including 0) are raised to this, so neither the refresh task
+// nor request handling fetches a new token in a tight loop
+const MIN_TOKEN_LIFETIME: Duration = Duration::from_secs(5);
+// Backoff bounds for the background refresh after a failed fetch
+const MIN_RETRY: Duration = Duration::from_secs(1);
+const MAX_RETRY: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FileOAuth2 {
+    token_url: String,
+    client_id: String,
+    // The secret itself, or the environment variable holding it
+    client_secret: Option<String>,
+    client_secret_env: Option<String>,
+    #[serde(default)]
+    scopes: Vec<String>,
+    audience: Option<String>,
+    // `basic` (HTTP Basic, the default) or `body` (form fields)
+    auth_style: Option<String>,
+    refresh_before_secs: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthStyle {
+    Basic,
+    Body,
+}
+
+#[derive(Clone)]
+pub struct OAuth2Config {
+    pub token_url: Url,
+    pub client_id: String,
+    client_secret: String,
+    pub scopes: Vec<String>,
+    pub audience: Option<String>,
+    pub auth_style: AuthStyle,
+    // Fetch a new token this long before the current one expires
+    pub refresh_before: Duration,
+}
+
+// Keep the client secret out of debug output
+impl std::fmt::Debug for OAuth2Config {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("OAuth2Config")
+            .field("token_url", &self.token_url)
+            .field("client_id", &self.client_id)
+            .field("scopes", &self.scopes)
+            .field("audience", &self.audience)
+            .field("auth_style", &self.auth_style)
+            .field("refresh_before", &self.refresh_before)
+            .finish_non_exhaustive()
+    }
+}
+
+impl OAuth2Config {
+    pub fn from_file(file: &FileOAuth2) -> Result<OAuth2Config, String> {
+        let token_url = Url::parse(&file.token_url)
+            .map_err(|e| format!("invalid token_url '{}': {}", file.token_url, e))?;
+        let client_secret = match (&file.client_secret, &file.client_secret_env) {
+            (Some(s), None) => s.clone(),
+            (None, Some(var)) => std::env::var(var).map_err(|_| format!("environment variable {} is not set", var))?,
+            _ => return Err("exactly one of `client_secret` and `client_secret_env` must be set".to_string()),
+        };
+        let auth_style = match file.auth_style.as_deref() {
+            None | Some("basic") => AuthStyle::Basic,
+            Some("body") => AuthStyle::Body,
+            Some(other) => return Err(format!("unknown auth_style '{}' (expected basic or body)", other)),
+        };
+        Ok(OAuth2Config {
+            token_url,
+            client_id: file.client_id.clone(),
+            client_secret,
+            scopes: file.scopes.clone(),
+            audience: file.audience.clone(),
+            auth_style,
+            refresh_before: Duration::from_secs(file.refresh_before_secs.unwrap_or(60)),
+        })
+    }
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    expires_in: Option<u64>,
+}
+
+struct CachedToken {
+    value: String,
+    expires_at: Instant,
+    // When the background task replaces it
+    refresh_at: Instant,
+}
+
+impl CachedToken {
+    fn fresh(&self) -> bool {
+        Instant::now() < self.expires_at
+    }
+}
+
+// Client-credentials access token for one upstream, fetched on demand and
+// refreshed in the background ahead of expiry
+pub struct TokenSource {
+    config: OAuth2Config,
+    client: reqwest::Client,
+    // Held across a fetch so concurrent callers share one token request
+    cached: Mutex<Option<CachedToken>>,
+}
+
+impl TokenSource {
+    pub fn new(config: OAuth2Config) -> TokenSource {
+        let client = reqwest::Client::builder()
+            .use_rustls_tls()
+            .timeout(Duration::from_secs(10))
+            .build()
+            .expect("Failed to build reqwest client");
+        TokenSource { config, client, cached: Mutex::new(None) }
+    }
+
+    // A token that has not expired, fetching one if needed
+    pub async fn token(&self) -> Result<String, String> {
+        let mut cached = self.cached.lock().await;
+        if let Some(t) = cached.as_ref().filter(|t| t.fresh()) {
+            return Ok(t.value.clone());
+        }
+        let token = self.fetch().await?;
+        let value = token.value.clone();
+        *cached = Some(token);
+        Ok(value)
+    }
+
+    // Drop `rejected` so the next call fetches a new token, unless another
+    // request already replaced it
+    pub async fn invalidate(&self, rejected: &str) {
+        let mut cached = self.cached.lock().await;
+        if cached.as_ref().is_some_and(|t| t.value == rejected) {
+            *cached = None;
+        }
+    }
+
+    async fn fetch(&self) -> Result<CachedToken, String> {
+        let c = &self.config;
+        let mut form = vec![("grant_type", "client_credentials".to_string())];
+        if !c.scopes.is_empty() {
+            form.push(("scope", c.scopes.join(" ")));
+        }
+        if let Some(aud) = &c.audience {
+            form.push(("audience", aud.clone()));
+        }
+        let mut request = self.client.post(c.token_url.clone());
+        match c.auth_style {
+            AuthStyle::Basic => request = request.basic_auth(&c.client_id, Some(&c.client_secret)),
+            AuthStyle::Body => {
+                form.push(("client_id", c.client_id.clone()));
+                form.push(("client_secret", c.client_secret.clone()));
+            }
+        }
+        let requested_at = Instant::now();
+        let resp = request
+            .form(&form)
+            .send()
+            .await
+            .map_err(|e| format!("token request to {} failed: {}", c.token_url, e))?;
+        if !resp.status().is_success() {
+            return Err(format!("token endpoint {} returned {}", c.token_url, resp.status()));
+        }
+        let body: TokenResponse = resp
+            .json()
+            .await
+            .map_err(|e| format!("invalid token response from {}: {}", c.token_url, e))?;
+        let lifetime = body
+            .expires_in
+            .map(Duration::from_secs)
+            .unwrap_or(DEFAULT_TOKEN_LIFETIME)
+            .clamp(MIN_TOKEN_LIFETIME, MAX_TOKEN_LIFETIME);
+        // Short-lived tokens are renewed at half-life rather than immediately
+        let refresh_in = lifetime.saturating_sub(c.refresh_before).max(lifetime / 2);
+        // A time that cannot be represented leaves the token already due
+        Ok(CachedToken {
+            value: body.access_token,
+            expires_at: requested_at.checked_add(lifetime).unwrap_or(requested_at),
+            refresh_at: requested_at.checked_add(refresh_in).unwrap_or(requested_at),
+        })
+    }
+
+    // Renew the token `refresh_before` its expiry, so requests rarely wait
+    async fn refresh_loop(&self, upstream: &str) {
+        let mut backoff = MIN_RETRY;
+        loop {
+            let wake = self.cached.lock().await.as_ref().map(|t| t.refresh_at);
+            if let Some(at) = wake {
+                tokio::time::sleep_until(at).await;
+            }
+            match self.fetch().await {
+                Ok(token) => {
+                    *self.cached.lock().await = Some(token);
+                    backoff = MIN_RETRY;
+                }
+                Err(e) => {
+                    eprintln!("Failed to refresh access token for upstream '{}': {}", upstream, e);
+                    tokio::time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(MAX_RETRY);
+                }
+            }
+        }
+    }
+}
+
+// One refresh task per upstream that uses client credentials
+pub fn spawn_refresh(state: Arc<AppState>) {
+    for name in state.tokens.keys() {
+        let (state, name) = (state.clone(), name.clone());
+        tokio::spawn(async move {
+            state.tokens[&name].refresh_loop(&name).await;
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Request, Response};
+    use std::convert::Infallible;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    // Token endpoint issuing `t1`, `t2`, ... with the given `expires_in`,
+    // recording the form fields of the last request
+    async fn token_server(expires_in: u64) -> (Url, Arc<AtomicUsize>, Arc<std::sync::Mutex<String>>) {
+        let issued = Arc::new(AtomicUsize::new(0));
+        let last_form = Arc::new(std::sync::Mutex::new(String::new()));
+        let (count, form) = (issued.clone(), last_form.clone());
+        let make_svc = make_service_fn(move |_| {
+            let (count, form) = (count.clone(), form.clone());
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                    let (count, form) = (count.clone(), form.clone());
+                    async move {
+                        let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+                        *form.lock().unwrap() = String::from_utf8_lossy(&body).into_owned();
+                        let n = count.fetch_add(1, Ordering::SeqCst) + 1;
+                        let token = format!(r#"{{"access_token":"t{}","token_type":"Bearer","expires_in":{}}}"#, n, expires_in);
+                        Ok::<_, Infallible>(Response::new(Body::from(token)))
+                    }
+                }))
+            }
+        });
+        let server = hyper::Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_svc);
+        let url = Url::parse(&format!("http://{}/token", server.local_addr())).unwrap();
+        tokio::spawn(server);
+        (url, issued, last_form)
+    }
+
+    fn source(token_url: Url, auth_style: AuthStyle) -> TokenSource {
+        TokenSource::new(OAuth2Config {
+            token_url,
+            client_id: "rust_api".to_string(),
+            client_secret: "s3cret".to_string(),
+            scopes: vec!["read".to_string(), "write".to_string()],
+            audience: None,
+            auth_style,
+            refresh_before: Duration::from_secs(60),
+        })
+    }
+
+    #[tokio::test]
+    async fn token_is_cached_until_rejected() {
+        let (url, issued, _) = token_server(3600).await;
+        let tokens = source(url, AuthStyle::Basic);
+        assert_eq!(tokens.token().await.unwrap(), "t1");
+        assert_eq!(tokens.token().await.unwrap(), "t1");
+        assert_eq!(issued.load(Ordering::SeqCst), 1);
+
+        // The upstream answered 401 with t1: the next call fetches t2
+        tokens.invalidate("t1").await;
+        assert_eq!(tokens.token().await.unwrap(), "t2");
+        // A late rejection of t1 does not throw away its replacement
+        tokens.invalidate("t1").await;
+        assert_eq!(tokens.token().await.unwrap(), "t2");
+        assert_eq!(issued.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn credentials_in_body() {
+        let (url, _, form) = token_server(3600).await;
+        source(url, AuthStyle::Body).token().await.unwrap();
+        let form = form.lock().unwrap().clone();
+        assert!(form.contains("grant_type=client_credentials"), "{}", form);
+        assert!(form.contains("scope=read+write"), "{}", form);
+        assert!(form.contains("client_secret=s3cret"), "{}", form);
+    }
+
+    #[tokio::test]
+    async fn huge_expires_in_is_clamped() {
+        let (url, _, _) = token_server(u64::MAX).await;
+        let tokens = source(url, AuthStyle::Basic);
+        let started = Instant::now();
+        let token = tokens.fetch().await.unwrap();
+        assert!(token.expires_at <= started + MAX_TOKEN_LIFETIME + Duration::from_secs(1));
+        assert!(token.fresh());
+    }
+
+    #[tokio::test]
+    async fn short_lifetimes_refresh_at_half_life() {
+        let (url, _, _) = token_server(10).await;
+        let token = source(url, AuthStyle::Basic).fetch().await.unwrap();
+        let until_refresh = token.refresh_at - Instant::now();
+        assert!(until_refresh > Duration::from_secs(4) && until_refresh <= Duration::from_secs(5));
+    }
+
+    #[tokio::test]
+    async fn zero_lifetime_is_raised_to_the_minimum() {
+        let (url, issued, _) = token_server(0).await;
+        let tokens = source(url, AuthStyle::Basic);
+        assert_eq!(tokens.token().await.unwrap(), "t1");
+        assert_eq!(tokens.token().await.unwrap(), "t1");
+        assert_eq!(issued.load(Ordering::SeqCst), 1);
+
+        let token = tokens.fetch().await.unwrap();
+        let now = Instant::now();
+        assert!(token.expires_at > now + MIN_TOKEN_LIFETIME - Duration::from_secs(1));
+        assert!(token.refresh_at > now + MIN_TOKEN_LIFETIME / 2 - Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,576 @@
+use crate::auth::Principal;
+use crate::errors::{ceil_secs, ApiError};
+use crate::AppState;
+use hyper::header::{HeaderName, HeaderValue, RETRY_AFTER};
+use hyper::HeaderMap;
+use redis::aio::ConnectionManager;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::OnceCell;
+use tokio::time::Instant;
+
+pub const RATELIMIT_LIMIT: &str = "ratelimit-limit";
+pub const RATELIMIT_REMAINING: &str = "ratelimit-remaining";
+pub const RATELIMIT_RESET: &str = "ratelimit-reset";
+pub const RATELIMIT_POLICY: &str = "ratelimit-policy";
+
+// Prefix of the Redis keys holding each bucket's state
+const REDIS_KEY_PREFIX: &str = "rust_api:ratelimit:";
+// Longest we wait on Redis before letting the request through
+const REDIS_TIMEOUT: Duration = Duration::from_millis(250);
+// How often idle in-memory buckets are dropped
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+// GCRA on the Redis server's clock, so replicas agree on time. Times are in
+// microseconds. Returns {allowed, wait, reset}.
+const GCRA_SCRIPT: &str = r"
+local t = redis.call('TIME')
+local now = tonumber(t[1]) * 1000000 + tonumber(t[2])
+local interval = tonumber(ARGV[1])
+local tolerance = tonumber(ARGV[2])
+local tat = tonumber(redis.call('GET', KEYS[1])) or now
+if tat < now then tat = now end
+local new_tat = tat + interval
+local allow_at = new_tat - tolerance
+if now < allow_at then
+  return {0, allow_at - now, tat - now}
+end
+redis.call('SET', KEYS[1], string.format('%d', new_tat), 'PX', math.ceil((new_tat - now) / 1000))
+return {1, 0, new_tat - now}
+";
+
+// Rate limit settings as written in the config file. The `[rate_limit]`
+// section sets the default for every route and a route-level table only
+// needs to list what it overrides.
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FileRateLimit {
+    enabled: Option<bool>,
+    requests: Option<u32>,
+    period_secs: Option<u64>,
+    burst: Option<u32>,
+    // `ip`, `api_key` or `header:<name>`
+    key: Option<String>,
+    // Only valid in `[rate_limit]`
+    redis_url: Option<String>,
+}
+
+impl FileRateLimit {
+    pub fn redis_url(&self) -> Option<&str> {
+        self.redis_url.as_deref()
+    }
+}
+
+// What identifies a client
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LimitKey {
+    Ip,
+    // The authenticated API key or JWT subject; the IP on open routes
+    ApiKey,
+    // A request header's value; the IP when it is missing
+    Header(HeaderName),
+}
+
+impl LimitKey {
+    fn parse(s: &str) -> Result<LimitKey, String> {
+        match s {
+            "ip" => Ok(LimitKey::Ip),
+            "api_key" => Ok(LimitKey::ApiKey),
+            _ => match s.strip_prefix("header:") {
+                Some(name) => HeaderName::from_bytes(name.trim().as_bytes())
+                    .map(LimitKey::Header)
+                    .map_err(|_| format!("invalid header name '{}' in key", name)),
+                None => Err(format!("unknown key '{}' (expected ip, api_key or header:<name>)", s)),
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct LimitSettings {
+    enabled: bool,
+    // Unset until a config level gives a rate; no limit applies without one
+    requests: Option<u32>,
+    period: Duration,
+    // Requests allowed at once; defaults to `requests`
+    burst: Option<u32>,
+    key: LimitKey,
+}
+
+impl Default for LimitSettings {
+    fn default() -> LimitSettings {
+        LimitSettings { enabled: true, requests: None, period: Duration::from_secs(1), burst: None, key: LimitKey::Ip }
+    }
+}
+
+impl LimitSettings {
+    // Apply the fields set in `file` on top of `self`
+    pub fn merge(&self, file: &FileRateLimit) -> Result<LimitSettings, String> {
+        let mut s = self.clone();
+        if let Some(e) = file.enabled {
+            s.enabled = e;
+        }
+        if let Some(n) = file.requests {
+            if n == 0 {
+                return Err("requests must be at least 1".to_string());
+            }
+            s.requests = Some(n);
+        }
+        if let Some(p) = file.period_secs {
+            if p == 0 {
+                return Err("period_secs must be at least 1".to_string());
+            }
+            s.period = Duration::from_secs(p);
+        }
+        if let Some(b) = file.burst {
+            if b == 0 {
+                return Err("burst must be at least 1".to_string());
+            }
+            s.burst = Some(b);
+        }
+        if let Some(k) = &file.key {
+            s.key = LimitKey::parse(k)?;
+        }
+        Ok(s)
+    }
+
+    // The limit to enforce, if any
+    pub fn policy(&self) -> Option<RateLimit> {
+        let requests = self.requests.filter(|_| self.enabled)?;
+        Some(RateLimit {
+            requests,
+            period: self.period,
+            burst: self.burst.unwrap_or(requests),
+            key: self.key.clone(),
+        })
+    }
+}
+
+// A route's limit: `requests` per `period`, with up to `burst` at once
+#[derive(Debug, Clone)]
+pub struct RateLimit {
+    pub requests: u32,
+    pub period: Duration,
+    pub burst: u32,
+    pub key: LimitKey,
+}
+
+impl RateLimit {
+    // Time between requests at the sustained rate
+    fn interval(&self) -> Duration {
+        self.period / self.requests
+    }
+
+    // How far ahead of now the bucket may be booked
+    fn tolerance(&self) -> Duration {
+        self.interval() * self.burst
+    }
+
+    // Identifies the caller within a route's buckets
+    fn client(&self, headers: &HeaderMap, remote: IpAddr, principal: Option<&Principal>) -> String {
+        let value = match (&self.key, principal) {
+            (LimitKey::ApiKey, Some(Principal::ApiKey { id, .. })) => Some(format!("key:{}", id)),
+            (LimitKey::ApiKey, Some(Principal::Jwt { subject: Some(sub) })) => Some(format!("sub:{}", sub)),
+            (LimitKey::Header(name), _) => headers
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(|v| format!("header:{}", v)),
+            _ => None,
+        };
+        value.unwrap_or_else(|| format!("ip:{}", remote))
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct RateLimitConfig {
+    // Share buckets through Redis instead of keeping them in memory
+    pub redis_url: Option<String>,
+}
+
+impl RateLimitConfig {
+    pub fn from_file(file: &FileRateLimit) -> Result<RateLimitConfig, String> {
+        if let Some(url) = &file.redis_url {
+            redis::Client::open(url.as_str()).map_err(|e| format!("invalid redis_url '{}': {}", url, e))?;
+        }
+        Ok(RateLimitConfig { redis_url: file.redis_url.clone() })
+    }
+}
+
+// Outcome of one check, reported in the `RateLimit-*` headers
+#[derive(Debug)]
+pub struct Decision {
+    allowed: bool,
+    limit: RateLimit,
+    remaining: u32,
+    // Until the bucket is full again
+    reset: Duration,
+    // Until the next request would be allowed, when this one was not
+    wait: Duration,
+}
+
+impl Decision {
+    fn new(limit: &RateLimit, allowed: bool, wait: Duration, reset: Duration) -> Decision {
+        let free = limit.tolerance().saturating_sub(reset);
+        let remaining = (free.as_micros() / limit.interval().as_micros().max(1)) as u32;
+        Decision { allowed, limit: limit.clone(), remaining: remaining.min(limit.burst), reset, wait }
+    }
+
+    // A request let through because the backend could not be asked
+    fn unchecked(limit: &RateLimit) -> Decision {
+        Decision::new(limit, true, Duration::ZERO, Duration::ZERO)
+    }
+
+    pub fn headers(&self) -> Vec<(HeaderName, HeaderValue)> {
+        let policy = format!("{};w={};burst={}", self.limit.requests, self.limit.period.as_secs(), self.limit.burst);
+        let mut headers = vec![
+            (HeaderName::from_static(RATELIMIT_LIMIT), HeaderValue::from(self.limit.burst)),
+            (HeaderName::from_static(RATELIMIT_REMAINING), HeaderValue::from(self.remaining)),
+            (HeaderName::from_static(RATELIMIT_RESET), HeaderValue::from(ceil_secs(self.reset))),
+            (HeaderName::from_static(RATELIMIT_POLICY), HeaderValue::from_str(&policy).expect("valid policy")),
+        ];
+        if !self.allowed {
+            headers.push((RETRY_AFTER, HeaderValue::from(ceil_secs(self.wait))));
+        }
+        headers
+    }
+
+    // The 429 to send when the request was not allowed
+    pub fn into_result(self) -> Result<Decision, ApiError> {
+        if self.allowed {
+            return Ok(self);
+        }
+        let mut err = ApiError::rate_limited(self.wait);
+        for (name, value) in self.headers() {
+            err.set_header(name, value);
+        }
+        Err(err)
+    }
+}
+
+enum Backend {
+    // Theoretical arrival time of the next request, per bucket
+    Memory(Mutex<HashMap<String, Instant>>),
+    Redis(Box<RedisBuckets>),
+}
+
+struct RedisBuckets {
+    client: redis::Client,
+    // Connected on first use and reconnected by the manager afterwards
+    conn: OnceCell<ConnectionManager>,
+    script: redis::Script,
+}
+
+// GCRA (the generic cell rate algorithm, a token bucket that stores one
+// timestamp per client) over in-memory or Redis-held buckets
+pub struct RateLimiter {
+    backend: Backend,
+}
+
+impl RateLimiter {
+    pub fn new(config: &RateLimitConfig) -> RateLimiter {
+        let backend = match &config.redis_url {
+            Some(url) => Backend::Redis(Box::new(RedisBuckets {
+                client: redis::Client::open(url.as_str()).expect("redis_url checked when loading config"),
+                conn: OnceCell::new(),
+                script: redis::Script::new(GCRA_SCRIPT),
+            })),
+            None => Backend::Memory(Mutex::new(HashMap::new())),
+        };
+        RateLimiter { backend }
+    }
+
+    // Count a request against `route`'s limit for this caller
+    pub async fn check(
+        &self,
+        route: &str,
+        limit: &RateLimit,
+        headers: &HeaderMap,
+        remote: IpAddr,
+        principal: Option<&Principal>,
+    ) -> Decision {
+        let bucket = format!("{}|{}", route, limit.client(headers, remote, principal));
+        match &self.backend {
+            Backend::Memory(buckets) => {
+                let now = Instant::now();
+                let mut buckets = buckets.lock().unwrap();
+                let tat = buckets.get(&bucket).copied().unwrap_or(now).max(now);
+                let new_tat = tat + limit.interval();
+                let allow_at = new_tat.checked_sub(limit.tolerance()).unwrap_or(now);
+                if now < allow_at {
+                    return Decision::new(limit, false, allow_at - now, tat - now);
+                }
+                buckets.insert(bucket, new_tat);
+                Decision::new(limit, true, Duration::ZERO, new_tat - now)
+            }
+            Backend::Redis(redis) => {
+                let call = async {
+                    let mut conn = redis
+                        .conn
+                        .get_or_try_init(|| ConnectionManager::new(redis.client.clone()))
+                        .await?
+                        .clone();
+                    redis
+                        .script
+                        .key(format!("{}{}", REDIS_KEY_PREFIX, bucket))
+                        .arg(limit.interval().as_micros() as u64)
+                        .arg(limit.tolerance().as_micros() as u64)
+                        .invoke_async::<(u8, u64, u64)>(&mut conn)
+                        .await
+                };
+                // Redis trouble must not take the proxy down with it
+                match tokio::time::timeout(REDIS_TIMEOUT, call).await {
+                    Ok(Ok((allowed, wait, reset))) => Decision::new(
+                        limit,
+                        allowed == 1,
+                        Duration::from_micros(wait),
+                        Duration::from_micros(reset),
+                    ),
+                    Ok(Err(e)) => {
+                        eprintln!("Rate limit check failed, allowing request: {}", e);
+                        Decision::unchecked(limit)
+                    }
+                    Err(_) => {
+                        eprintln!("Rate limit check timed out, allowing request");
+                        Decision::unchecked(limit)
+                    }
+                }
+            }
+        }
+    }
+}
+
+// Drop in-memory buckets that have refilled, since they hold no state
+pub fn spawn_sweep(state: Arc<AppState>) {
+    if !matches!(state.rate_limiter.backend, Backend::Memory(_)) {
+        return;
+    }
+    let mut interval = tokio::time::interval(SWEEP_INTERVAL);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    tokio::spawn(async move {
+        loop {
+            interval.tick().await;
+            if let Backend::Memory(buckets) = &state.rate_limiter.backend {
+                let now = Instant::now();
+                buckets.lock().unwrap().retain(|_, tat| *tat > now);
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpListener;
+
+    const CLIENT: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+    fn limit(file: FileRateLimit) -> RateLimit {
+        LimitSettings::default().merge(&file).unwrap().policy().unwrap()
+    }
+
+    fn per_second(requests: u32, burst: u32) -> RateLimit {
+        limit(FileRateLimit { requests: Some(requests), burst: Some(burst), ..Default::default() })
+    }
+
+    async fn check(limiter: &RateLimiter, limit: &RateLimit) -> Decision {
+        limiter.check("GET /", limit, &HeaderMap::new(), CLIENT, None).await
+    }
+
+    // Buckets the stub holds: key -> theoretical arrival time in microseconds
+    // of its own clock
+    #[derive(Default)]
+    struct Stub {
+        buckets: Mutex<HashMap<String, u64>>,
+        evals: AtomicUsize,
+    }
+
+    impl Stub {
+        // The script's logic, run on the stub's clock
+        fn gcra(&self, key: &str, interval: u64, tolerance: u64) -> (u64, u64, u64) {
+            let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_micros() as u64;
+            let mut buckets = self.buckets.lock().unwrap();
+            let tat = buckets.get(key).copied().unwrap_or(now).max(now);
+            let new_tat = tat + interval;
+            let allow_at = new_tat.saturating_sub(tolerance);
+            if now < allow_at {
+                return (0, allow_at - now, tat - now);
+            }
+            buckets.insert(key.to_string(), new_tat);
+            (1, 0, new_tat - now)
+        }
+    }
+
+    // Speaks just enough RESP for `redis::Script`: EVALSHA fails with
+    // NOSCRIPT until the script is loaded
+    async fn redis_stub(stub: Arc<Stub>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("redis://{}/", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let loaded = Arc::new(Mutex::new(None::<String>));
+            loop {
+                let (socket, _) = listener.accept().await.unwrap();
+                let (stub, loaded) = (stub.clone(), loaded.clone());
+                tokio::spawn(async move {
+                    let (read, mut write) = socket.into_split();
+                    let mut read = BufReader::new(read);
+                    loop {
+                        let mut line = String::new();
+                        if read.read_line(&mut line).await.unwrap_or(0) == 0 {
+                            return;
+                        }
+                        let n: usize = line.trim()[1..].parse().unwrap();
+                        let mut args = Vec::new();
+                        for _ in 0..n {
+                            line.clear();
+                            read.read_line(&mut line).await.unwrap();
+                            let len: usize = line.trim()[1..].parse().unwrap();
+                            let mut arg = vec![0; len + 2];
+                            read.read_exact(&mut arg).await.unwrap();
+                            arg.truncate(len);
+                            args.push(String::from_utf8(arg).unwrap());
+                        }
+                        let reply = match args[0].to_ascii_uppercase().as_str() {
+                            "SCRIPT" => {
+                                assert_eq!(args[2], GCRA_SCRIPT);
+                                let sha = redis::Script::new(&args[2]).get_hash().to_string();
+                                *loaded.lock().unwrap() = Some(sha.clone());
+                                format!("${}\r\n{}\r\n", sha.len(), sha)
+                            }
+                            "EVALSHA" if loaded.lock().unwrap().as_deref() != Some(args[1].as_str()) => {
+                                "-NOSCRIPT No matching script\r\n".to_string()
+                            }
+                            "EVALSHA" => {
+                                stub.evals.fetch_add(1, Ordering::SeqCst);
+                                let (allowed, wait, reset) = stub.gcra(&args[3], args[4].parse().unwrap(), args[5].parse().unwrap());
+                                format!("*3\r\n:{}\r\n:{}\r\n:{}\r\n", allowed, wait, reset)
+                            }
+                            _ => "+OK\r\n".to_string(),
+                        };
+                        write.write_all(reply.as_bytes()).await.unwrap();
+                    }
+                });
+            }
+        });
+        url
+    }
+
+    #[tokio::test]
+    async fn gcra_allows_the_burst_then_the_rate() {
+        let limiter = RateLimiter::new(&RateLimitConfig::default());
+        // 3 a minute with bursts of 3: one request every 20s
+        let limit = limit(FileRateLimit { requests: Some(3), period_secs: Some(60), ..Default::default() });
+        for remaining in [2, 1, 0] {
+            let d = check(&limiter, &limit).await;
+            assert!(d.allowed);
+            assert_eq!(d.remaining, remaining);
+        }
+        let d = check(&limiter, &limit).await;
+        assert!(!d.allowed);
+        assert!(d.wait > Duration::from_secs(19) && d.wait <= Duration::from_secs(20));
+        assert!(d.reset > Duration::from_secs(59) && d.reset <= Duration::from_secs(60));
+    }
+
+    #[tokio::test]
+    async fn buckets_refill_at_the_rate() {
+        let limiter = RateLimiter::new(&RateLimitConfig::default());
+        // One request every 20ms, none saved up
+        let limit = per_second(50, 1);
+        assert!(check(&limiter, &limit).await.allowed);
+        assert!(!check(&limiter, &limit).await.allowed);
+        tokio::time::sleep(Duration::from_millis(25)).await;
+        assert!(check(&limiter, &limit).await.allowed);
+        assert!(!check(&limiter, &limit).await.allowed);
+    }
+
+    #[tokio::test]
+    async fn clients_have_their_own_buckets() {
+        let limiter = RateLimiter::new(&RateLimitConfig::default());
+        let limit = limit(FileRateLimit { requests: Some(1), period_secs: Some(60), key: Some("header:x-client".to_string()), ..Default::default() });
+        let headers = |client: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert("x-client", client.parse().unwrap());
+            headers
+        };
+        assert!(limiter.check("GET /", &limit, &headers("a"), CLIENT, None).await.allowed);
+        assert!(!limiter.check("GET /", &limit, &headers("a"), CLIENT, None).await.allowed);
+        assert!(limiter.check("GET /", &limit, &headers("b"), CLIENT, None).await.allowed);
+        // Falls back to the IP without the header
+        assert!(limiter.check("GET /", &limit, &HeaderMap::new(), CLIENT, None).await.allowed);
+        // Routes are counted separately
+        assert!(limiter.check("GET /other", &limit, &headers("a"), CLIENT, None).await.allowed);
+    }
+
+    #[test]
+    fn callers_are_identified_by_the_configured_key() {
+        let by_key = limit(FileRateLimit { requests: Some(1), key: Some("api_key".to_string()), ..Default::default() });
+        let key = Principal::ApiKey { id: "k1".to_string(), owner: None };
+        let jwt = Principal::Jwt { subject: Some("alice".to_string()) };
+        assert_eq!(by_key.client(&HeaderMap::new(), CLIENT, Some(&key)), "key:k1");
+        assert_eq!(by_key.client(&HeaderMap::new(), CLIENT, Some(&jwt)), "sub:alice");
+        assert_eq!(by_key.client(&HeaderMap::new(), CLIENT, Some(&Principal::Jwt { subject: None })), "ip:127.0.0.1");
+        assert_eq!(by_key.client(&HeaderMap::new(), CLIENT, None), "ip:127.0.0.1");
+    }
+
+    #[test]
+    fn headers_describe_the_decision() {
+        let limit = per_second(10, 3);
+        let denied = Decision::new(&limit, false, Duration::from_millis(1500), Duration::from_millis(300));
+        let headers: HashMap<String, String> =
+            denied.headers().into_iter().map(|(n, v)| (n.to_string(), v.to_str().unwrap().to_string())).collect();
+        assert_eq!(headers[RATELIMIT_LIMIT], "3");
+        assert_eq!(headers[RATELIMIT_REMAINING], "0");
+        assert_eq!(headers[RATELIMIT_RESET], "1");
+        assert_eq!(headers[RATELIMIT_POLICY], "10;w=1;burst=3");
+        assert_eq!(headers["retry-after"], "2");
+        let err = denied.into_result().unwrap_err();
+        assert_eq!(err.status, hyper::StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    #[test]
+    fn settings_merge_and_validate() {
+        let merge = |file: FileRateLimit| LimitSettings::default().merge(&file);
+        assert!(merge(FileRateLimit::default()).unwrap().policy().is_none());
+        assert!(merge(FileRateLimit { requests: Some(5), enabled: Some(false), ..Default::default() }).unwrap().policy().is_none());
+        assert!(merge(FileRateLimit { requests: Some(0), ..Default::default() }).is_err());
+        assert!(merge(FileRateLimit { burst: Some(0), ..Default::default() }).is_err());
+        assert!(merge(FileRateLimit { key: Some("cookie".to_string()), ..Default::default() }).is_err());
+        let policy = merge(FileRateLimit { requests: Some(5), ..Default::default() }).unwrap().policy().unwrap();
+        assert_eq!(policy.burst, 5);
+        assert!(RateLimitConfig::from_file(&FileRateLimit { redis_url: Some("http://x".to_string()), ..Default::default() }).is_err());
+    }
+
+    #[tokio::test]
+    async fn redis_buckets_are_shared_between_replicas() {
+        let stub = Arc::new(Stub::default());
+        let config = RateLimitConfig { redis_url: Some(redis_stub(stub.clone()).await) };
+        let (a, b) = (RateLimiter::new(&config), RateLimiter::new(&config));
+        let limit = limit(FileRateLimit { requests: Some(2), period_secs: Some(60), ..Default::default() });
+        assert!(check(&a, &limit).await.allowed);
+        let d = check(&b, &limit).await;
+        assert!(d.allowed);
+        assert_eq!(d.remaining, 0);
+        let d = check(&a, &limit).await;
+        assert!(!d.allowed);
+        assert!(d.wait > Duration::from_secs(29) && d.wait <= Duration::from_secs(30));
+        assert_eq!(stub.evals.load(Ordering::SeqCst), 3);
+        let keys: Vec<String> = stub.buckets.lock().unwrap().keys().cloned().collect();
+        assert_eq!(keys, ["rust_api:ratelimit:GET /|ip:127.0.0.1"]);
+    }
+
+    #[tokio::test]
+    async fn requests_pass_when_redis_is_down() {
+        // Bound and dropped, so nothing is listening
+        let addr = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+        let limiter = RateLimiter::new(&RateLimitConfig { redis_url: Some(format!("redis://{}/", addr)) });
+        let limit = per_second(1, 1);
+        for _ in 0..3 {
+            assert!(check(&limiter, &limit).await.allowed);
+        }
+    }
+}
@@ -0,0 +1,501 @@
+use regex::Regex;
+use serde::Deserialize;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+
+// Replacement for masked values
+const MASK: &str = "[REDACTED]";
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FileRedaction {
+    // Prepended to values before hashing so hashes cannot be looked up
+    hash_salt: Option<String>,
+    log: Option<FileProfile>,
+    upstream: Option<FileProfile>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileProfile {
+    #[serde(default)]
+    rules: Vec<FileRule>,
+    // Built-in detectors: `email`, `card`, `phone`
+    #[serde(default)]
+    detectors: Vec<String>,
+    // Extra detectors, name -> regular expression
+    #[serde(default)]
+    patterns: BTreeMap<String, String>,
+    detector_action: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileRule {
+    path: String,
+    action: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Action {
+    Mask,
+    Hash,
+    Drop,
+}
+
+impl Action {
+    fn parse(s: &str) -> Result<Action, String> {
+        match s {
+            "mask" => Ok(Action::Mask),
+            "hash" => Ok(Action::Hash),
+            "drop" => Ok(Action::Drop),
+            _ => Err(format!("unknown action '{}' (expected mask, hash or drop)", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Selector {
+    Key(String),
+    Index(usize),
+    Any,
+}
+
+// One step of a JSON path; `descendant` steps (`..`) match at any depth
+#[derive(Debug, Clone)]
+struct Step {
+    descendant: bool,
+    selector: Selector,
+}
+
+// Parse `$.a.b`, `$.items[*].card`, `$.list[0]`, `$['odd key']` or `$..password`
+fn parse_path(path: &str) -> Result<Vec<Step>, String> {
+    let rest = path.strip_prefix('$').ok_or_else(|| format!("path '{}' must start with '$'", path))?;
+    let mut steps = Vec::new();
+    let mut chars = rest.chars().peekable();
+    while let Some(c) = chars.next() {
+        let (descendant, selector) = match c {
+            '.' => {
+                let descendant = chars.next_if_eq(&'.').is_some();
+                let mut name = String::new();
+                while let Some(c) = chars.next_if(|c| *c != '.' && *c != '[') {
+                    name.push(c);
+                }
+                match name.as_str() {
+                    "" => return Err(format!("path '{}' has an empty segment", path)),
+                    "*" => (descendant, Selector::Any),
+                    _ => (descendant, Selector::Key(name)),
+                }
+            }
+            '[' => {
+                let mut inner = String::new();
+                for c in chars.by_ref() {
+                    if c == ']' {
+                        break;
+                    }
+                    inner.push(c);
+                }
+                let quoted = inner
+                    .strip_prefix('\'')
+                    .and_then(|s| s.strip_suffix('\''))
+                    .or_else(|| inner.strip_prefix('"').and_then(|s| s.strip_suffix('"')));
+                let selector = if inner == "*" {
+                    Selector::Any
+                } else if let Some(key) = quoted {
+                    Selector::Key(key.to_string())
+                } else {
+                    Selector::Index(inner.parse().map_err(|_| format!("path '{}' has an invalid index '[{}]'", path, inner))?)
+                };
+                (false, selector)
+            }
+            _ => return Err(format!("path '{}' is invalid near '{}'", path, c)),
+        };
+        steps.push(Step { descendant, selector });
+    }
+    if steps.is_empty() {
+        return Err(format!("path '{}' must select something below the root", path));
+    }
+    Ok(steps)
+}
+
+#[derive(Debug, Clone)]
+struct Rule {
+    steps: Vec<Step>,
+    action: Action,
+}
+
+#[derive(Debug, Clone)]
+struct Detector {
+    name: String,
+    regex: Regex,
+}
+
+impl Detector {
+    fn builtin(name: &str) -> Result<Detector, String> {
+        let pattern = match name {
+            "email" => r"[A-Za-z0-9._%+-]+@[A-Za-z0-9.-]+\.[A-Za-z]{2,}",
+            "card" => r"\b(?:\d[ -]?){12,18}\d\b",
+            "phone" => r"\+?(?:\(\d{1,4}\)|\d{1,4})(?:[ .-]?\d{2,4}){2,5}\b",
+            _ => return Err(format!("unknown detector '{}' (expected email, card or phone)", name)),
+        };
+        Ok(Detector { name: name.to_string(), regex: Regex::new(pattern).expect("valid built-in pattern") })
+    }
+
+    // Built-in detectors double-check candidates the regex alone over-matches
+    fn accepts(&self, candidate: &str) -> bool {
+        let digits: Vec<u32> = candidate.chars().filter_map(|c| c.to_digit(10)).collect();
+        match self.name.as_str() {
+            "card" => (13..=19).contains(&digits.len()) && luhn(&digits),
+            // Without a country code, require a full national number so
+            // dates and short ids are left alone
+            "phone" if candidate.starts_with('+') => (8..=15).contains(&digits.len()),
+            "phone" => (10..=15).contains(&digits.len()),
+            _ => true,
+        }
+    }
+}
+
+fn luhn(digits: &[u32]) -> bool {
+    let sum: u32 = digits
+        .iter()
+        .rev()
+        .enumerate()
+        .map(|(i, &d)| if i % 2 == 1 { if d * 2 > 9 { d * 2 - 9 } else { d * 2 } } else { d })
+        .sum();
+    sum.is_multiple_of(10)
+}
+
+// Rules and detectors applied to one destination (logs or upstream bodies)
+#[derive(Debug, Clone, Default)]
+pub struct Redactor {
+    rules: Vec<Rule>,
+    detectors: Vec<Detector>,
+    detector_action: Option<Action>,
+    salt: String,
+}
+
+impl Redactor {
+    fn from_file(file: &FileProfile, salt: &str) -> Result<Redactor, String> {
+        let rules = file
+            .rules
+            .iter()
+            .enumerate()
+            .map(|(i, r)| {
+                let rule = || Ok(Rule { steps: parse_path(&r.path)?, action: Action::parse(&r.action)? });
+                rule().map_err(|e: String| format!("rules[{}]: {}", i, e))
+            })
+            .collect::<Result<_, _>>()?;
+        let mut detectors = file
+            .detectors
+            .iter()
+            .map(|d| Detector::builtin(d))
+            .collect::<Result<Vec<_>, _>>()?;
+        for (name, pattern) in &file.patterns {
+            let regex = Regex::new(pattern).map_err(|e| format!("patterns.{}: {}", name, e))?;
+            detectors.push(Detector { name: name.clone(), regex });
+        }
+        let detector_action = match &file.detector_action {
+            Some(a) => Action::parse(a).map_err(|e| format!("detector_action: {}", e))?,
+            None => Action::Mask,
+        };
+        Ok(Redactor {
+            rules,
+            detector_action: (!detectors.is_empty()).then_some(detector_action),
+            detectors,
+            salt: salt.to_string(),
+        })
+    }
+
+    // Apply path rules, then scan every remaining string with the detectors
+    pub fn redact(&self, value: &mut Value) {
+        for rule in &self.rules {
+            self.apply_rule(value, &rule.steps, rule.action);
+        }
+        if let Some(action) = self.detector_action {
+            self.scan(value, action);
+        }
+    }
+
+    // Detector pass over free text such as a request path; matches are
+    // masked or hashed in place, and `drop` masks them
+    pub fn redact_text<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        match self.detector_action {
+            Some(Action::Hash) => self.replace_matches(text, Action::Hash),
+            Some(_) => self.replace_matches(text, Action::Mask),
+            None => Cow::Borrowed(text),
+        }
+    }
+
+    fn hash(&self, value: &Value) -> Value {
+        let text = match value {
+            Value::String(s) => Cow::Borrowed(s.as_str()),
+            other => Cow::Owned(other.to_string()),
+        };
+        self.hash_str(&text).into()
+    }
+
+    fn hash_str(&self, text: &str) -> String {
+        let digest = Sha256::new().chain_update(&self.salt).chain_update(text).finalize();
+        let hex: String = digest.iter().map(|b| format!("{:02x}", b)).collect();
+        format!("sha256:{}", hex)
+    }
+
+    fn apply_rule(&self, value: &mut Value, steps: &[Step], action: Action) {
+        let Some((step, rest)) = steps.split_first() else {
+            return;
+        };
+        if step.descendant {
+            // Search below first so dropping at this level cannot hide matches
+            match value {
+                Value::Object(map) => map.values_mut().for_each(|v| self.apply_rule(v, steps, action)),
+                Value::Array(items) => items.iter_mut().for_each(|v| self.apply_rule(v, steps, action)),
+                _ => {}
+            }
+        }
+        match (value, &step.selector) {
+            (Value::Object(map), Selector::Key(key)) => {
+                if rest.is_empty() && action == Action::Drop {
+                    map.remove(key);
+                } else if let Some(child) = map.get_mut(key) {
+                    self.apply_at(child, rest, action);
+                }
+            }
+            (Value::Object(map), Selector::Any) => {
+                if rest.is_empty() && action == Action::Drop {
+                    map.clear();
+                } else {
+                    map.values_mut().for_each(|child| self.apply_at(child, rest, action));
+                }
+            }
+            (Value::Array(items), Selector::Index(i)) => {
+                if rest.is_empty() && action == Action::Drop {
+                    if *i < items.len() {
+                        items.remove(*i);
+                    }
+                } else if let Some(child) = items.get_mut(*i) {
+                    self.apply_at(child, rest, action);
+                }
+            }
+            (Value::Array(items), Selector::Any) => {
+                if rest.is_empty() && action == Action::Drop {
+                    items.clear();
+                } else {
+                    items.iter_mut().for_each(|child| self.apply_at(child, rest, action));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // Continue down the path, or mask/hash the selected value at its end
+    fn apply_at(&self, value: &mut Value, rest: &[Step], action: Action) {
+        if !rest.is_empty() {
+            self.apply_rule(value, rest, action);
+        } else if action == Action::Hash {
+            *value = self.hash(value);
+        } else {
+            *value = MASK.into();
+        }
+    }
+
+    // Returns false when a detector matched and the action is `drop`, so the
+    // caller removes the value
+    fn scan(&self, value: &mut Value, action: Action) -> bool {
+        match value {
+            Value::String(s) => {
+                if action == Action::Drop {
+                    return !self.detectors.iter().any(|d| d.regex.find_iter(s).any(|m| d.accepts(m.as_str())));
+                }
+                if let Cow::Owned(replaced) = self.replace_matches(s, action) {
+                    *s = replaced;
+                }
+                true
+            }
+            Value::Object(map) => {
+                map.retain(|_, v| self.scan(v, action));
+                true
+            }
+            Value::Array(items) => {
+                items.retain_mut(|v| self.scan(v, action));
+                true
+            }
+            _ => true,
+        }
+    }
+
+    fn replace_matches<'a>(&self, text: &'a str, action: Action) -> Cow<'a, str> {
+        let mut out = Cow::Borrowed(text);
+        for d in &self.detectors {
+            let replaced = d.regex.replace_all(&out, |caps: &regex::Captures| {
+                let m = &caps[0];
+                if !d.accepts(m) {
+                    m.to_string()
+                } else if action == Action::Hash {
+                    self.hash_str(m)
+                } else {
+                    format!("[REDACTED:{}]", d.name)
+                }
+            });
+            if let Cow::Owned(s) = replaced {
+                out = Cow::Owned(s);
+            }
+        }
+        out
+    }
+}
+
+// Independent profiles for what we log and what we send upstream
+#[derive(Debug, Clone, Default)]
+pub struct RedactionConfig {
+    pub log: Redactor,
+    pub upstream: Redactor,
+}
+
+impl RedactionConfig {
+    pub fn from_file(file: &FileRedaction) -> Result<RedactionConfig, String> {
+        let salt = file.hash_salt.clone().unwrap_or_default();
+        let profile = |p: &Option<FileProfile>, name: &str| match p {
+            Some(p) => Redactor::from_file(p, &salt).map_err(|e| format!("{}: {}", name, e)),
+            None => Ok(Redactor::default()),
+        };
+        Ok(RedactionConfig { log: profile(&file.log, "log")?, upstream: profile(&file.upstream, "upstream")? })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    // The `log` profile of a `[redaction]` section
+    fn redactor(toml: &str) -> Redactor {
+        let file: FileRedaction = toml::from_str(toml).unwrap();
+        RedactionConfig::from_file(&file).unwrap().log
+    }
+
+    fn selectors(path: &str) -> Vec<String> {
+        parse_path(path)
+            .unwrap()
+            .iter()
+            .map(|s| {
+                let prefix = if s.descendant { ".." } else { "" };
+                match &s.selector {
+                    Selector::Key(k) => format!("{}{}", prefix, k),
+                    Selector::Index(i) => format!("{}[{}]", prefix, i),
+                    Selector::Any => format!("{}*", prefix),
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn paths_parse_into_steps() {
+        assert_eq!(selectors("$.a.b"), ["a", "b"]);
+        assert_eq!(selectors("$.items[*].card"), ["items", "*", "card"]);
+        assert_eq!(selectors("$.list[0]"), ["list", "[0]"]);
+        assert_eq!(selectors("$['odd key'].x"), ["odd key", "x"]);
+        assert_eq!(selectors("$[\"a.b\"]"), ["a.b"]);
+        assert_eq!(selectors("$..password"), ["..password"]);
+        assert_eq!(selectors("$.*"), ["*"]);
+    }
+
+    #[test]
+    fn bad_paths_are_rejected() {
+        assert!(parse_path("a.b").is_err());
+        assert!(parse_path("$").is_err());
+        assert!(parse_path("$.a..").is_err());
+        assert!(parse_path("$.a.").is_err());
+        assert!(parse_path("$.list[x]").is_err());
+        assert!(parse_path("$a").is_err());
+    }
+
+    #[test]
+    fn luhn_checksum() {
+        let digits = |s: &str| s.chars().filter_map(|c| c.to_digit(10)).collect::<Vec<_>>();
+        assert!(luhn(&digits("4111 1111 1111 1111")));
+        assert!(luhn(&digits("5500005555555559")));
+        assert!(luhn(&digits("378282246310005")));
+        assert!(!luhn(&digits("4111 1111 1111 1112")));
+        assert!(!luhn(&digits("1234567812345678")));
+    }
+
+    #[test]
+    fn rules_mask_hash_and_drop() {
+        let r = redactor(
+            r#"
+            hash_salt = "pepper"
+            [log]
+            rules = [
+                { path = "$.user.ssn", action = "mask" },
+                { path = "$..password", action = "drop" },
+                { path = "$.items[*].email", action = "hash" },
+                { path = "$.tags[0]", action = "drop" },
+            ]
+            "#,
+        );
+        let mut body = json!({
+            "user": { "ssn": "123-45-6789", "password": "x", "name": "Ann" },
+            "password": "y",
+            "items": [{ "email": "a@example.com" }, { "email": "b@example.com" }],
+            "tags": ["secret", "public"],
+        });
+        r.redact(&mut body);
+        let hashed = r.hash_str("a@example.com");
+        assert!(hashed.starts_with("sha256:") && hashed.len() == 7 + 64);
+        assert_ne!(hashed, Redactor::default().hash_str("a@example.com"));
+        assert_eq!(
+            body,
+            json!({
+                "user": { "ssn": "[REDACTED]", "name": "Ann" },
+                "items": [{ "email": hashed }, { "email": r.hash_str("b@example.com") }],
+                "tags": ["public"],
+            })
+        );
+    }
+
+    #[test]
+    fn detectors_check_their_candidates() {
+        let r = redactor(
+            r#"
+            [log]
+            detectors = ["email", "card", "phone"]
+            patterns = { order = "ORD-[0-9]{6}" }
+            "#,
+        );
+        let text = "ann@example.com paid with 4111 1111 1111 1111 on 2024-01-15, call +44 20 7946 0958 re ORD-123456";
+        assert_eq!(
+            r.redact_text(text),
+            "[REDACTED:email] paid with [REDACTED:card] on 2024-01-15, call [REDACTED:phone] re [REDACTED:order]"
+        );
+        // Fails the Luhn check, so not a card number
+        assert_eq!(r.redact_text("ref 1234 5678 1234 5678"), "ref 1234 5678 1234 5678");
+        assert!(matches!(r.redact_text("nothing here"), Cow::Borrowed(_)));
+    }
+
+    #[test]
+    fn detector_drop_removes_values() {
+        let r = redactor(
+            r#"
+            [log]
+            detectors = ["email"]
+            detector_action = "drop"
+            "#,
+        );
+        let mut body = json!({ "contact": "ann@example.com", "list": ["ok", "bob@example.com"], "n": 1 });
+        r.redact(&mut body);
+        assert_eq!(body, json!({ "list": ["ok"], "n": 1 }));
+        // Free text cannot lose a piece, so it is masked instead
+        assert_eq!(r.redact_text("/users/ann@example.com"), "/users/[REDACTED:email]");
+    }
+
+    #[test]
+    fn config_errors_name_the_profile() {
+        let err = |toml: &str| RedactionConfig::from_file(&toml::from_str(toml).unwrap()).unwrap_err();
+        assert_eq!(err("[upstream]\nrules = [{ path = \"x\", action = \"mask\" }]"), "upstream: rules[0]: path 'x' must start with '$'");
+        assert!(err("[log]\ndetectors = [\"ssn\"]").starts_with("log: unknown detector 'ssn'"));
+        assert!(err("[log]\nrules = [{ path = \"$.a\", action = \"burn\" }]").contains("unknown action 'burn'"));
+        assert!(err("[log]\npatterns = { bad = \"(\" }").starts_with("log: patterns.bad: "));
+    }
+}
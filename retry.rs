@@ -0,0 +1,263 @@
+use hyper::header::{HeaderMap, RETRY_AFTER};
+use hyper::{Method, StatusCode};
+use rand::Rng;
+use serde::Deserialize;
+use std::time::{Duration, SystemTime};
+
+// Retry settings as written in the config file; every field is optional so a
+// route-level table only needs to list what it overrides
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FileRetry {
+    max_attempts: Option<u32>,
+    base_delay_ms: Option<u64>,
+    max_delay_ms: Option<u64>,
+    jitter: Option<bool>,
+    retry_on_status: Option<Vec<u16>>,
+    retry_on_errors: Option<Vec<String>>,
+    respect_retry_after: Option<bool>,
+}
+
+// Transport failures that may be retried
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    Connect,
+    Timeout,
+    Other,
+}
+
+impl ErrorKind {
+    pub fn classify(e: &reqwest::Error) -> ErrorKind {
+        if e.is_connect() {
+            ErrorKind::Connect
+        } else if e.is_timeout() {
+            ErrorKind::Timeout
+        } else {
+            ErrorKind::Other
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ErrorKind::Connect => "connect",
+            ErrorKind::Timeout => "timeout",
+            ErrorKind::Other => "other",
+        }
+    }
+
+    fn parse(s: &str) -> Option<ErrorKind> {
+        match s {
+            "connect" => Some(ErrorKind::Connect),
+            "timeout" => Some(ErrorKind::Timeout),
+            "other" => Some(ErrorKind::Other),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    // Total number of attempts, including the first one
+    pub max_attempts: u32,
+    base_delay: Duration,
+    max_delay: Duration,
+    jitter: bool,
+    retry_on_status: Vec<StatusCode>,
+    retry_on_errors: Vec<ErrorKind>,
+    respect_retry_after: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 1,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(2),
+            jitter: true,
+            retry_on_status: vec![
+                StatusCode::BAD_GATEWAY,
+                StatusCode::SERVICE_UNAVAILABLE,
+                StatusCode::GATEWAY_TIMEOUT,
+            ],
+            retry_on_errors: vec![ErrorKind::Connect, ErrorKind::Timeout],
+            respect_retry_after: true,
+        }
+    }
+}
+
+impl RetryPolicy {
+    // Apply the fields set in `file` on top of `self`
+    pub fn merge(&self, file: &FileRetry) -> Result<RetryPolicy, String> {
+        let mut p = self.clone();
+        if let Some(n) = file.max_attempts {
+            if n == 0 {
+                return Err("max_attempts must be at least 1".to_string());
+            }
+            p.max_attempts = n;
+        }
+        if let Some(ms) = file.base_delay_ms {
+            p.base_delay = Duration::from_millis(ms);
+        }
+        if let Some(ms) = file.max_delay_ms {
+            p.max_delay = Duration::from_millis(ms);
+        }
+        if p.base_delay > p.max_delay {
+            return Err("base_delay_ms must not exceed max_delay_ms".to_string());
+        }
+        if let Some(j) = file.jitter {
+            p.jitter = j;
+        }
+        if let Some(codes) = &file.retry_on_status {
+            p.retry_on_status = codes
+                .iter()
+                .map(|c| StatusCode::from_u16(*c).map_err(|_| format!("invalid status code {}", c)))
+                .collect::<Result<_, _>>()?;
+        }
+        if let Some(kinds) = &file.retry_on_errors {
+            p.retry_on_errors = kinds
+                .iter()
+                .map(|k| {
+                    ErrorKind::parse(k)
+                        .ok_or_else(|| format!("unknown error kind '{}' (expected connect, timeout or other)", k))
+                })
+                .collect::<Result<_, _>>()?;
+        }
+        if let Some(r) = file.respect_retry_after {
+            p.respect_retry_after = r;
+        }
+        Ok(p)
+    }
+
+    // Only idempotent methods, or requests the caller marked with an
+    // idempotency key, may be sent more than once
+    pub fn allows(&self, method: &Method, has_idempotency_key: bool) -> bool {
+        self.max_attempts > 1 && (has_idempotency_key || is_idempotent(method))
+    }
+
+    pub fn retries_status(&self, status: StatusCode) -> bool {
+        self.retry_on_status.contains(&status)
+    }
+
+    pub fn retries_error(&self, kind: ErrorKind) -> bool {
+        self.retry_on_errors.contains(&kind)
+    }
+
+    // Delay before attempt number `attempt + 1` (attempts count from 1).
+    // Returns `None` when the upstream asked us to wait longer than the cap.
+    pub fn delay(&self, attempt: u32, headers: Option<&HeaderMap>) -> Option<Duration> {
+        if self.respect_retry_after {
+            if let Some(wait) = headers.and_then(retry_after) {
+                return if wait <= self.max_delay { Some(wait) } else { None };
+            }
+        }
+        let exp = self.base_delay.saturating_mul(2u32.saturating_pow(attempt - 1));
+        let capped = exp.min(self.max_delay);
+        if self.jitter {
+            // Full jitter: uniform in [0, capped]
+            let ms = rand::thread_rng().gen_range(0..=capped.as_millis() as u64);
+            Some(Duration::from_millis(ms))
+        } else {
+            Some(capped)
+        }
+    }
+}
+
+fn is_idempotent(method: &Method) -> bool {
+    matches!(
+        *method,
+        Method::GET | Method::HEAD | Method::PUT | Method::DELETE | Method::OPTIONS | Method::TRACE
+    )
+}
+
+// Parse a `Retry-After` header given either in seconds or as an HTTP date
+fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+    let at = httpdate::parse_http_date(value).ok()?;
+    Some(at.duration_since(SystemTime::now()).unwrap_or(Duration::ZERO))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::header::HeaderValue;
+
+    fn policy(file: FileRetry) -> RetryPolicy {
+        RetryPolicy::default().merge(&file).unwrap()
+    }
+
+    fn retry_after_header(value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(RETRY_AFTER, HeaderValue::from_str(value).unwrap());
+        headers
+    }
+
+    #[test]
+    fn delays_double_up_to_the_cap() {
+        let p = policy(FileRetry { base_delay_ms: Some(100), max_delay_ms: Some(1000), jitter: Some(false), ..Default::default() });
+        let delays: Vec<u64> = (1..=6).map(|a| p.delay(a, None).unwrap().as_millis() as u64).collect();
+        assert_eq!(delays, [100, 200, 400, 800, 1000, 1000]);
+        // Far-out attempts saturate instead of overflowing
+        assert_eq!(p.delay(200, None), Some(Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn jitter_stays_within_the_backoff() {
+        let p = policy(FileRetry { base_delay_ms: Some(100), max_delay_ms: Some(1000), ..Default::default() });
+        for _ in 0..100 {
+            assert!(p.delay(3, None).unwrap() <= Duration::from_millis(400));
+        }
+    }
+
+    #[test]
+    fn retry_after_seconds_and_dates() {
+        assert_eq!(retry_after(&retry_after_header("3")), Some(Duration::from_secs(3)));
+        assert_eq!(retry_after(&retry_after_header(" 0 ")), Some(Duration::ZERO));
+        assert_eq!(retry_after(&retry_after_header("soon")), None);
+        assert_eq!(retry_after(&retry_after_header("-1")), None);
+        assert_eq!(retry_after(&HeaderMap::new()), None);
+
+        let later = httpdate::fmt_http_date(SystemTime::now() + Duration::from_secs(60));
+        let wait = retry_after(&retry_after_header(&later)).unwrap();
+        assert!(wait > Duration::from_secs(55) && wait <= Duration::from_secs(60));
+        // Dates in the past mean retry now
+        assert_eq!(retry_after(&retry_after_header("Sun, 06 Nov 1994 08:49:37 GMT")), Some(Duration::ZERO));
+    }
+
+    #[test]
+    fn retry_after_overrides_backoff_within_the_cap() {
+        let p = policy(FileRetry { max_delay_ms: Some(5000), jitter: Some(false), ..Default::default() });
+        assert_eq!(p.delay(1, Some(&retry_after_header("2"))), Some(Duration::from_secs(2)));
+        // Longer than we are willing to wait
+        assert_eq!(p.delay(1, Some(&retry_after_header("10"))), None);
+
+        let p = policy(FileRetry { respect_retry_after: Some(false), jitter: Some(false), ..Default::default() });
+        assert_eq!(p.delay(1, Some(&retry_after_header("10"))), Some(Duration::from_millis(100)));
+    }
+
+    #[test]
+    fn only_idempotent_requests_are_retried() {
+        let p = policy(FileRetry { max_attempts: Some(3), ..Default::default() });
+        assert!(p.allows(&Method::GET, false));
+        assert!(p.allows(&Method::PUT, false));
+        assert!(!p.allows(&Method::POST, false));
+        assert!(p.allows(&Method::POST, true));
+        assert!(!RetryPolicy::default().allows(&Method::GET, false));
+    }
+
+    #[test]
+    fn merge_validates() {
+        let merge = |file: FileRetry| RetryPolicy::default().merge(&file);
+        assert!(merge(FileRetry { max_attempts: Some(0), ..Default::default() }).is_err());
+        assert!(merge(FileRetry { base_delay_ms: Some(3000), ..Default::default() }).is_err());
+        assert!(merge(FileRetry { retry_on_status: Some(vec![1000]), ..Default::default() }).is_err());
+        assert!(merge(FileRetry { retry_on_errors: Some(vec!["dns".to_string()]), ..Default::default() }).is_err());
+        let p = merge(FileRetry { retry_on_status: Some(vec![429]), retry_on_errors: Some(vec!["other".to_string()]), ..Default::default() }).unwrap();
+        assert!(p.retries_status(StatusCode::TOO_MANY_REQUESTS));
+        assert!(!p.retries_status(StatusCode::BAD_GATEWAY));
+        assert!(p.retries_error(ErrorKind::Other));
+        assert!(!p.retries_error(ErrorKind::Connect));
+    }
+}
@@ -0,0 +1,350 @@
+use crate::config::RouteConfig;
+use hyper::Method;
+
+// One segment of a route path pattern
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Segment {
+    // Must match the request segment exactly
+    Literal(String),
+    // `{name}`: matches any single non-empty segment
+    Param(String),
+    // `{*name}`: matches the remaining segments (possibly none); must be last
+    Wildcard(String),
+}
+
+// Parsed path pattern such as `/users/{id}` or `/files/{*path}`
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct PathPattern {
+    segments: Vec<Segment>,
+}
+
+// Parameters captured while matching a path, in pattern order
+pub type Params = Vec<(String, String)>;
+
+fn split_path(path: &str) -> impl Iterator<Item = &str> {
+    path.split('/').filter(|s| !s.is_empty())
+}
+
+// Decode `%XX` escapes; malformed escapes are kept as they are
+fn percent_decode(s: &str) -> Vec<u8> {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes.get(i + 1..i + 3).and_then(|h| std::str::from_utf8(h).ok());
+        match hex.and_then(|h| u8::from_str_radix(h, 16).ok()) {
+            Some(b) if bytes[i] == b'%' => {
+                out.push(b);
+                i += 3;
+            }
+            _ => {
+                out.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    out
+}
+
+// `.` and `..` would let a captured value climb out of the upstream path
+// prefix, so they are refused in any spelling (`%2e`, `.%2E`, ...)
+fn is_dot_segment(segment: &str) -> bool {
+    matches!(percent_decode(segment).as_slice(), b"." | b"..")
+}
+
+// Percent-encode a captured value for the upstream path. Each segment is
+// decoded first so existing escapes are not doubled, then everything but
+// unreserved characters (RFC 3986 section 2.3) is escaped again; `/` between
+// wildcard segments is kept.
+fn encode_value(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    for (i, segment) in value.split('/').enumerate() {
+        if i > 0 {
+            out.push('/');
+        }
+        for b in percent_decode(segment) {
+            if b.is_ascii_alphanumeric() || matches!(b, b'-' | b'.' | b'_' | b'~') {
+                out.push(b as char);
+            } else {
+                out.push_str(&format!("%{:02X}", b));
+            }
+        }
+    }
+    out
+}
+
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+impl PathPattern {
+    pub fn parse(pattern: &str) -> Result<PathPattern, String> {
+        if !pattern.starts_with('/') {
+            return Err(format!("path '{}' must start with '/'", pattern));
+        }
+        let mut segments = Vec::new();
+        let mut names = Vec::new();
+        let parts: Vec<&str> = split_path(pattern).collect();
+        for (i, part) in parts.iter().enumerate() {
+            let segment = match part.strip_prefix('{').and_then(|p| p.strip_suffix('}')) {
+                Some(inner) => {
+                    let (wildcard, name) = match inner.strip_prefix('*') {
+                        Some(n) => (true, n),
+                        None => (false, inner),
+                    };
+                    if !is_valid_name(name) {
+                        return Err(format!("path '{}': invalid parameter name '{}'", pattern, name));
+                    }
+                    if names.contains(&name) {
+                        return Err(format!("path '{}': parameter '{}' used twice", pattern, name));
+                    }
+                    names.push(name);
+                    if wildcard {
+                        if i != parts.len() - 1 {
+                            return Err(format!("path '{}': wildcard '{{*{}}}' must be the last segment", pattern, name));
+                        }
+                        Segment::Wildcard(name.to_string())
+                    } else {
+                        Segment::Param(name.to_string())
+                    }
+                }
+                None if part.contains('{') || part.contains('}') => {
+                    return Err(format!("path '{}': parameters must span a whole segment, got '{}'", pattern, part));
+                }
+                None => Segment::Literal(part.to_string()),
+            };
+            segments.push(segment);
+        }
+        Ok(PathPattern { segments })
+    }
+
+    pub fn param_names(&self) -> impl Iterator<Item = &str> {
+        self.segments.iter().filter_map(|s| match s {
+            Segment::Param(n) | Segment::Wildcard(n) => Some(n.as_str()),
+            Segment::Literal(_) => None,
+        })
+    }
+
+    // Match a request path, returning the captured parameters
+    pub fn matches(&self, path: &str) -> Option<Params> {
+        let mut params = Vec::new();
+        let mut parts = split_path(path);
+        for segment in &self.segments {
+            match segment {
+                Segment::Literal(lit) => {
+                    if parts.next()? != lit {
+                        return None;
+                    }
+                }
+                Segment::Param(name) => params.push((name.clone(), parts.next()?.to_string())),
+                Segment::Wildcard(name) => {
+                    let rest: Vec<&str> = parts.by_ref().collect();
+                    params.push((name.clone(), rest.join("/")));
+                }
+            }
+        }
+        match parts.next() {
+            Some(_) => None,
+            None => Some(params),
+        }
+    }
+
+    // Ordering key used to prefer literal segments over parameters, and
+    // parameters over wildcards, when several patterns match a path
+    fn specificity(&self) -> Vec<u8> {
+        let mut key: Vec<u8> = self
+            .segments
+            .iter()
+            .map(|s| match s {
+                Segment::Literal(_) => 3,
+                Segment::Param(_) => 2,
+                Segment::Wildcard(_) => 0,
+            })
+            .collect();
+        // An exact-length pattern beats a wildcard matching zero segments
+        if !matches!(self.segments.last(), Some(Segment::Wildcard(_))) {
+            key.push(1);
+        }
+        key
+    }
+}
+
+// Methods whose requests carry a JSON body
+pub fn expects_body(method: &Method) -> bool {
+    method == Method::POST || method == Method::PUT || method == Method::PATCH
+}
+
+// Substitute `{name}` / `{*name}` placeholders in an upstream path template.
+// Values are percent-encoded and inserted in a single pass, so a value that
+// itself looks like `{other}` is never expanded.
+pub fn render_template(template: &str, params: &Params) -> String {
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        out.push_str(&rest[..start]);
+        let Some(len) = rest[start..].find('}') else {
+            rest = &rest[start..];
+            break;
+        };
+        let placeholder = &rest[start..start + len + 1];
+        let name = placeholder[1..len].trim_start_matches('*');
+        match params.iter().find(|(n, _)| n == name) {
+            Some((_, value)) => out.push_str(&encode_value(value)),
+            None => out.push_str(placeholder),
+        }
+        rest = &rest[start + len + 1..];
+    }
+    out.push_str(rest);
+    out
+}
+
+// Result of looking up a request in the route table
+pub enum RouteMatch<'a> {
+    Found(&'a RouteConfig, Params),
+    // The path is known but not for this method; carries the allowed methods
+    MethodNotAllowed(Vec<Method>),
+    // A captured parameter is a `.` or `..` segment; carries its name
+    DotSegment(String),
+    NotFound,
+}
+
+pub struct Router {
+    routes: Vec<RouteConfig>,
+}
+
+impl Router {
+    pub fn new(mut routes: Vec<RouteConfig>) -> Router {
+        // Most specific patterns first; the sort is stable so declaration
+        // order breaks ties
+        routes.sort_by_key(|r| std::cmp::Reverse(r.pattern.specificity()));
+        Router { routes }
+    }
+
+    pub fn lookup(&self, method: &Method, path: &str) -> RouteMatch<'_> {
+        let mut allowed: Vec<Method> = Vec::new();
+        for route in &self.routes {
+            if let Some(params) = route.pattern.matches(path) {
+                if route.method == method {
+                    let dotted = params.iter().find(|(_, v)| v.split('/').any(is_dot_segment));
+                    if let Some((name, _)) = dotted {
+                        return RouteMatch::DotSegment(name.clone());
+                    }
+                    return RouteMatch::Found(route, params);
+                }
+                if !allowed.contains(&route.method) {
+                    allowed.push(route.method.clone());
+                }
+            }
+        }
+        if allowed.is_empty() {
+            RouteMatch::NotFound
+        } else {
+            RouteMatch::MethodNotAllowed(allowed)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    fn params(pairs: &[(&str, &str)]) -> Params {
+        pairs.iter().map(|(n, v)| (n.to_string(), v.to_string())).collect()
+    }
+
+    fn router(routes: &[(&str, &str)]) -> Router {
+        let mut toml = String::from("upstream = \"http://127.0.0.1:9\"\n");
+        for (method, path) in routes {
+            toml.push_str(&format!("[[routes]]\nmethod = \"{}\"\npath = \"{}\"\n", method, path));
+        }
+        Router::new(Config::from_toml(&toml).unwrap().routes)
+    }
+
+    fn found(router: &Router, method: Method, path: &str) -> Option<(String, Params)> {
+        match router.lookup(&method, path) {
+            RouteMatch::Found(route, params) => Some((route.path.clone(), params)),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn parse_rejects_bad_patterns() {
+        assert!(PathPattern::parse("users").is_err());
+        assert!(PathPattern::parse("/users/{}").is_err());
+        assert!(PathPattern::parse("/users/{id-1}").is_err());
+        assert!(PathPattern::parse("/users/{id}/{id}").is_err());
+        assert!(PathPattern::parse("/files/{*path}/meta").is_err());
+        assert!(PathPattern::parse("/users/id{id}").is_err());
+        assert!(PathPattern::parse("/users/{id").is_err());
+        assert!(PathPattern::parse("/").is_ok());
+    }
+
+    #[test]
+    fn params_and_wildcards_capture_segments() {
+        let users = PathPattern::parse("/users/{id}/posts/{post_id}").unwrap();
+        assert_eq!(users.param_names().collect::<Vec<_>>(), ["id", "post_id"]);
+        assert_eq!(users.matches("/users/7/posts/9"), Some(params(&[("id", "7"), ("post_id", "9")])));
+        assert_eq!(users.matches("/users/7/posts"), None);
+        assert_eq!(users.matches("/users/7/posts/9/x"), None);
+        // Empty and repeated slashes are not segments
+        assert_eq!(users.matches("//users/7//posts/9/"), Some(params(&[("id", "7"), ("post_id", "9")])));
+
+        let files = PathPattern::parse("/files/{*path}").unwrap();
+        assert_eq!(files.matches("/files/a/b/c.txt"), Some(params(&[("path", "a/b/c.txt")])));
+        assert_eq!(files.matches("/files"), Some(params(&[("path", "")])));
+        assert_eq!(files.matches("/other/a"), None);
+    }
+
+    #[test]
+    fn specific_routes_win_regardless_of_order() {
+        let router = router(&[
+            ("GET", "/files/{*path}"),
+            ("GET", "/users/{id}"),
+            ("GET", "/users/me"),
+            ("GET", "/files/readme"),
+        ]);
+        assert_eq!(found(&router, Method::GET, "/users/me"), Some(("/users/me".to_string(), vec![])));
+        assert_eq!(found(&router, Method::GET, "/users/42"), Some(("/users/{id}".to_string(), params(&[("id", "42")]))));
+        assert_eq!(found(&router, Method::GET, "/files/readme"), Some(("/files/readme".to_string(), vec![])));
+        assert_eq!(
+            found(&router, Method::GET, "/files/readme/old"),
+            Some(("/files/{*path}".to_string(), params(&[("path", "readme/old")])))
+        );
+    }
+
+    #[test]
+    fn other_methods_on_a_known_path_are_not_allowed() {
+        let router = router(&[("GET", "/users/{id}"), ("DELETE", "/users/{id}"), ("POST", "/users")]);
+        match router.lookup(&Method::PUT, "/users/1") {
+            RouteMatch::MethodNotAllowed(allowed) => assert_eq!(allowed, [Method::GET, Method::DELETE]),
+            _ => panic!("expected 405"),
+        }
+        assert!(matches!(router.lookup(&Method::GET, "/nothing"), RouteMatch::NotFound));
+    }
+
+    #[test]
+    fn templates_are_filled_from_params() {
+        let p = params(&[("id", "7"), ("path", "a/b")]);
+        assert_eq!(render_template("/v2/users/{id}/files/{*path}", &p), "/v2/users/7/files/a/b");
+        assert_eq!(render_template("/v2/{path}?user={id}", &p), "/v2/a/b?user=7");
+    }
+
+    #[test]
+    fn captured_values_cannot_escape_the_upstream_prefix() {
+        let router = router(&[("GET", "/users/{id}"), ("GET", "/files/{*path}")]);
+        for path in ["/users/..", "/users/%2e%2E", "/users/.%2e", "/files/a/../../admin", "/files/%2E", "/files/a/%2e%2e/b"] {
+            assert!(matches!(router.lookup(&Method::GET, path), RouteMatch::DotSegment(_)), "{}", path);
+        }
+        assert!(found(&router, Method::GET, "/users/..x").is_some());
+        assert!(found(&router, Method::GET, "/files/a/.hidden").is_some());
+
+        // Values are encoded once and never expanded as placeholders
+        let p = params(&[("id", "a?b#c {*path}"), ("path", "x%2Fy/{id}")]);
+        assert_eq!(
+            render_template("/v2/{id}/files/{*path}", &p),
+            "/v2/a%3Fb%23c%20%7B%2Apath%7D/files/x%2Fy/%7Bid%7D"
+        );
+        assert_eq!(render_template("/v2/{missing}/{id", &p), "/v2/{missing}/{id");
+    }
+}
@@ -0,0 +1,115 @@
+use crate::errors::ApiError;
+use hyper::StatusCode;
+use serde_json::Value;
+use std::path::Path;
+
+// JSON Schema (draft 2020-12) compiled from a file at startup
+#[derive(Debug)]
+pub struct Schema {
+    validator: jsonschema::Validator,
+}
+
+impl Schema {
+    pub fn load(path: &Path) -> Result<Schema, String> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("cannot read schema {}: {}", path.display(), e))?;
+        let schema: Value = serde_json::from_str(&text)
+            .map_err(|e| format!("schema {} is not valid JSON: {}", path.display(), e))?;
+        let validator = jsonschema::draft202012::new(&schema)
+            .map_err(|e| format!("schema {} is invalid: {}", path.display(), e))?;
+        Ok(Schema { validator })
+    }
+
+    // Check a payload, reporting every violation at once
+    pub fn validate(&self, instance: &Value) -> Result<(), ApiError> {
+        let violations: Vec<Value> = self
+            .validator
+            .iter_errors(instance)
+            .map(|e| {
+                let schema_path = e.schema_path.to_string();
+                let keyword = schema_path.rsplit('/').next().unwrap_or_default().to_string();
+                serde_json::json!({
+                    "pointer": e.instance_path.to_string(),
+                    "keyword": keyword,
+                    "schema_path": schema_path,
+                    "message": e.to_string(),
+                })
+            })
+            .collect();
+        if violations.is_empty() {
+            return Ok(());
+        }
+        Err(ApiError::new(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "schema-violation",
+            format!("Request body violates the route schema ({} error(s))", violations.len()),
+        )
+        .with_extension("errors", violations))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    // Schema file removed when dropped
+    struct TempSchema(PathBuf);
+
+    impl TempSchema {
+        fn new(name: &str, text: &str) -> TempSchema {
+            let path = std::env::temp_dir().join(format!("rust_api-{}-{}", std::process::id(), name));
+            std::fs::write(&path, text).unwrap();
+            TempSchema(path)
+        }
+    }
+
+    impl Drop for TempSchema {
+        fn drop(&mut self) {
+            std::fs::remove_file(&self.0).ok();
+        }
+    }
+
+    const ITEM_SCHEMA: &str = r#"{
+        "type": "object",
+        "properties": {
+            "name": { "type": "string", "minLength": 1 },
+            "count": { "type": "integer", "minimum": 0 }
+        },
+        "required": ["name"]
+    }"#;
+
+    #[tokio::test]
+    async fn violations_become_a_422_problem() {
+        let file = TempSchema::new("item.json", ITEM_SCHEMA);
+        let schema = Schema::load(&file.0).unwrap();
+        assert!(schema.validate(&serde_json::json!({ "name": "x", "count": 1 })).is_ok());
+
+        let err = schema.validate(&serde_json::json!({ "count": -1 })).unwrap_err();
+        let response = err.into_response("req-1");
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(response.headers()[hyper::header::CONTENT_TYPE], crate::errors::PROBLEM_CONTENT_TYPE);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let problem: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(problem["type"], "/problems/schema-violation");
+        assert_eq!(problem["detail"], "Request body violates the route schema (2 error(s))");
+        let mut found: Vec<(String, String)> = problem["errors"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|e| (e["pointer"].as_str().unwrap().to_string(), e["keyword"].as_str().unwrap().to_string()))
+            .collect();
+        found.sort();
+        assert_eq!(found, [("".to_string(), "required".to_string()), ("/count".to_string(), "minimum".to_string())]);
+    }
+
+    #[test]
+    fn unusable_schemas_are_rejected_at_load() {
+        let missing = Schema::load(Path::new("/nonexistent/schema.json")).unwrap_err();
+        assert!(missing.starts_with("cannot read schema /nonexistent/schema.json"), "{}", missing);
+        let file = TempSchema::new("broken.json", "{");
+        assert!(Schema::load(&file.0).unwrap_err().contains("is not valid JSON"));
+        let file = TempSchema::new("invalid.json", r#"{"type": 7}"#);
+        assert!(Schema::load(&file.0).unwrap_err().contains("is invalid"));
+    }
+}
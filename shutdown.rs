@@ -0,0 +1,134 @@
+use serde::Deserialize;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use tokio::sync::watch;
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FileShutdown {
+    drain_timeout_ms: Option<u64>,
+    readiness_delay_ms: Option<u64>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ShutdownConfig {
+    // How long in-flight requests may take to finish once we stop accepting
+    pub drain_timeout: Duration,
+    // Time between failing readiness and closing the listener, so load
+    // balancers can stop routing to us first
+    pub readiness_delay: Duration,
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> ShutdownConfig {
+        ShutdownConfig {
+            drain_timeout: Duration::from_secs(30),
+            readiness_delay: Duration::ZERO,
+        }
+    }
+}
+
+impl ShutdownConfig {
+    pub fn merge(&self, file: &FileShutdown) -> ShutdownConfig {
+        let mut c = self.clone();
+        if let Some(ms) = file.drain_timeout_ms {
+            c.drain_timeout = Duration::from_millis(ms);
+        }
+        if let Some(ms) = file.readiness_delay_ms {
+            c.readiness_delay = Duration::from_millis(ms);
+        }
+        c
+    }
+}
+
+// Counts a request as in flight for as long as it is alive
+pub struct InFlight<'a>(&'a AtomicUsize);
+
+impl<'a> InFlight<'a> {
+    pub fn start(counter: &'a AtomicUsize) -> InFlight<'a> {
+        counter.fetch_add(1, Ordering::SeqCst);
+        InFlight(counter)
+    }
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// Connections the HTTP server handed off, such as those upgraded to h2c. The
+// server's graceful shutdown no longer covers them, so they are told to stop
+// and waited for separately.
+pub struct Detached {
+    stop: watch::Sender<bool>,
+}
+
+impl Default for Detached {
+    fn default() -> Detached {
+        Detached { stop: watch::channel(false).0 }
+    }
+}
+
+impl Detached {
+    // Held by a detached connection for as long as it is served. Resolves
+    // `stopped` once shutdown starts.
+    pub fn track(&self) -> watch::Receiver<bool> {
+        self.stop.subscribe()
+    }
+
+    pub fn stop(&self) {
+        self.stop.send_replace(true);
+    }
+
+    // Resolves once every detached connection has closed
+    pub async fn closed(&self) {
+        self.stop.closed().await
+    }
+}
+
+// Resolves once `stop` is called on the connection's `Detached`
+pub async fn stopped(rx: &mut watch::Receiver<bool>) {
+    rx.wait_for(|stopping| *stopping).await.ok();
+}
+
+// Resolves on the first SIGTERM or SIGINT, returning the signal name
+#[cfg(unix)]
+pub async fn signal() -> &'static str {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut term = signal(SignalKind::terminate()).expect("Failed to install SIGTERM handler");
+    let mut int = signal(SignalKind::interrupt()).expect("Failed to install SIGINT handler");
+    tokio::select! {
+        _ = term.recv() => "SIGTERM",
+        _ = int.recv() => "SIGINT",
+    }
+}
+
+#[cfg(not(unix))]
+pub async fn signal() -> &'static str {
+    tokio::signal::ctrl_c().await.expect("Failed to install Ctrl-C handler");
+    "Ctrl-C"
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn detached_connections_are_stopped_and_awaited() {
+        let detached = Detached::default();
+        let mut rx = detached.track();
+        assert_eq!(detached.stop.receiver_count(), 1);
+        let conn = tokio::spawn(async move {
+            stopped(&mut rx).await;
+        });
+        detached.stop();
+        tokio::time::timeout(Duration::from_secs(1), detached.closed()).await.unwrap();
+        assert_eq!(detached.stop.receiver_count(), 0);
+        conn.await.unwrap();
+
+        // Connections upgraded after shutdown started stop right away
+        let mut late = detached.track();
+        tokio::time::timeout(Duration::from_secs(1), stopped(&mut late)).await.unwrap();
+    }
+}
@@ -0,0 +1,175 @@
+use hyper::header::HeaderMap;
+use serde::Deserialize;
+use std::time::Duration;
+
+// Inbound headers that let a caller shorten our deadline. `X-Request-Timeout`
+// takes a number with an optional `ms`/`s`/`m` unit (plain numbers are
+// milliseconds); `grpc-timeout` uses the gRPC `<digits><H|M|S|m|u|n>` form.
+pub const X_REQUEST_TIMEOUT: &str = "x-request-timeout";
+pub const GRPC_TIMEOUT: &str = "grpc-timeout";
+
+// Timeout settings as written in the config file; every field is optional so
+// a route-level table only needs to list what it overrides
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FileTimeouts {
+    connect_ms: Option<u64>,
+    read_ms: Option<u64>,
+    total_ms: Option<u64>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Timeouts {
+    // Establishing the TCP/TLS connection to the upstream
+    pub connect: Duration,
+    // Waiting for the upstream response headers (connection setup included)
+    // and then between body chunks
+    pub read: Duration,
+    // Whole request, from route match to the last byte of the upstream reply,
+    // including retries
+    pub total: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Timeouts {
+        Timeouts {
+            connect: Duration::from_secs(5),
+            read: Duration::from_secs(30),
+            total: Duration::from_secs(60),
+        }
+    }
+}
+
+impl Timeouts {
+    // Apply the fields set in `file` on top of `self`
+    pub fn merge(&self, file: &FileTimeouts) -> Result<Timeouts, String> {
+        let mut t = self.clone();
+        let non_zero = |ms: u64, key: &str| {
+            if ms == 0 {
+                Err(format!("{} must be greater than 0", key))
+            } else {
+                Ok(Duration::from_millis(ms))
+            }
+        };
+        if let Some(ms) = file.connect_ms {
+            t.connect = non_zero(ms, "connect_ms")?;
+        }
+        if let Some(ms) = file.read_ms {
+            t.read = non_zero(ms, "read_ms")?;
+        }
+        if let Some(ms) = file.total_ms {
+            t.total = non_zero(ms, "total_ms")?;
+        }
+        Ok(t)
+    }
+}
+
+fn parse_request_timeout(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    let split = value.find(|c: char| !c.is_ascii_digit()).unwrap_or(value.len());
+    let (digits, unit) = value.split_at(split);
+    let n: u64 = digits.parse().ok()?;
+    match unit.trim() {
+        "" | "ms" => Some(Duration::from_millis(n)),
+        "s" => Some(Duration::from_secs(n)),
+        "m" => Some(Duration::from_secs(n.checked_mul(60)?)),
+        _ => None,
+    }
+}
+
+fn parse_grpc_timeout(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    if value.len() < 2 || value.len() > 9 {
+        return None;
+    }
+    let (digits, unit) = value.split_at(value.len() - 1);
+    if !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let n: u64 = digits.parse().ok()?;
+    match unit {
+        "H" => Some(Duration::from_secs(n.checked_mul(3600)?)),
+        "M" => Some(Duration::from_secs(n.checked_mul(60)?)),
+        "S" => Some(Duration::from_secs(n)),
+        "m" => Some(Duration::from_millis(n)),
+        "u" => Some(Duration::from_micros(n)),
+        "n" => Some(Duration::from_nanos(n)),
+        _ => None,
+    }
+}
+
+// Budget requested by the caller, if any. Malformed values are ignored.
+pub fn inbound_budget(headers: &HeaderMap) -> Option<Duration> {
+    let header = |name| headers.get(name).and_then(|v| v.to_str().ok());
+    let a = header(X_REQUEST_TIMEOUT).and_then(parse_request_timeout);
+    let b = header(GRPC_TIMEOUT).and_then(parse_grpc_timeout);
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::header::HeaderValue;
+
+    fn headers(pairs: &[(&'static str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.insert(*name, HeaderValue::from_str(value).unwrap());
+        }
+        headers
+    }
+
+    #[test]
+    fn request_timeout_units() {
+        assert_eq!(parse_request_timeout("250"), Some(Duration::from_millis(250)));
+        assert_eq!(parse_request_timeout("250ms"), Some(Duration::from_millis(250)));
+        assert_eq!(parse_request_timeout(" 3 s "), Some(Duration::from_secs(3)));
+        assert_eq!(parse_request_timeout("2m"), Some(Duration::from_secs(120)));
+        assert_eq!(parse_request_timeout("1.5s"), None);
+        assert_eq!(parse_request_timeout("-5"), None);
+        assert_eq!(parse_request_timeout("5h"), None);
+        assert_eq!(parse_request_timeout("ms"), None);
+        assert_eq!(parse_request_timeout(&format!("{}m", u64::MAX)), None);
+    }
+
+    #[test]
+    fn grpc_timeout_units() {
+        assert_eq!(parse_grpc_timeout("1H"), Some(Duration::from_secs(3600)));
+        assert_eq!(parse_grpc_timeout("2M"), Some(Duration::from_secs(120)));
+        assert_eq!(parse_grpc_timeout("3S"), Some(Duration::from_secs(3)));
+        assert_eq!(parse_grpc_timeout("400m"), Some(Duration::from_millis(400)));
+        assert_eq!(parse_grpc_timeout("500u"), Some(Duration::from_micros(500)));
+        assert_eq!(parse_grpc_timeout("600n"), Some(Duration::from_nanos(600)));
+        // At most eight digits
+        assert_eq!(parse_grpc_timeout("99999999S"), Some(Duration::from_secs(99_999_999)));
+        assert_eq!(parse_grpc_timeout("100000000S"), None);
+        assert_eq!(parse_grpc_timeout("S"), None);
+        assert_eq!(parse_grpc_timeout("+5S"), None);
+        assert_eq!(parse_grpc_timeout("5s"), None);
+    }
+
+    #[test]
+    fn shorter_inbound_budget_wins() {
+        assert_eq!(inbound_budget(&HeaderMap::new()), None);
+        assert_eq!(inbound_budget(&headers(&[(X_REQUEST_TIMEOUT, "2s")])), Some(Duration::from_secs(2)));
+        assert_eq!(inbound_budget(&headers(&[(GRPC_TIMEOUT, "700m")])), Some(Duration::from_millis(700)));
+        let both = headers(&[(X_REQUEST_TIMEOUT, "2s"), (GRPC_TIMEOUT, "700m")]);
+        assert_eq!(inbound_budget(&both), Some(Duration::from_millis(700)));
+        // A malformed header does not hide a valid one
+        let one_bad = headers(&[(X_REQUEST_TIMEOUT, "soon"), (GRPC_TIMEOUT, "1S")]);
+        assert_eq!(inbound_budget(&one_bad), Some(Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn merge_rejects_zero() {
+        let merge = |file: FileTimeouts| Timeouts::default().merge(&file);
+        assert!(merge(FileTimeouts { connect_ms: Some(0), ..Default::default() }).is_err());
+        assert!(merge(FileTimeouts { total_ms: Some(0), ..Default::default() }).is_err());
+        let t = merge(FileTimeouts { read_ms: Some(1500), ..Default::default() }).unwrap();
+        assert_eq!(t.read, Duration::from_millis(1500));
+        assert_eq!(t.connect, Duration::from_secs(5));
+    }
+}
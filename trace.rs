@@ -0,0 +1,498 @@
+use hyper::header::HeaderMap;
+use reqwest::{Client, Url};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::{mpsc, oneshot};
+
+// W3C Trace Context headers
+pub const TRACEPARENT: &str = "traceparent";
+pub const TRACESTATE: &str = "tracestate";
+
+// Spans queued beyond this are dropped rather than slowing requests down
+const QUEUE_CAPACITY: usize = 4096;
+const MAX_BATCH: usize = 512;
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FileTracing {
+    enabled: Option<bool>,
+    endpoint: Option<String>,
+    service_name: Option<String>,
+    sample_ratio: Option<f64>,
+    parent_based: Option<bool>,
+    export_interval_ms: Option<u64>,
+}
+
+#[derive(Debug, Clone)]
+pub struct TracingConfig {
+    pub enabled: bool,
+    // OTLP/HTTP collector base URL; spans are POSTed to `<endpoint>/v1/traces`
+    pub endpoint: Url,
+    pub service_name: String,
+    // Share of new traces that are recorded
+    pub sample_ratio: f64,
+    // Follow the caller's sampling decision when a `traceparent` is present
+    pub parent_based: bool,
+    pub export_interval: Duration,
+}
+
+impl Default for TracingConfig {
+    fn default() -> TracingConfig {
+        TracingConfig {
+            enabled: false,
+            endpoint: Url::parse("http://127.0.0.1:4318").expect("valid default endpoint"),
+            service_name: "rust_api".to_string(),
+            sample_ratio: 1.0,
+            parent_based: true,
+            export_interval: Duration::from_secs(5),
+        }
+    }
+}
+
+impl TracingConfig {
+    pub fn merge(&self, file: &FileTracing) -> Result<TracingConfig, String> {
+        let mut c = self.clone();
+        if let Some(e) = file.enabled {
+            c.enabled = e;
+        }
+        if let Some(e) = &file.endpoint {
+            c.endpoint = Url::parse(e).map_err(|err| format!("invalid endpoint '{}': {}", e, err))?;
+        }
+        if let Some(n) = &file.service_name {
+            c.service_name = n.clone();
+        }
+        if let Some(r) = file.sample_ratio {
+            if !(0.0..=1.0).contains(&r) {
+                return Err(format!("sample_ratio must be between 0 and 1, got {}", r));
+            }
+            c.sample_ratio = r;
+        }
+        if let Some(p) = file.parent_based {
+            c.parent_based = p;
+        }
+        if let Some(ms) = file.export_interval_ms {
+            if ms == 0 {
+                return Err("export_interval_ms must be greater than 0".to_string());
+            }
+            c.export_interval = Duration::from_millis(ms);
+        }
+        Ok(c)
+    }
+}
+
+// Identifies one span within a trace
+#[derive(Debug, Clone)]
+pub struct SpanContext {
+    pub trace_id: u128,
+    pub span_id: u64,
+    pub sampled: bool,
+    // Vendor state received from the caller, passed on untouched
+    pub tracestate: Option<String>,
+}
+
+impl SpanContext {
+    // A new span in the same trace
+    pub fn child(&self) -> SpanContext {
+        SpanContext { span_id: new_span_id(), ..self.clone() }
+    }
+
+    pub fn traceparent(&self) -> String {
+        format!("00-{:032x}-{:016x}-{:02x}", self.trace_id, self.span_id, u8::from(self.sampled))
+    }
+}
+
+fn new_span_id() -> u64 {
+    loop {
+        let id = rand::random::<u64>();
+        if id != 0 {
+            return id;
+        }
+    }
+}
+
+fn new_trace_id() -> u128 {
+    loop {
+        let id = rand::random::<u128>();
+        if id != 0 {
+            return id;
+        }
+    }
+}
+
+// Parse `00-<trace-id>-<parent-id>-<flags>`; invalid headers are ignored
+fn parse_traceparent(value: &str) -> Option<(u128, u64, bool)> {
+    let parts: Vec<&str> = value.trim().split('-').collect();
+    let [version, trace, parent, flags] = parts.as_slice() else {
+        return None;
+    };
+    if version.len() != 2 || *version == "ff" || trace.len() != 32 || parent.len() != 16 || flags.len() != 2 {
+        return None;
+    }
+    let trace_id = u128::from_str_radix(trace, 16).ok().filter(|t| *t != 0)?;
+    let parent_id = u64::from_str_radix(parent, 16).ok().filter(|p| *p != 0)?;
+    let flags = u8::from_str_radix(flags, 16).ok()?;
+    Some((trace_id, parent_id, flags & 1 == 1))
+}
+
+#[derive(Debug, Clone, Copy)]
+enum SpanKind {
+    Server,
+    Client,
+}
+
+// A finished span, ready for export
+struct Span {
+    context: SpanContext,
+    parent_span_id: Option<u64>,
+    name: String,
+    kind: SpanKind,
+    start: SystemTime,
+    end: SystemTime,
+    attributes: Vec<(&'static str, Value)>,
+    error: bool,
+}
+
+// Span being recorded; call `finish` to queue it for export
+pub struct ActiveSpan {
+    span: Span,
+}
+
+impl ActiveSpan {
+    fn new(context: SpanContext, parent_span_id: Option<u64>, name: String, kind: SpanKind) -> ActiveSpan {
+        let now = SystemTime::now();
+        ActiveSpan {
+            span: Span {
+                context,
+                parent_span_id,
+                name,
+                kind,
+                start: now,
+                end: now,
+                attributes: Vec::new(),
+                error: false,
+            },
+        }
+    }
+
+    pub fn context(&self) -> &SpanContext {
+        &self.span.context
+    }
+
+    // Rename once more is known, e.g. the matched route
+    pub fn set_name(&mut self, name: String) {
+        self.span.name = name;
+    }
+
+    pub fn set_attribute(&mut self, key: &'static str, value: impl Into<Value>) {
+        self.span.attributes.push((key, value.into()));
+    }
+
+    pub fn set_error(&mut self) {
+        self.span.error = true;
+    }
+
+    pub fn finish(mut self, tracer: &Tracer) {
+        self.span.end = SystemTime::now();
+        tracer.export(self.span);
+    }
+}
+
+enum Message {
+    Span(Span),
+    Flush(oneshot::Sender<()>),
+}
+
+// Creates spans and ships sampled ones to the OTLP collector in batches
+pub struct Tracer {
+    config: TracingConfig,
+    tx: Option<mpsc::Sender<Message>>,
+}
+
+impl Tracer {
+    // Start the export task when tracing is enabled
+    pub fn new(config: TracingConfig) -> Tracer {
+        if !config.enabled {
+            return Tracer { config, tx: None };
+        }
+        let (tx, rx) = mpsc::channel(QUEUE_CAPACITY);
+        tokio::spawn(export_loop(config.clone(), rx));
+        Tracer { config, tx: Some(tx) }
+    }
+
+    // Start the server span for an inbound request, continuing the caller's
+    // trace when it sent a valid `traceparent`
+    pub fn server_span(&self, name: String, headers: &HeaderMap) -> ActiveSpan {
+        let header = |n| headers.get(n).and_then(|v| v.to_str().ok());
+        let parent = header(TRACEPARENT).and_then(parse_traceparent);
+        let ratio_sampled = || self.config.enabled && rand::random::<f64>() < self.config.sample_ratio;
+        let (trace_id, parent_span_id, sampled) = match parent {
+            Some((trace_id, parent_id, parent_sampled)) => {
+                let sampled = if self.config.parent_based {
+                    self.config.enabled && parent_sampled
+                } else {
+                    ratio_sampled()
+                };
+                (trace_id, Some(parent_id), sampled)
+            }
+            None => (new_trace_id(), None, ratio_sampled()),
+        };
+        let tracestate = parent.and(header(TRACESTATE)).map(str::to_string);
+        let context = SpanContext { trace_id, span_id: new_span_id(), sampled, tracestate };
+        ActiveSpan::new(context, parent_span_id, name, SpanKind::Server)
+    }
+
+    // Start a client span for an upstream call made on behalf of `parent`
+    pub fn client_span(&self, name: String, parent: &SpanContext) -> ActiveSpan {
+        ActiveSpan::new(parent.child(), Some(parent.span_id), name, SpanKind::Client)
+    }
+
+    fn export(&self, span: Span) {
+        if let (Some(tx), true) = (&self.tx, span.context.sampled) {
+            // Queue full or exporter gone: drop the span
+            tx.try_send(Message::Span(span)).ok();
+        }
+    }
+
+    // Export everything queued so far; used at shutdown
+    pub async fn flush(&self) {
+        if let Some(tx) = &self.tx {
+            let (done_tx, done_rx) = oneshot::channel();
+            if tx.send(Message::Flush(done_tx)).await.is_ok() {
+                done_rx.await.ok();
+            }
+        }
+    }
+}
+
+fn unix_nanos(t: SystemTime) -> String {
+    t.duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos().to_string()
+}
+
+fn attribute_value(v: &Value) -> Value {
+    match v {
+        Value::String(s) => json!({ "stringValue": s }),
+        Value::Bool(b) => json!({ "boolValue": b }),
+        Value::Number(n) if n.is_i64() || n.is_u64() => json!({ "intValue": n.to_string() }),
+        Value::Number(n) => json!({ "doubleValue": n.as_f64() }),
+        other => json!({ "stringValue": other.to_string() }),
+    }
+}
+
+// OTLP/JSON encoding of a span (`ExportTraceServiceRequest` span entry)
+fn encode_span(span: &Span) -> Value {
+    let attributes: Vec<Value> = span
+        .attributes
+        .iter()
+        .map(|(k, v)| json!({ "key": k, "value": attribute_value(v) }))
+        .collect();
+    let mut value = json!({
+        "traceId": format!("{:032x}", span.context.trace_id),
+        "spanId": format!("{:016x}", span.context.span_id),
+        "name": span.name,
+        "kind": match span.kind { SpanKind::Server => 2, SpanKind::Client => 3 },
+        "startTimeUnixNano": unix_nanos(span.start),
+        "endTimeUnixNano": unix_nanos(span.end),
+        "attributes": attributes,
+        "status": { "code": if span.error { 2 } else { 0 } },
+    });
+    if let Some(parent) = span.parent_span_id {
+        value["parentSpanId"] = format!("{:016x}", parent).into();
+    }
+    if let Some(state) = &span.context.tracestate {
+        value["traceState"] = state.clone().into();
+    }
+    value
+}
+
+async fn send_batch(client: &Client, url: &Url, config: &TracingConfig, batch: &mut Vec<Span>) {
+    if batch.is_empty() {
+        return;
+    }
+    let body = json!({
+        "resourceSpans": [{
+            "resource": {
+                "attributes": [{ "key": "service.name", "value": { "stringValue": config.service_name } }]
+            },
+            "scopeSpans": [{
+                "scope": { "name": "rust_api" },
+                "spans": batch.iter().map(encode_span).collect::<Vec<_>>(),
+            }]
+        }]
+    });
+    match client.post(url.clone()).json(&body).send().await {
+        Ok(resp) if resp.status().is_success() => {}
+        Ok(resp) => eprintln!("Trace export to {} failed: status {}", url, resp.status()),
+        Err(e) => eprintln!("Trace export to {} failed: {}", url, e),
+    }
+    batch.clear();
+}
+
+async fn export_loop(config: TracingConfig, mut rx: mpsc::Receiver<Message>) {
+    let client = reqwest::Client::builder()
+        .use_rustls_tls()
+        .timeout(Duration::from_secs(10))
+        .build()
+        .expect("Failed to build reqwest client");
+    let mut url = config.endpoint.clone();
+    url.set_path(&format!("{}/v1/traces", config.endpoint.path().trim_end_matches('/')));
+
+    let mut batch = Vec::new();
+    let mut interval = tokio::time::interval(config.export_interval);
+    loop {
+        tokio::select! {
+            msg = rx.recv() => match msg {
+                Some(Message::Span(span)) => {
+                    batch.push(span);
+                    if batch.len() >= MAX_BATCH {
+                        send_batch(&client, &url, &config, &mut batch).await;
+                    }
+                }
+                Some(Message::Flush(done)) => {
+                    send_batch(&client, &url, &config, &mut batch).await;
+                    done.send(()).ok();
+                }
+                None => {
+                    send_batch(&client, &url, &config, &mut batch).await;
+                    return;
+                }
+            },
+            _ = interval.tick() => send_batch(&client, &url, &config, &mut batch).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Request, Response};
+    use std::convert::Infallible;
+
+    // Collector stub handing each export's path and body to the test
+    fn collector() -> (Url, mpsc::UnboundedReceiver<(String, Value)>) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let make_svc = make_service_fn(move |_| {
+            let tx = tx.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                    let tx = tx.clone();
+                    async move {
+                        let path = req.uri().path().to_string();
+                        let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+                        tx.send((path, serde_json::from_slice(&body).unwrap())).ok();
+                        Ok::<_, Infallible>(Response::new(Body::from("{}")))
+                    }
+                }))
+            }
+        });
+        let server = hyper::Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_svc);
+        let url = Url::parse(&format!("http://{}/otlp/", server.local_addr())).unwrap();
+        tokio::spawn(server);
+        (url, rx)
+    }
+
+    fn config(file: FileTracing) -> TracingConfig {
+        TracingConfig::default().merge(&file).unwrap()
+    }
+
+    fn headers(traceparent: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(TRACEPARENT, traceparent.parse().unwrap());
+        headers.insert(TRACESTATE, "vendor=1".parse().unwrap());
+        headers
+    }
+
+    const PARENT: &str = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+
+    #[test]
+    fn traceparent_parsing() {
+        assert_eq!(parse_traceparent(PARENT), Some((0x4bf92f3577b34da6a3ce929d0e0e4736, 0x00f067aa0ba902b7, true)));
+        assert_eq!(parse_traceparent("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-00").map(|p| p.2), Some(false));
+        // Later versions may append fields we do not know how to read
+        assert_eq!(parse_traceparent("ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"), None);
+        assert_eq!(parse_traceparent("00-00000000000000000000000000000000-00f067aa0ba902b7-01"), None);
+        assert_eq!(parse_traceparent("00-4bf92f3577b34da6a3ce929d0e0e4736-0000000000000000-01"), None);
+        assert_eq!(parse_traceparent("00-4bf92f3577b34da6a3ce929d0e0e473-00f067aa0ba902b7-01"), None);
+        assert_eq!(parse_traceparent("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7"), None);
+        assert_eq!(parse_traceparent("00-xyz92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"), None);
+    }
+
+    #[test]
+    fn caller_trace_is_continued() {
+        let tracer = Tracer::new(config(FileTracing::default()));
+        let span = tracer.server_span("GET /".to_string(), &headers(PARENT));
+        let context = span.context();
+        assert_eq!(context.trace_id, 0x4bf92f3577b34da6a3ce929d0e0e4736);
+        assert_ne!(context.span_id, 0x00f067aa0ba902b7);
+        assert_eq!(span.span.parent_span_id, Some(0x00f067aa0ba902b7));
+        assert_eq!(context.tracestate.as_deref(), Some("vendor=1"));
+        // Tracing is off, so nothing is sampled whatever the caller says
+        assert!(!context.sampled);
+        let traceparent = context.child().traceparent();
+        assert!(traceparent.starts_with("00-4bf92f3577b34da6a3ce929d0e0e4736-"));
+        assert!(traceparent.ends_with("-00"));
+    }
+
+    #[tokio::test]
+    async fn sampling_follows_the_parent_or_the_ratio() {
+        let never = FileTracing { enabled: Some(true), sample_ratio: Some(0.0), ..Default::default() };
+        let tracer = Tracer::new(config(never));
+        assert!(!tracer.server_span("GET /".to_string(), &HeaderMap::new()).context().sampled);
+        assert!(tracer.server_span("GET /".to_string(), &headers(PARENT)).context().sampled);
+
+        let own = FileTracing { enabled: Some(true), sample_ratio: Some(0.0), parent_based: Some(false), ..Default::default() };
+        let tracer = Tracer::new(config(own));
+        assert!(!tracer.server_span("GET /".to_string(), &headers(PARENT)).context().sampled);
+    }
+
+    #[tokio::test]
+    async fn spans_are_exported_to_the_collector() {
+        let (endpoint, mut exports) = collector();
+        let file = FileTracing {
+            enabled: Some(true),
+            endpoint: Some(endpoint.to_string()),
+            service_name: Some("edge".to_string()),
+            export_interval_ms: Some(60_000),
+            ..Default::default()
+        };
+        let tracer = Tracer::new(config(file));
+        let mut server = tracer.server_span("GET /users/{id}".to_string(), &headers(PARENT));
+        let mut client = tracer.client_span("GET".to_string(), server.context());
+        client.set_attribute("http.response.status_code", 502);
+        client.set_error();
+        client.finish(&tracer);
+        server.set_attribute("http.route", "/users/{id}");
+        server.finish(&tracer);
+        tracer.flush().await;
+
+        let (path, body) = exports.try_recv().unwrap();
+        assert_eq!(path, "/otlp/v1/traces");
+        let resource = &body["resourceSpans"][0];
+        assert_eq!(resource["resource"]["attributes"][0]["value"]["stringValue"], "edge");
+        let spans = resource["scopeSpans"][0]["spans"].as_array().unwrap();
+        assert_eq!(spans.len(), 2);
+        let (client, server) = (&spans[0], &spans[1]);
+        assert_eq!(server["traceId"], "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_eq!(server["parentSpanId"], "00f067aa0ba902b7");
+        assert_eq!(server["kind"], 2);
+        assert_eq!(server["traceState"], "vendor=1");
+        assert_eq!(server["status"]["code"], 0);
+        assert_eq!(server["attributes"][0], json!({ "key": "http.route", "value": { "stringValue": "/users/{id}" } }));
+        assert_eq!(client["traceId"], server["traceId"]);
+        assert_eq!(client["parentSpanId"], server["spanId"]);
+        assert_eq!(client["kind"], 3);
+        assert_eq!(client["status"]["code"], 2);
+        assert_eq!(client["attributes"][0]["value"], json!({ "intValue": "502" }));
+    }
+
+    #[tokio::test]
+    async fn unsampled_spans_are_not_exported() {
+        let (endpoint, mut exports) = collector();
+        let file = FileTracing { enabled: Some(true), endpoint: Some(endpoint.to_string()), ..Default::default() };
+        let tracer = Tracer::new(config(file));
+        tracer.server_span("GET /".to_string(), &headers(&PARENT.replace("-01", "-00"))).finish(&tracer);
+        tracer.flush().await;
+        assert!(exports.try_recv().is_err());
+    }
+}